@@ -0,0 +1,956 @@
+use crate::qr_code::{
+    ALPHANUMERIC_CHARSET, ECC_BLOCKS, ECC_CODEWORDS_PER_BLOCK, EccLevel, Matrix, Mode,
+    bits_to_codewords, correct_errors, data_codewords, decode_text, format_bch, function_modules,
+    mask_pattern, read_zigzag, total_codewords, version_bch,
+};
+use crate::{gs1, micro_qr, rmqr};
+
+// A segment read back from a QR code
+// Numeric and alphanumeric segments hold their characters as ASCII, byte
+// segments their bytes, and kanji segments two Shift JIS bytes per
+// character
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedSegment {
+    pub mode: Mode,
+    pub data: Vec<u8>,
+    // ECI designator in effect for the segment: the last one before it
+    pub eci: Option<u32>,
+}
+
+impl DecodedSegment {
+    // The segment as text: byte segments in the character set declared by
+    // their ECI designator, kanji segments from Shift JIS
+    // None for unsupported designators or invalid byte sequences
+    pub fn text(&self) -> Option<String> {
+        match self.mode {
+            Mode::Byte => decode_text(&self.data, self.eci),
+            Mode::Kanji => decode_text(&self.data, Some(20)),
+            _ => Some(self.data.iter().map(|byte| *byte as char).collect()),
+        }
+    }
+}
+
+// Everything read from a QR code: how it was built and the message it
+// holds
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decoded {
+    // Micro QR codes count versions from 1 (M1) to 4 (M4), rMQR codes
+    // from 0 (R7x43) to 31 (R17x139)
+    pub version: usize,
+    pub ecc_level: EccLevel,
+    // Micro QR codes number their 4 masks from 0 to 3, rMQR codes always
+    // use mask 4
+    pub mask: usize,
+    // Number of codewords restored by error correction
+    pub corrected_errors: usize,
+    // Position, total number of symbols, and parity of a message split
+    // across several QR codes
+    pub structured_append: Option<(usize, usize, u8)>,
+    // FNC1 in first position: the message is a GS1 element string
+    pub gs1: bool,
+    // FNC1 in second position, with its application indicator
+    pub application_indicator: Option<u8>,
+    pub segments: Vec<DecodedSegment>,
+}
+
+impl Decoded {
+    // The message bytes of all segments in a row
+    // Behind an FNC1 mode indicator, alphanumeric segments use % for the
+    // group separator, which is undone here
+    pub fn bytes(&self) -> Vec<u8> {
+        let fnc1 = self.gs1 || self.application_indicator.is_some();
+        let mut bytes: Vec<u8> = vec![];
+
+        for segment in &self.segments {
+            if fnc1 && segment.mode == Mode::Alphanumeric {
+                bytes.extend(gs1::from_alphanumeric(&segment.data));
+            } else {
+                bytes.extend_from_slice(&segment.data);
+            }
+        }
+
+        bytes
+    }
+
+    // The message as text, every segment in its own character set
+    // None if any segment can't be converted
+    pub fn text(&self) -> Option<String> {
+        let fnc1 = self.gs1 || self.application_indicator.is_some();
+
+        self.segments
+            .iter()
+            .map(|segment| {
+                if fnc1 && segment.mode == Mode::Alphanumeric {
+                    let bytes = gs1::from_alphanumeric(&segment.data);
+                    Some(bytes.iter().map(|byte| *byte as char).collect())
+                } else {
+                    segment.text()
+                }
+            })
+            .collect()
+    }
+
+    // Split the message of a GS1 QR code into its elements, checked
+    // against the AI table
+    pub fn gs1_elements(&self) -> Result<Vec<gs1::Element>, &'static str> {
+        if !self.gs1 {
+            return Err("Not a GS1 QR code");
+        }
+
+        gs1::parse_element_string(&self.bytes())
+    }
+}
+
+// Read bits of a data stream one field at a time
+struct BitReader<'a> {
+    bits: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    // Number of bits not read yet
+    fn remaining(&self) -> usize {
+        self.bits.len() - self.position
+    }
+
+    // Whether the data ends here: either there aren't enough bits left
+    // for a terminator of the given length, or they are all 0s
+    fn at_terminator(&self, length: usize) -> bool {
+        self.remaining() < length
+            || self.bits[self.position..self.position + length]
+                .iter()
+                .all(|bit| *bit == 0)
+    }
+
+    // Next `length` bits as a number, MSB first
+    fn read(&mut self, length: usize) -> Result<u32, &'static str> {
+        if length > self.remaining() {
+            return Err("Data ends in the middle of a segment");
+        }
+
+        let value = self.bits[self.position..self.position + length]
+            .iter()
+            .fold(0, |value, bit| value << 1 | *bit as u32);
+        self.position += length;
+
+        Ok(value)
+    }
+}
+
+// The kinds of symbols and their versions, which differ in mode
+// indicators, character count indicators and terminators
+#[derive(Clone, Copy)]
+enum Symbol {
+    Qr(usize),
+    Micro(usize),
+    Rmqr(usize),
+}
+
+impl Symbol {
+    // Length of the terminator of 0s, which is just as long as the
+    // shortest segment header
+    fn terminator_bits(self) -> usize {
+        match self {
+            Symbol::Qr(_) => 4,
+            Symbol::Micro(version) => version * 2 + 1,
+            Symbol::Rmqr(_) => 3,
+        }
+    }
+
+    // Mode indicator of the next segment
+    fn read_mode(self, reader: &mut BitReader) -> Result<Mode, &'static str> {
+        let mode = match self {
+            Symbol::Qr(_) => Mode::from_indicator(reader.read(4)?),
+            Symbol::Micro(version) => Some(micro_qr::mode(reader.read(version - 1)?)),
+            Symbol::Rmqr(_) => rmqr::mode(reader.read(3)?),
+        };
+
+        mode.ok_or("Unknown mode indicator")
+    }
+
+    // Width of the character count indicator of a mode
+    fn character_count_bits(self, mode: Mode) -> Result<usize, &'static str> {
+        match self {
+            Symbol::Qr(version) => Ok(mode.character_count_bits(version)),
+            Symbol::Micro(version) => micro_qr::character_count_bits(mode, version)
+                .ok_or("Mode not available in this Micro QR version"),
+            Symbol::Rmqr(version) => Ok(rmqr::character_count_bits(mode, version)),
+        }
+    }
+}
+
+// Decode a QR code from its modules (0 - white, 1 - black), as returned by
+// Matrix::export, with or without the quiet zone around it
+// Reverses every step of build_matrix: reads the format string, removes
+// the mask, collects the data bits in zig-zag order, splits them into
+// blocks, corrects errors, and parses the segments
+pub fn decode(modules: &[Vec<u8>]) -> Result<Decoded, &'static str> {
+    let modules = strip_quiet_zone(modules)?;
+    let size = modules.len();
+
+    if modules[0].len() != size {
+        return Err("QR code must be square");
+    }
+    if !(21..=177).contains(&size) || size % 4 != 1 {
+        return Err("Not a valid QR code size");
+    }
+    let version = (size - 17) / 4;
+
+    // From version 7 on, the version information has to agree with the
+    // size. If it can't be read, the size alone decides.
+    if version >= 7
+        && let Ok(read_version) = decode_version(&modules)
+        && read_version != version
+    {
+        return Err("Version information doesn't match the size");
+    }
+
+    let (ecc_level, mask) = decode_format(&modules)?;
+
+    // Remove the mask from all data modules
+    let function = function_modules(version);
+    let eval = mask_pattern(mask);
+    let mut unmasked = modules.clone();
+    for row in 0..size {
+        for col in 0..size {
+            if !function[row][col] && eval(row, col) == 0 {
+                unmasked[row][col] ^= 1;
+            }
+        }
+    }
+
+    // Collect the codewords, ignoring the remainder bits
+    let mut data_bits = read_zigzag(&unmasked, &function, size - 1, Some(6));
+    data_bits.truncate(total_codewords(version) * 8);
+    let codewords = bits_to_codewords(&data_bits);
+
+    let (data, corrected_errors) = extract_data(
+        &codewords,
+        ECC_BLOCKS[ecc_level as usize][version],
+        ECC_CODEWORDS_PER_BLOCK[ecc_level as usize][version],
+        data_codewords(version, ecc_level),
+    )?;
+    let mut decoded = parse_segments(&data, Symbol::Qr(version))?;
+    decoded.version = version;
+    decoded.ecc_level = ecc_level;
+    decoded.mask = mask;
+    decoded.corrected_errors = corrected_errors;
+
+    Ok(decoded)
+}
+
+// Decode a QR code built by this crate
+pub fn decode_matrix(matrix: &Matrix) -> Result<Decoded, &'static str> {
+    decode(&matrix.export())
+}
+
+// Decode a Micro QR code from its modules, as returned by
+// micro_qr::Matrix::export, with or without the quiet zone around it
+// The last data codeword of M1 and M3 codes holds only 4 bits, which are
+// completed with 4 bits of 0s for error correction. M1 codes only detect
+// errors.
+pub fn decode_micro(modules: &[Vec<u8>]) -> Result<Decoded, &'static str> {
+    let modules = strip_quiet_zone(modules)?;
+    let size = modules.len();
+
+    if modules[0].len() != size || !(11..=17).contains(&size) || size % 2 == 0 {
+        return Err("Not a valid Micro QR code size");
+    }
+    let version = (size - 9) / 2;
+
+    let (ecc_level, mask) = match decode_micro_format(&modules)? {
+        (read_version, ecc_level, mask) if read_version == version => (ecc_level, mask),
+        _ => return Err("Format information doesn't match the size"),
+    };
+
+    // Remove the mask from all data modules
+    let function = micro_qr::function_modules(version);
+    let eval = mask_pattern(micro_qr::MASKS[mask]);
+    let mut unmasked = modules.clone();
+    for row in 0..size {
+        for col in 0..size {
+            if !function[row][col] && eval(row, col) == 0 {
+                unmasked[row][col] ^= 1;
+            }
+        }
+    }
+
+    let data_bits = micro_qr::data_bits(version, ecc_level).ok_or("Invalid Micro QR version")?;
+    let ecc_length = micro_qr::ECC_CODEWORDS[version][ecc_level as usize];
+    let bits = read_zigzag(&unmasked, &function, size - 1, None);
+    if bits.len() < data_bits + ecc_length * 8 {
+        return Err("Micro QR code holds too few modules");
+    }
+
+    // Complete a short last data codeword before the error correction
+    // codewords
+    let mut block = bits[..data_bits].to_vec();
+    block.resize(data_bits.div_ceil(8) * 8, 0);
+    block.extend_from_slice(&bits[data_bits..data_bits + ecc_length * 8]);
+
+    let (message, corrected_errors) = correct_errors(&bits_to_codewords(&block), ecc_length, &[])?;
+    if version == 1 && corrected_errors > 0 {
+        return Err("M1 codes only detect errors");
+    }
+
+    let mut data: Vec<u8> = message
+        .iter()
+        .flat_map(|codeword| (0..8).rev().map(move |n| (codeword >> n) & 1))
+        .collect();
+    data.truncate(data_bits);
+
+    let mut decoded = parse_segments(&data, Symbol::Micro(version))?;
+    decoded.version = version;
+    decoded.ecc_level = ecc_level;
+    decoded.mask = mask;
+    decoded.corrected_errors = corrected_errors;
+
+    Ok(decoded)
+}
+
+// Decode an rMQR code from its modules, as returned by
+// rmqr::Matrix::export, with or without the quiet zone around it
+pub fn decode_rmqr(modules: &[Vec<u8>]) -> Result<Decoded, &'static str> {
+    let modules = strip_quiet_zone(modules)?;
+    let (height, width) = (modules.len(), modules[0].len());
+
+    let version = match rmqr::SIZES.iter().position(|size| *size == (height, width)) {
+        Some(version) => version,
+        None => return Err("Not a valid rMQR code size"),
+    };
+    let ecc_level = match decode_rmqr_format(&modules)? {
+        (read_version, ecc_level) if read_version == version => ecc_level,
+        _ => return Err("Format information doesn't match the size"),
+    };
+
+    // Remove the mask from all data modules
+    let function = rmqr::function_modules(version);
+    let eval = mask_pattern(4);
+    let mut unmasked = modules.clone();
+    for row in 0..height {
+        for col in 0..width {
+            if !function[row][col] && eval(row, col) == 0 {
+                unmasked[row][col] ^= 1;
+            }
+        }
+    }
+
+    // Collect the codewords, ignoring the remainder bits
+    let mut data_bits = read_zigzag(&unmasked, &function, width - 2, None);
+    data_bits.truncate(rmqr::TOTAL_CODEWORDS[version] * 8);
+    let codewords = bits_to_codewords(&data_bits);
+
+    let (block_count, ecc_length) = rmqr::ecc_blocks(version, ecc_level);
+    let (data, corrected_errors) = extract_data(
+        &codewords,
+        block_count,
+        ecc_length,
+        rmqr::data_codewords(version, ecc_level),
+    )?;
+    let mut decoded = parse_segments(&data, Symbol::Rmqr(version))?;
+    decoded.version = version;
+    decoded.ecc_level = ecc_level;
+    decoded.mask = 4;
+    decoded.corrected_errors = corrected_errors;
+
+    Ok(decoded)
+}
+
+// Decode a QR, Micro QR or rMQR code, told apart by their shape: rMQR
+// codes are wider than high, Micro QR codes at most 17 modules wide
+pub fn decode_any(modules: &[Vec<u8>]) -> Result<Decoded, &'static str> {
+    let stripped = strip_quiet_zone(modules)?;
+
+    if stripped.len() != stripped[0].len() {
+        decode_rmqr(&stripped)
+    } else if stripped.len() <= 17 {
+        decode_micro(&stripped)
+    } else {
+        decode(&stripped)
+    }
+}
+
+// Cut off light rows and columns around the symbol
+// The finder and timing patterns reach into the corners of all kinds of
+// symbols, so the dark modules span the whole symbol
+fn strip_quiet_zone(modules: &[Vec<u8>]) -> Result<Vec<Vec<u8>>, &'static str> {
+    let dark_rows: Vec<usize> = (0..modules.len())
+        .filter(|&row| modules[row].contains(&1))
+        .collect();
+    let (top, bottom) = match (dark_rows.first(), dark_rows.last()) {
+        (Some(top), Some(bottom)) => (*top, *bottom),
+        _ => return Err("No QR code found"),
+    };
+
+    let width = modules.iter().map(|row| row.len()).min().unwrap_or(0);
+    let dark_cols: Vec<usize> = (0..width)
+        .filter(|&col| modules.iter().any(|row| row[col] == 1))
+        .collect();
+    let (left, right) = match (dark_cols.first(), dark_cols.last()) {
+        (Some(left), Some(right)) => (*left, *right),
+        _ => return Err("No QR code found"),
+    };
+
+    Ok(modules[top..=bottom]
+        .iter()
+        .map(|row| row[left..=right].to_vec())
+        .collect())
+}
+
+// Find the valid format string closest to one read from a QR code
+// Returns the 5 bits of format data and the number of bits that differ
+fn nearest_format(format_string: u16) -> (u16, u32) {
+    let xor_mask: u16 = 0b101010000010010;
+
+    (0..32)
+        .map(|format_data| {
+            let candidate = format_bch(format_data) ^ xor_mask;
+            (format_data, (candidate ^ format_string).count_ones())
+        })
+        .min_by_key(|(_, distance)| *distance)
+        .unwrap_or((0, u32::MAX))
+}
+
+// Read both copies of the format string from the modules of a QR code
+// (without quiet zone), in the order place_format_string writes them, and
+// return the error correction level and mask number
+// Valid format strings are at least 7 bits apart, so up to 3 damaged bits
+// are corrected. The copy closer to a valid format string wins.
+pub fn decode_format(modules: &[Vec<u8>]) -> Result<(EccLevel, usize), &'static str> {
+    let size = modules.len();
+    let mut around_finder: [u8; 15] = [0; 15];
+    let mut split: [u8; 15] = [0; 15];
+
+    for n in 0..8 {
+        // Around upper left finder pattern, skipping the timing pattern
+        if n < 6 {
+            around_finder[n] = modules[8][n];
+            around_finder[14 - n] = modules[n][8];
+        } else {
+            around_finder[n] = modules[8][n + 1];
+            around_finder[14 - n] = modules[1 + n][8];
+        }
+        // Next to lower left and upper right finder pattern
+        if n < 7 {
+            split[n] = modules[size - 1 - n][8];
+        }
+        split[14 - n] = modules[8][size - 1 - n];
+    }
+
+    let (format_data, distance) = [around_finder, split]
+        .iter()
+        .map(|bits| {
+            nearest_format(
+                bits.iter()
+                    .fold(0, |format_string, bit| format_string << 1 | *bit as u16),
+            )
+        })
+        .min_by_key(|(_, distance)| *distance)
+        .unwrap_or((0, u32::MAX));
+
+    if distance > 3 {
+        return Err("Format information is damaged");
+    }
+
+    Ok((
+        EccLevel::from_format_bits(format_data >> 3),
+        (format_data & 0b111) as usize,
+    ))
+}
+
+// Read the format string of a Micro QR code (without quiet zone) and
+// return the version, error correction level and mask number
+// There's only one copy, so up to 3 damaged bits are corrected, as with
+// QR codes
+fn decode_micro_format(modules: &[Vec<u8>]) -> Result<(usize, EccLevel, usize), &'static str> {
+    let format_string = (0..15).fold(0, |format_string, n| {
+        let (row, col) = micro_qr::format_position(n);
+        format_string | (modules[row][col] as u16) << n
+    });
+
+    let (format_data, distance) = (0..32)
+        .map(|format_data| {
+            let candidate = format_bch(format_data) ^ micro_qr::FORMAT_MASK;
+            (format_data, (candidate ^ format_string).count_ones())
+        })
+        .min_by_key(|(_, distance)| *distance)
+        .unwrap_or((0, u32::MAX));
+
+    if distance > 3 {
+        return Err("Format information is damaged");
+    }
+
+    let (version, ecc_level) = micro_qr::SYMBOLS[(format_data >> 2) as usize];
+    Ok((version, ecc_level, (format_data & 0b11) as usize))
+}
+
+// Read both copies of the format information of an rMQR code (without
+// quiet zone) and return the version and error correction level
+// Valid format strings are 18 bits long with 12 BCH bits, so up to 3
+// damaged bits are corrected. The copy closer to a valid one wins.
+fn decode_rmqr_format(modules: &[Vec<u8>]) -> Result<(usize, EccLevel), &'static str> {
+    let (height, width) = (modules.len(), modules[0].len());
+    let version = rmqr::SIZES
+        .iter()
+        .position(|size| *size == (height, width))
+        .ok_or("Not a valid rMQR code size")?;
+
+    let (format_data, distance) = [
+        (false, rmqr::FORMAT_MASKS[0]),
+        (true, rmqr::FORMAT_MASKS[1]),
+    ]
+    .iter()
+    .flat_map(|(sub_finder, xor_mask)| {
+        let format_string = rmqr::format_positions(version, *sub_finder)
+            .into_iter()
+            .enumerate()
+            .fold(0, |format_string, (n, (row, col))| {
+                format_string | (modules[row][col] as u32) << n
+            })
+            ^ xor_mask;
+
+        (0..64).map(move |format_data| {
+            let candidate = version_bch(format_data);
+            (format_data, (candidate ^ format_string).count_ones())
+        })
+    })
+    .min_by_key(|(_, distance)| *distance)
+    .unwrap_or((0, u32::MAX));
+
+    if distance > 3 {
+        return Err("Format information is damaged");
+    }
+
+    Ok((
+        (format_data & 0b11111) as usize,
+        rmqr::level(format_data >> 5),
+    ))
+}
+
+// Read both copies of the version information from the modules of a QR
+// code of version 7 or higher (without quiet zone), in the order
+// place_version_information writes them, and return the version
+// Valid version strings are at least 8 bits apart, so up to 3 damaged bits
+// are corrected
+pub fn decode_version(modules: &[Vec<u8>]) -> Result<usize, &'static str> {
+    let size = modules.len();
+    if size < 45 {
+        return Err("Only QR codes from version 7 on hold version information");
+    }
+
+    let mut lower_left: u32 = 0;
+    let mut upper_right: u32 = 0;
+    for n in 0..18 {
+        let a = size - 11 + n % 3;
+        let b = n / 3;
+        lower_left |= (modules[a][b] as u32) << n;
+        upper_right |= (modules[b][a] as u32) << n;
+    }
+
+    let (version, distance) = [lower_left, upper_right]
+        .iter()
+        .flat_map(|version_string| {
+            (7..=40).map(move |version| {
+                let candidate = version_bch(version as u32);
+                (version, (candidate ^ version_string).count_ones())
+            })
+        })
+        .min_by_key(|(_, distance)| *distance)
+        .unwrap_or((0, u32::MAX));
+
+    if distance > 3 {
+        return Err("Version information is damaged");
+    }
+
+    Ok(version)
+}
+
+// Undo the interleaving of apply_ecc and correct errors in every block
+// Returns the data codewords of all blocks in order, as bits, and the
+// number of codewords that were corrected
+fn extract_data(
+    codewords: &[u8],
+    block_count: usize,
+    ecc_length: usize,
+    data_length: usize,
+) -> Result<(Vec<u8>, usize), &'static str> {
+    // Short blocks come first, long blocks hold one more data codeword
+    let short_length = data_length / block_count;
+    let long_blocks = data_length % block_count;
+    let block_length = |n: usize| {
+        if n >= block_count - long_blocks {
+            short_length + 1
+        } else {
+            short_length
+        }
+    };
+
+    let mut blocks: Vec<Vec<u8>> = (0..block_count)
+        .map(|n| Vec::with_capacity(block_length(n) + ecc_length))
+        .collect();
+    let mut codewords = codewords.iter();
+
+    // Data codewords were taken from each block in turn, skipping blocks
+    // that were already used up, then the error correction codewords
+    for n in 0..=short_length {
+        for (m, block) in blocks.iter_mut().enumerate() {
+            if n < block_length(m) {
+                block.extend(codewords.next());
+            }
+        }
+    }
+    for _n in 0..ecc_length {
+        for block in blocks.iter_mut() {
+            block.extend(codewords.next());
+        }
+    }
+
+    let mut data: Vec<u8> = Vec::with_capacity(data_length * 8);
+    let mut corrected_errors = 0;
+    for block in blocks {
+        let (message, corrected) = correct_errors(&block, ecc_length, &[])?;
+        corrected_errors += corrected;
+
+        for codeword in message {
+            for n in (0..8).rev() {
+                data.push((codeword >> n) & 1);
+            }
+        }
+    }
+
+    Ok((data, corrected_errors))
+}
+
+// Parse mode indicators, character counts and characters until the
+// terminator or the end of the data
+// An ECI designator applies to all segments up to the next one
+fn parse_segments(data: &[u8], symbol: Symbol) -> Result<Decoded, &'static str> {
+    let mut reader = BitReader {
+        bits: data,
+        position: 0,
+    };
+    let mut eci = None;
+    let mut decoded = Decoded {
+        version: 0,
+        ecc_level: EccLevel::L,
+        mask: 0,
+        corrected_errors: 0,
+        structured_append: None,
+        gs1: false,
+        application_indicator: None,
+        segments: vec![],
+    };
+
+    // A terminator may be cut short, or left out, if the data is full
+    while !reader.at_terminator(symbol.terminator_bits()) {
+        let mode = symbol.read_mode(&mut reader)?;
+        let count = reader.read(symbol.character_count_bits(mode)?)? as usize;
+
+        let data = match mode {
+            Mode::Numeric => read_numeric(&mut reader, count)?,
+            Mode::Alphanumeric => read_alphanumeric(&mut reader, count)?,
+            Mode::Byte => (0..count)
+                .map(|_| reader.read(8).map(|byte| byte as u8))
+                .collect::<Result<Vec<u8>, &'static str>>()?,
+            Mode::Kanji => read_kanji(&mut reader, count)?,
+            Mode::Eci => {
+                eci = Some(read_eci(&mut reader)?);
+                continue;
+            }
+            Mode::StructuredAppend => {
+                let index = reader.read(4)? as usize;
+                let total = reader.read(4)? as usize + 1;
+                let parity = reader.read(8)? as u8;
+                decoded.structured_append = Some((index, total, parity));
+                continue;
+            }
+            Mode::Fnc1First => {
+                decoded.gs1 = true;
+                continue;
+            }
+            Mode::Fnc1Second => {
+                decoded.application_indicator = Some(reader.read(8)? as u8);
+                continue;
+            }
+        };
+
+        decoded.segments.push(DecodedSegment { mode, data, eci });
+    }
+
+    Ok(decoded)
+}
+
+// Groups of three digits in 10 bits, two or one digits left over in 7 or
+// 4 bits
+fn read_numeric(reader: &mut BitReader, count: usize) -> Result<Vec<u8>, &'static str> {
+    let mut digits: Vec<u8> = Vec::with_capacity(count);
+
+    while digits.len() < count {
+        let group = (count - digits.len()).min(3);
+        let value = reader.read([0, 4, 7, 10][group])?;
+        if value >= [1, 10, 100, 1000][group] {
+            return Err("Invalid digits in numeric segment");
+        }
+
+        for n in (0..group).rev() {
+            digits.push(b'0' + (value / 10u32.pow(n as u32) % 10) as u8);
+        }
+    }
+
+    Ok(digits)
+}
+
+// Pairs of characters in 11 bits, a single one left over in 6 bits
+fn read_alphanumeric(reader: &mut BitReader, count: usize) -> Result<Vec<u8>, &'static str> {
+    let mut characters: Vec<u8> = Vec::with_capacity(count);
+
+    while characters.len() < count {
+        if count - characters.len() >= 2 {
+            let value = reader.read(11)? as usize;
+            if value >= 45 * 45 {
+                return Err("Invalid characters in alphanumeric segment");
+            }
+            characters.push(ALPHANUMERIC_CHARSET[value / 45]);
+            characters.push(ALPHANUMERIC_CHARSET[value % 45]);
+        } else {
+            let value = reader.read(6)? as usize;
+            if value >= 45 {
+                return Err("Invalid characters in alphanumeric segment");
+            }
+            characters.push(ALPHANUMERIC_CHARSET[value]);
+        }
+    }
+
+    Ok(characters)
+}
+
+// 13 bits per character: split into upper and lower byte by 0xC0, then
+// add the offset of the Shift JIS range the code lies in
+fn read_kanji(reader: &mut BitReader, count: usize) -> Result<Vec<u8>, &'static str> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(count * 2);
+
+    for _n in 0..count {
+        let value = reader.read(13)?;
+        let code = ((value / 0xC0) << 8) | (value % 0xC0);
+        let code = if code < 0x1F00 {
+            code + 0x8140
+        } else {
+            code + 0xC140
+        };
+        if code > 0xFFFF {
+            return Err("Invalid characters in kanji segment");
+        }
+
+        bytes.push((code >> 8) as u8);
+        bytes.push(code as u8);
+    }
+
+    Ok(bytes)
+}
+
+// ECI designators take 1, 2 or 3 bytes, as told by their leading bits:
+// 0xxxxxxx, 10xxxxxx ..., 110xxxxx ...
+fn read_eci(reader: &mut BitReader) -> Result<u32, &'static str> {
+    let first = reader.read(8)?;
+
+    if first & 0b1000_0000 == 0 {
+        Ok(first)
+    } else if first & 0b1100_0000 == 0b1000_0000 {
+        Ok((first & 0b0011_1111) << 8 | reader.read(8)?)
+    } else if first & 0b1110_0000 == 0b1100_0000 {
+        Ok((first & 0b0001_1111) << 16 | reader.read(16)?)
+    } else {
+        Err("Invalid ECI designator")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qr_code::{
+        DefaultPenalty, MaskSelection, Segment, build_matrix, build_matrix_masked, select_version,
+    };
+
+    fn round_trip(segments: &[Segment], ecc_level: EccLevel) -> Decoded {
+        let version = select_version(segments, ecc_level).unwrap();
+        decode_matrix(&build_matrix(segments, version, ecc_level)).unwrap()
+    }
+
+    #[test]
+    fn every_segment_keeps_its_eci() {
+        let segments = [
+            Segment::eci(3).unwrap(),
+            Segment::bytes(&[0xE9]),
+            Segment::eci(26).unwrap(),
+            Segment::bytes("é".as_bytes()),
+            Segment::eci(20).unwrap(),
+            Segment::bytes(&[0x83, 0x41]),
+        ];
+        let decoded = round_trip(&segments, EccLevel::M);
+
+        let ecis: Vec<Option<u32>> = decoded.segments.iter().map(|segment| segment.eci).collect();
+        assert_eq!(ecis, vec![Some(3), Some(26), Some(20)]);
+        assert_eq!(decoded.text().as_deref(), Some("ééア"));
+    }
+
+    #[test]
+    fn bytes_without_eci_are_iso_8859_1() {
+        let decoded = round_trip(&[Segment::bytes(&[0x41, 0xFC])], EccLevel::L);
+
+        assert_eq!(decoded.segments[0].eci, None);
+        assert_eq!(decoded.text().as_deref(), Some("Aü"));
+    }
+
+    // Positions of both copies of the format string, in the order
+    // decode_format reads them
+    fn format_positions(size: usize) -> [[(usize, usize); 15]; 2] {
+        let mut around_finder = [(0, 0); 15];
+        let mut split = [(0, 0); 15];
+        for n in 0..8 {
+            if n < 6 {
+                around_finder[n] = (8, n);
+                around_finder[14 - n] = (n, 8);
+            } else {
+                around_finder[n] = (8, n + 1);
+                around_finder[14 - n] = (1 + n, 8);
+            }
+            if n < 7 {
+                split[n] = (size - 1 - n, 8);
+            }
+            split[14 - n] = (8, size - 1 - n);
+        }
+
+        [around_finder, split]
+    }
+
+    // All sets of up to 3 of the 15 format bits
+    fn flips() -> Vec<Vec<usize>> {
+        let mut flips = vec![vec![]];
+        for a in 0..15 {
+            flips.push(vec![a]);
+            for b in a + 1..15 {
+                flips.push(vec![a, b]);
+                for c in b + 1..15 {
+                    flips.push(vec![a, b, c]);
+                }
+            }
+        }
+
+        flips
+    }
+
+    #[test]
+    fn format_survives_3_flipped_bits_in_each_copy() {
+        let segments = [Segment::bytes(b"format")];
+        let (matrix, _choice) = build_matrix_masked(
+            &segments,
+            1,
+            EccLevel::Q,
+            MaskSelection::Forced(5),
+            &DefaultPenalty,
+        )
+        .unwrap();
+        let modules = strip_quiet_zone(&matrix.export()).unwrap();
+        let [around_finder, split] = format_positions(modules.len());
+
+        for flipped in flips() {
+            // Either copy damaged alone, and both damaged at once
+            for copies in [vec![around_finder], vec![split], vec![around_finder, split]] {
+                let mut damaged = modules.clone();
+                for copy in &copies {
+                    for bit in &flipped {
+                        let (row, col) = copy[*bit];
+                        damaged[row][col] ^= 1;
+                    }
+                }
+                assert_eq!(decode_format(&damaged), Ok((EccLevel::Q, 5)));
+            }
+        }
+
+        // A copy damaged beyond repair leaves the other one
+        let mut damaged = modules.clone();
+        for (row, col) in &around_finder[..4] {
+            damaged[*row][*col] ^= 1;
+        }
+        assert_eq!(decode_format(&damaged), Ok((EccLevel::Q, 5)));
+    }
+
+    #[test]
+    fn micro_qr_codes_read_back() {
+        for (version, ecc_level) in micro_qr::SYMBOLS {
+            let matrix = micro_qr::build_matrix(&[Segment::numeric(b"01234")], version, ecc_level);
+            let decoded = decode_micro(&matrix.export()).unwrap();
+
+            assert_eq!((decoded.version, decoded.ecc_level), (version, ecc_level));
+            assert_eq!(decoded.corrected_errors, 0);
+            assert_eq!(decoded.segments[0].mode, Mode::Numeric);
+            assert_eq!(decoded.bytes(), b"01234");
+        }
+
+        // Every mode M4 codes offer
+        let segments = [
+            Segment::alphanumeric(&[10, 36, 0]),
+            Segment::bytes(b"ab"),
+            Segment::kanji(&[0x8341]),
+        ];
+        let matrix = micro_qr::build_matrix(&segments, 4, EccLevel::L);
+        let modes: Vec<Mode> = decode_micro(&matrix.export())
+            .unwrap()
+            .segments
+            .iter()
+            .map(|segment| segment.mode)
+            .collect();
+        assert_eq!(modes, vec![Mode::Alphanumeric, Mode::Byte, Mode::Kanji]);
+    }
+
+    #[test]
+    fn micro_qr_errors_are_corrected_from_m2_on() {
+        for (version, ecc_level) in [(1, EccLevel::L), (2, EccLevel::L)] {
+            let matrix = micro_qr::build_matrix(&[Segment::numeric(b"01234")], version, ecc_level);
+            let mut modules = strip_quiet_zone(&matrix.export()).unwrap();
+            let last = modules.len() - 1;
+            modules[last][last] ^= 1;
+
+            let decoded = decode_micro(&modules);
+            if version == 1 {
+                assert_eq!(decoded, Err("M1 codes only detect errors"));
+            } else {
+                assert_eq!(decoded.unwrap().corrected_errors, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn rmqr_codes_of_every_size_read_back() {
+        for version in 0..rmqr::SIZES.len() {
+            for ecc_level in [EccLevel::M, EccLevel::H] {
+                let matrix = rmqr::build_matrix(&[Segment::numeric(b"01234")], version, ecc_level);
+                let decoded = decode_rmqr(&matrix.export()).unwrap();
+
+                assert_eq!((decoded.version, decoded.ecc_level), (version, ecc_level));
+                assert_eq!(decoded.bytes(), b"01234");
+            }
+        }
+
+        let segments = [Segment::eci(26).unwrap(), Segment::bytes("é".as_bytes())];
+        let matrix = rmqr::build_matrix(&segments, 10, EccLevel::M);
+        let decoded = decode_rmqr(&matrix.export()).unwrap();
+        assert_eq!(decoded.segments[0].eci, Some(26));
+        assert_eq!(decoded.text().as_deref(), Some("é"));
+    }
+
+    #[test]
+    fn decode_any_tells_symbols_apart_by_shape() {
+        let segments = [Segment::numeric(b"01234")];
+
+        let qr = build_matrix(&segments, 1, EccLevel::M).export();
+        let micro = micro_qr::build_matrix(&segments, 2, EccLevel::M).export();
+        let rmqr = rmqr::build_matrix(&segments, 0, EccLevel::M).export();
+
+        for (modules, version) in [(qr, 1), (micro, 2), (rmqr, 0)] {
+            let decoded = decode_any(&modules).unwrap();
+            assert_eq!(decoded.version, version);
+            assert_eq!(decoded.bytes(), b"01234");
+        }
+    }
+}
@@ -0,0 +1,839 @@
+use crate::decoder::{Decoded, decode, decode_version};
+use crate::png::GrayImage;
+
+// A black and white image, one bool per pixel (true - dark), row by row
+// from the top
+pub struct BinaryImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<bool>,
+}
+
+impl BinaryImage {
+    // Whether the pixel in a given column and row is dark, None outside
+    // the image
+    pub fn is_dark(&self, x: isize, y: isize) -> Option<bool> {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return None;
+        }
+
+        Some(self.pixels[y as usize * self.width + x as usize])
+    }
+}
+
+// Side length of the blocks the threshold is calculated for
+const BLOCK_SIZE: usize = 8;
+
+// Blocks whose brightest and darkest pixel differ by no more than this are
+// taken to be of a single color
+const MIN_CONTRAST: u8 = 24;
+
+// Turn a grayscale image into black and white with a threshold that adapts
+// to uneven lighting
+// The image is divided into blocks of 8 x 8 pixels. Each block gets the
+// average brightness of its pixels. A block without contrast lies within a
+// dark or light area: it gets the level of the blocks above and to the
+// left if it is darker than those, and half its darkest pixel otherwise.
+// Every pixel is then compared with the average level of the 5 x 5 blocks
+// around its own.
+pub fn binarize(image: &GrayImage) -> BinaryImage {
+    let columns = image.width.div_ceil(BLOCK_SIZE);
+    let rows = image.height.div_ceil(BLOCK_SIZE);
+    let mut levels: Vec<u32> = vec![0; columns * rows];
+
+    for row in 0..rows {
+        for col in 0..columns {
+            let mut sum: u32 = 0;
+            let mut count: u32 = 0;
+            let mut darkest = 255;
+            let mut brightest = 0;
+
+            for y in row * BLOCK_SIZE..((row + 1) * BLOCK_SIZE).min(image.height) {
+                for x in col * BLOCK_SIZE..((col + 1) * BLOCK_SIZE).min(image.width) {
+                    let pixel = image.pixel(x, y);
+                    sum += pixel as u32;
+                    count += 1;
+                    darkest = darkest.min(pixel);
+                    brightest = brightest.max(pixel);
+                }
+            }
+
+            let mut level = sum / count;
+            if brightest - darkest <= MIN_CONTRAST {
+                level = darkest as u32 / 2;
+                if row > 0 && col > 0 {
+                    let neighbors = (levels[(row - 1) * columns + col]
+                        + 2 * levels[row * columns + col - 1]
+                        + levels[(row - 1) * columns + col - 1])
+                        / 4;
+                    if (darkest as u32) < neighbors {
+                        level = neighbors;
+                    }
+                }
+            }
+            levels[row * columns + col] = level;
+        }
+    }
+
+    let mut binary = BinaryImage {
+        width: image.width,
+        height: image.height,
+        pixels: vec![false; image.width * image.height],
+    };
+
+    for row in 0..rows {
+        for col in 0..columns {
+            let mut sum = 0;
+            let mut count = 0;
+            for r in row.saturating_sub(2)..(row + 3).min(rows) {
+                for c in col.saturating_sub(2)..(col + 3).min(columns) {
+                    sum += levels[r * columns + c];
+                    count += 1;
+                }
+            }
+            let threshold = sum / count;
+
+            for y in row * BLOCK_SIZE..((row + 1) * BLOCK_SIZE).min(image.height) {
+                for x in col * BLOCK_SIZE..((col + 1) * BLOCK_SIZE).min(image.width) {
+                    binary.pixels[y * image.width + x] = image.pixel(x, y) as u32 <= threshold;
+                }
+            }
+        }
+    }
+
+    binary
+}
+
+// Center of a finder or alignment pattern in the image, and the estimated
+// width of a module around it, in pixels
+#[derive(Clone, Copy, Debug)]
+struct Pattern {
+    x: f64,
+    y: f64,
+    module: f64,
+    // Number of scan lines the pattern was found on
+    count: usize,
+}
+
+impl Pattern {
+    fn distance(&self, other: &Pattern) -> f64 {
+        ((self.x - other.x).powi(2) + (self.y - other.y).powi(2)).sqrt()
+    }
+}
+
+// Check whether five runs of alternating colors (dark, light, dark, light,
+// dark) have the widths 1:1:3:1:1 of a line through a finder pattern
+// Each run may be off by half a module
+fn is_finder_ratio(counts: &[usize; 5]) -> bool {
+    let total: usize = counts.iter().sum();
+    if total < 7 {
+        return false;
+    }
+
+    let module = total as f64 / 7.0;
+    let variance = module / 2.0;
+
+    counts
+        .iter()
+        .zip([1.0, 1.0, 3.0, 1.0, 1.0])
+        .all(|(count, modules)| (*count as f64 - module * modules).abs() < variance * modules)
+}
+
+// Check whether the three inner runs of a line through an alignment
+// pattern (light, dark, light) are a module wide each
+// The outer dark runs merge with dark data modules, only their presence
+// counts
+fn is_alignment_ratio(counts: &[usize; 5], module: f64) -> bool {
+    counts[0] > 0
+        && counts[4] > 0
+        && counts[1..4]
+            .iter()
+            .all(|count| (*count as f64 - module).abs() < module / 2.0)
+}
+
+// Measure the five runs of alternating colors along a line through a dark
+// point, in both directions
+// `is_dark` tells the color at an offset from the point along the line.
+// Runs stop growing at `max`, which the ratio checks then reject.
+// Returns the widths of the runs and the offset of the center of the
+// middle run
+fn runs_through(is_dark: impl Fn(isize) -> Option<bool>, max: usize) -> Option<([usize; 5], f64)> {
+    if is_dark(0) != Some(true) {
+        return None;
+    }
+
+    let mut counts = [0; 5];
+
+    // Middle run and the runs before it, going backward
+    let mut offset = 0;
+    for (run, dark) in [(2, true), (1, false), (0, true)] {
+        while is_dark(offset) == Some(dark) && counts[run] < max {
+            counts[run] += 1;
+            offset -= 1;
+        }
+    }
+
+    // Rest of the middle run and the runs after it, going forward
+    let mut offset = 1;
+    for (run, dark) in [(2, true), (3, false), (4, true)] {
+        while is_dark(offset) == Some(dark) && counts[run] < max {
+            counts[run] += 1;
+            offset += 1;
+        }
+    }
+
+    if counts.contains(&0) {
+        return None;
+    }
+
+    let center = (offset - counts[4] as isize - counts[3] as isize) as f64 - counts[2] as f64 / 2.0;
+
+    Some((counts, center))
+}
+
+// Confirm a finder pattern found on a row by measuring it along the column
+// through its center, then along the row through the corrected center
+// Returns the pattern if both lines show the 1:1:3:1:1 ratio at a similar
+// size
+fn confirm_finder(image: &BinaryImage, x: f64, y: usize, width: usize) -> Option<Pattern> {
+    let col = x as isize;
+    let (vertical, offset) = runs_through(|n| image.is_dark(col, y as isize + n), width * 2)?;
+    let height: usize = vertical.iter().sum();
+    if !is_finder_ratio(&vertical) || height.abs_diff(width) * 5 > width * 2 {
+        return None;
+    }
+
+    let center_y = y as f64 + offset;
+    let row = center_y as isize;
+    let (horizontal, offset) = runs_through(|n| image.is_dark(col + n, row), width * 2)?;
+    let width: usize = horizontal.iter().sum();
+    if !is_finder_ratio(&horizontal) {
+        return None;
+    }
+
+    Some(Pattern {
+        x: col as f64 + offset,
+        y: center_y,
+        module: (width + height) as f64 / 14.0,
+        count: 1,
+    })
+}
+
+// Scan every row of the image for the 1:1:3:1:1 runs of a finder pattern
+// and confirm them along the column. Patterns found on several rows are
+// merged, the more rows the more reliable.
+fn find_finder_patterns(image: &BinaryImage) -> Vec<Pattern> {
+    let mut patterns: Vec<Pattern> = vec![];
+
+    for y in 0..image.height {
+        let mut counts = [0; 5];
+        let mut run = 0;
+
+        for x in 0..=image.width {
+            let dark = x < image.width && image.pixels[y * image.width + x];
+
+            // Runs start with a dark one
+            if run == 0 && counts[0] == 0 && !dark {
+                continue;
+            }
+
+            // Same color as the current run: extend it
+            if dark == (run % 2 == 0) {
+                counts[run] += 1;
+                continue;
+            }
+
+            // The color changes after the fifth run: check for a finder
+            // pattern, then keep the last three runs
+            if run == 4 {
+                if is_finder_ratio(&counts) {
+                    let width: usize = counts.iter().sum();
+                    let center = (x - counts[4] - counts[3]) as f64 - counts[2] as f64 / 2.0;
+                    if let Some(pattern) = confirm_finder(image, center, y, width) {
+                        add_pattern(&mut patterns, pattern);
+                    }
+                }
+                counts = [counts[2], counts[3], counts[4], 1, 0];
+                run = 3;
+                continue;
+            }
+
+            run += 1;
+            counts[run] = 1;
+        }
+    }
+
+    patterns
+}
+
+// Merge a pattern with one found before at nearly the same spot and of
+// nearly the same size, or add it as a new one
+fn add_pattern(patterns: &mut Vec<Pattern>, pattern: Pattern) {
+    for known in patterns.iter_mut() {
+        if known.distance(&pattern) < known.module * 2.0
+            && (known.module - pattern.module).abs() < known.module
+        {
+            let count = known.count as f64;
+            known.x = (known.x * count + pattern.x) / (count + 1.0);
+            known.y = (known.y * count + pattern.y) / (count + 1.0);
+            known.module = (known.module * count + pattern.module) / (count + 1.0);
+            known.count += 1;
+            return;
+        }
+    }
+
+    patterns.push(pattern);
+}
+
+// Choose the three finder patterns of a symbol and put them in order:
+// upper left, upper right, lower left
+// The three should be of about the same size, and form an isosceles right
+// triangle with the upper left one in the right angle
+fn select_finder_patterns(mut patterns: Vec<Pattern>) -> Option<[Pattern; 3]> {
+    // Noise may look like a finder pattern on a single row, real ones show
+    // on several, about as many as the most reliable pattern
+    let most = patterns
+        .iter()
+        .map(|pattern| pattern.count)
+        .max()
+        .unwrap_or(0);
+    let least = (most / 4).max(2);
+    if patterns
+        .iter()
+        .filter(|pattern| pattern.count >= least)
+        .count()
+        >= 3
+    {
+        patterns.retain(|pattern| pattern.count >= least);
+    }
+    patterns.sort_by_key(|pattern| std::cmp::Reverse(pattern.count));
+    patterns.truncate(10);
+
+    let mut best: Option<(f64, [Pattern; 3])> = None;
+    for a in 0..patterns.len() {
+        for b in a + 1..patterns.len() {
+            for c in b + 1..patterns.len() {
+                let triple = [patterns[a], patterns[b], patterns[c]];
+                let modules: Vec<f64> = triple.iter().map(|pattern| pattern.module).collect();
+                let smallest = modules.iter().cloned().fold(f64::MAX, f64::min);
+                let largest = modules.iter().cloned().fold(0.0, f64::max);
+                if largest > smallest * 1.5 {
+                    continue;
+                }
+
+                // The upper left pattern lies opposite the longest side
+                let sides = [
+                    triple[1].distance(&triple[2]),
+                    triple[0].distance(&triple[2]),
+                    triple[0].distance(&triple[1]),
+                ];
+                let corner = (0..3)
+                    .max_by(|n, m| sides[*n].total_cmp(&sides[*m]))
+                    .unwrap_or(0);
+                let (short_1, short_2) = (sides[(corner + 1) % 3], sides[(corner + 2) % 3]);
+
+                // Finder patterns are 14 to 170 modules apart, allow for
+                // some error in the module size
+                if short_1.min(short_2) < largest * 10.0 || short_1.max(short_2) > smallest * 200.0
+                {
+                    continue;
+                }
+
+                let score = (short_1 - short_2).abs() / short_1.max(short_2)
+                    + (short_1.powi(2) + short_2.powi(2) - sides[corner].powi(2)).abs()
+                        / sides[corner].powi(2);
+                if best
+                    .as_ref()
+                    .is_none_or(|(best_score, _)| score < *best_score)
+                {
+                    let upper_left = triple[corner];
+                    let mut upper_right = triple[(corner + 1) % 3];
+                    let mut lower_left = triple[(corner + 2) % 3];
+
+                    // Going from upper right to lower left turns clockwise
+                    // around the upper left pattern (with y pointing down)
+                    let cross = (upper_right.x - upper_left.x) * (lower_left.y - upper_left.y)
+                        - (upper_right.y - upper_left.y) * (lower_left.x - upper_left.x);
+                    if cross < 0.0 {
+                        std::mem::swap(&mut upper_right, &mut lower_left);
+                    }
+
+                    best = Some((score, [upper_left, upper_right, lower_left]));
+                }
+            }
+        }
+    }
+
+    best.map(|(_, finders)| finders)
+}
+
+// Check the 5x5 modules around a possible alignment pattern center: a dark
+// center, a light ring and a dark ring. `axes` step one module along the
+// symbol's rows and columns. Allows for two modules read wrong.
+// Data modules often pass the checks along a single row and column, but
+// rarely the whole pattern
+fn is_alignment_pattern(image: &BinaryImage, x: f64, y: f64, axes: [(f64, f64); 2]) -> bool {
+    let mut wrong = 0;
+
+    for row in -2i32..=2 {
+        for col in -2i32..=2 {
+            let dark = row.abs().max(col.abs()) != 1;
+            let px = x + col as f64 * axes[0].0 + row as f64 * axes[1].0;
+            let py = y + col as f64 * axes[0].1 + row as f64 * axes[1].1;
+            if image.is_dark(px.floor() as isize, py.floor() as isize) != Some(dark) {
+                wrong += 1;
+            }
+        }
+    }
+
+    wrong <= 2
+}
+
+// Search for an alignment pattern around its estimated position, in an
+// area of growing size, and return the one found closest to the estimate
+// `module` is the width of a module along rows and columns of the image
+fn find_alignment_pattern(
+    image: &BinaryImage,
+    x: f64,
+    y: f64,
+    module: f64,
+    axes: [(f64, f64); 2],
+) -> Option<Pattern> {
+    let max = (module * 3.0).ceil() as usize;
+
+    for allowance in [4.0, 8.0, 16.0] {
+        let reach = (module * allowance) as isize;
+        let mut best: Option<(f64, Pattern)> = None;
+
+        for row in y as isize - reach..=y as isize + reach {
+            for col in x as isize - reach..=x as isize + reach {
+                let Some((horizontal, offset)) = runs_through(|n| image.is_dark(col + n, row), max)
+                else {
+                    continue;
+                };
+                if !is_alignment_ratio(&horizontal, module) {
+                    continue;
+                }
+
+                let center_x = col as f64 + offset;
+                let Some((vertical, offset)) =
+                    runs_through(|n| image.is_dark(center_x as isize, row + n), max)
+                else {
+                    continue;
+                };
+                if !is_alignment_ratio(&vertical, module) {
+                    continue;
+                }
+                let center_y = row as f64 + offset;
+                if !is_alignment_pattern(image, center_x, center_y, axes) {
+                    continue;
+                }
+
+                let pattern = Pattern {
+                    x: center_x,
+                    y: center_y,
+                    module,
+                    count: 1,
+                };
+                let distance = ((pattern.x - x).powi(2) + (pattern.y - y).powi(2)).sqrt();
+                if best
+                    .as_ref()
+                    .is_none_or(|(best_distance, _)| distance < *best_distance)
+                {
+                    best = Some((distance, pattern));
+                }
+            }
+        }
+
+        if let Some((_, pattern)) = best {
+            return Some(pattern);
+        }
+    }
+
+    None
+}
+
+// Width of a finder pattern along the line from its center toward another
+// pattern: the distance to the far edge of its outer ring, in both
+// directions
+// Unlike runs along rows and columns, this doesn't grow when the symbol is
+// rotated
+fn width_toward(image: &BinaryImage, from: &Pattern, to: &Pattern) -> Option<f64> {
+    let length = from.distance(to);
+    let (dx, dy) = ((to.x - from.x) / length, (to.y - from.y) / length);
+    let mut width = 0.0;
+
+    for direction in [1.0, -1.0] {
+        // Dark center, light ring, dark ring, then light again
+        let mut changes = 0;
+        let mut dark = true;
+        let mut step = 0.0;
+
+        while changes < 3 {
+            step += 1.0;
+            if step > length / 2.0 {
+                return None;
+            }
+            let x = from.x + direction * dx * step;
+            let y = from.y + direction * dy * step;
+            if image.is_dark(x.floor() as isize, y.floor() as isize)? != dark {
+                dark = !dark;
+                changes += 1;
+            }
+        }
+
+        width += step;
+    }
+
+    Some(width)
+}
+
+// Projective transformation between two planes, as a 3 x 3 matrix
+// Maps (x, y) to ((a x + b y + c) / (g x + h y + i), (d x + e y + f) /
+// (g x + h y + i))
+#[derive(Clone, Copy)]
+struct Perspective([f64; 9]);
+
+impl Perspective {
+    // Transformation from the unit square onto a quadrilateral, corners
+    // given in the order (0, 0), (1, 0), (1, 1), (0, 1)
+    fn square_to_quad(corners: [(f64, f64); 4]) -> Perspective {
+        let [(x0, y0), (x1, y1), (x2, y2), (x3, y3)] = corners;
+        let dx3 = x0 - x1 + x2 - x3;
+        let dy3 = y0 - y1 + y2 - y3;
+
+        // Parallelograms need no perspective
+        if dx3.abs() < 1e-9 && dy3.abs() < 1e-9 {
+            return Perspective([x1 - x0, x2 - x1, x0, y1 - y0, y2 - y1, y0, 0.0, 0.0, 1.0]);
+        }
+
+        let dx1 = x1 - x2;
+        let dx2 = x3 - x2;
+        let dy1 = y1 - y2;
+        let dy2 = y3 - y2;
+        let denominator = dx1 * dy2 - dx2 * dy1;
+        let g = (dx3 * dy2 - dx2 * dy3) / denominator;
+        let h = (dx1 * dy3 - dx3 * dy1) / denominator;
+
+        Perspective([
+            x1 - x0 + g * x1,
+            x3 - x0 + h * x3,
+            x0,
+            y1 - y0 + g * y1,
+            y3 - y0 + h * y3,
+            y0,
+            g,
+            h,
+            1.0,
+        ])
+    }
+
+    // Inverse transformation, up to a factor, which doesn't matter
+    fn adjugate(&self) -> Perspective {
+        let [a, b, c, d, e, f, g, h, i] = self.0;
+
+        Perspective([
+            e * i - f * h,
+            c * h - b * i,
+            b * f - c * e,
+            f * g - d * i,
+            a * i - c * g,
+            c * d - a * f,
+            d * h - e * g,
+            b * g - a * h,
+            a * e - b * d,
+        ])
+    }
+
+    // Apply this transformation after another one
+    fn after(&self, other: &Perspective) -> Perspective {
+        let p = self.0;
+        let q = other.0;
+        let mut product = [0.0; 9];
+
+        for row in 0..3 {
+            for col in 0..3 {
+                product[row * 3 + col] = (0..3).map(|n| p[row * 3 + n] * q[n * 3 + col]).sum();
+            }
+        }
+
+        Perspective(product)
+    }
+
+    // Transformation from one quadrilateral onto another, by way of the
+    // unit square
+    fn quad_to_quad(from: [(f64, f64); 4], to: [(f64, f64); 4]) -> Perspective {
+        Perspective::square_to_quad(to).after(&Perspective::square_to_quad(from).adjugate())
+    }
+
+    fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+        let [a, b, c, d, e, f, g, h, i] = self.0;
+        let denominator = g * x + h * y + i;
+
+        (
+            (a * x + b * y + c) / denominator,
+            (d * x + e * y + f) / denominator,
+        )
+    }
+}
+
+// Sample the color at the center of every module, mapping module
+// coordinates to image coordinates with a perspective transformation
+// Module coordinates run from 0 to `size` along both axes
+fn sample_grid(
+    image: &BinaryImage,
+    size: usize,
+    transform: &Perspective,
+) -> Result<Vec<Vec<u8>>, &'static str> {
+    let mut grid: Vec<Vec<u8>> = vec![vec![0; size]; size];
+
+    for (row, line) in grid.iter_mut().enumerate() {
+        for (col, module) in line.iter_mut().enumerate() {
+            let (x, y) = transform.apply(col as f64 + 0.5, row as f64 + 0.5);
+            match image.is_dark(x.floor() as isize, y.floor() as isize) {
+                Some(dark) => *module = dark as u8,
+                None => return Err("QR code reaches beyond the image"),
+            }
+        }
+    }
+
+    Ok(grid)
+}
+
+// Map the centers of finder patterns and of the lower right alignment
+// pattern (or the corner opposite the upper left finder pattern, without
+// one) from module coordinates to the image, and sample the modules
+fn sample_symbol(
+    image: &BinaryImage,
+    finders: &[Pattern; 3],
+    size: usize,
+    module: f64,
+) -> Result<Vec<Vec<u8>>, &'static str> {
+    let [upper_left, upper_right, lower_left] = finders;
+    let far = size as f64 - 3.5;
+
+    // Where the fourth corner would be if the symbol were a parallelogram
+    let corner_x = upper_right.x + lower_left.x - upper_left.x;
+    let corner_y = upper_right.y + lower_left.y - upper_left.y;
+
+    let mut fourth = ((far, far), (corner_x, corner_y));
+
+    // From version 2 on, the lower right alignment pattern lies 3 modules
+    // further in, and reveals any perspective distortion
+    if size > 21 {
+        let inward = 1.0 - 3.0 / (far - 3.5);
+        let estimate_x = upper_left.x + inward * (corner_x - upper_left.x);
+        let estimate_y = upper_left.y + inward * (corner_y - upper_left.y);
+
+        // Rows and columns cross the modules of a rotated symbol at an
+        // angle, and so they look wider
+        let angle = (upper_right.y - upper_left.y).atan2(upper_right.x - upper_left.x);
+        let width = module / angle.cos().abs().max(angle.sin().abs());
+
+        // One module along the rows and columns of the symbol
+        let axes = [
+            (
+                (upper_right.x - upper_left.x) / (far - 3.5),
+                (upper_right.y - upper_left.y) / (far - 3.5),
+            ),
+            (
+                (lower_left.x - upper_left.x) / (far - 3.5),
+                (lower_left.y - upper_left.y) / (far - 3.5),
+            ),
+        ];
+
+        if let Some(alignment) = find_alignment_pattern(image, estimate_x, estimate_y, width, axes)
+        {
+            fourth = ((far - 3.0, far - 3.0), (alignment.x, alignment.y));
+        }
+    }
+
+    let transform = Perspective::quad_to_quad(
+        [(3.5, 3.5), (far, 3.5), fourth.0, (3.5, far)],
+        [
+            (upper_left.x, upper_left.y),
+            (upper_right.x, upper_right.y),
+            fourth.1,
+            (lower_left.x, lower_left.y),
+        ],
+    );
+
+    sample_grid(image, size, &transform)
+}
+
+// Locate a QR code in a grayscale image, such as a photo, and read the
+// color of each of its modules
+// Finds the three finder patterns, estimates the module size and version
+// from their distance, refines the geometry with the lower right alignment
+// pattern, and samples the modules through a perspective transformation.
+// From version 7 on, the version information overrides the estimate.
+// Returns the modules in the same shape Matrix holds them, without quiet
+// zone (0 - white, 1 - black)
+pub fn detect(image: &GrayImage) -> Result<Vec<Vec<u8>>, &'static str> {
+    let binary = binarize(image);
+
+    let finders = match select_finder_patterns(find_finder_patterns(&binary)) {
+        Some(finders) => finders,
+        None => return Err("No QR code found"),
+    };
+    let [upper_left, upper_right, lower_left] = &finders;
+
+    // Measure the finder patterns toward each other: they are 7 modules
+    // wide. The median ignores a line that runs into a smudge. Fall back on
+    // the size found along rows and columns.
+    let mut widths: Vec<f64> = [
+        (upper_left, upper_right),
+        (upper_right, upper_left),
+        (upper_left, lower_left),
+        (lower_left, upper_left),
+    ]
+    .iter()
+    .filter_map(|(from, to)| width_toward(&binary, from, to))
+    .collect();
+    widths.sort_by(f64::total_cmp);
+    let module = if widths.is_empty() {
+        finders.iter().map(|pattern| pattern.module).sum::<f64>() / 3.0
+    } else {
+        (widths[(widths.len() - 1) / 2] + widths[widths.len() / 2]) / 14.0
+    };
+
+    // Finder pattern centers lie 7 modules closer together than the
+    // symbol is wide. Sizes are 1 more than a multiple of 4.
+    let distance = (upper_left.distance(upper_right) + upper_left.distance(lower_left)) / 2.0;
+    let estimate = (distance / module).round() as usize + 7;
+    let size = match estimate % 4 {
+        0 => estimate + 1,
+        2 => estimate - 1,
+        3 => estimate + 2,
+        _ => estimate,
+    };
+    if !(21..=177).contains(&size) {
+        return Err("No QR code found");
+    }
+
+    let grid = sample_symbol(&binary, &finders, size, module)?;
+
+    // From version 7 on, the version information tells the size. It shows
+    // a misjudged version, and confirms one a version off where the
+    // estimate fails to.
+    if size < 45 {
+        return Ok(grid);
+    }
+    for candidate in [size, size + 4, size - 4] {
+        if !(45..=177).contains(&candidate) {
+            continue;
+        }
+        let sampled = if candidate == size {
+            grid.clone()
+        } else {
+            let module = distance / (candidate - 7) as f64;
+            match sample_symbol(&binary, &finders, candidate, module) {
+                Ok(sampled) => sampled,
+                Err(_) => continue,
+            }
+        };
+
+        if let Ok(version) = decode_version(&sampled) {
+            let size = version * 4 + 17;
+            if size == candidate {
+                return Ok(sampled);
+            }
+            let module = distance / (size - 7) as f64;
+            return sample_symbol(&binary, &finders, size, module);
+        }
+    }
+
+    Ok(grid)
+}
+
+// Locate and decode a QR code in a grayscale image
+pub fn scan(image: &GrayImage) -> Result<Decoded, &'static str> {
+    decode(&detect(image)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qr_code::{EccLevel, Segment, build_matrix, select_version};
+
+    // Draw a QR code (with quiet zone) onto an image of the given size,
+    // its corners at the given points, clockwise from the upper left
+    // Sampled at 4 points per pixel for soft edges, with lighting that
+    // fades from left to right
+    fn render(
+        modules: &[Vec<u8>],
+        corners: [(f64, f64); 4],
+        width: usize,
+        height: usize,
+    ) -> GrayImage {
+        let size = modules.len() as f64;
+        let to_modules = Perspective::quad_to_quad(
+            corners,
+            [(0.0, 0.0), (size, 0.0), (size, size), (0.0, size)],
+        );
+
+        let mut pixels: Vec<u8> = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut brightness = 0.0;
+                for (dx, dy) in [(0.25, 0.25), (0.75, 0.25), (0.25, 0.75), (0.75, 0.75)] {
+                    let (col, row) = to_modules.apply(x as f64 + dx, y as f64 + dy);
+                    let dark = col >= 0.0
+                        && row >= 0.0
+                        && col < size
+                        && row < size
+                        && modules[row as usize][col as usize] == 1;
+                    brightness += if dark { 40.0 } else { 220.0 } / 4.0;
+                }
+                let lighting = 0.6 + 0.4 * x as f64 / width as f64;
+                pixels.push((brightness * lighting) as u8);
+            }
+        }
+
+        GrayImage {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    fn symbol(password: &[u8], ecc_level: EccLevel) -> Vec<Vec<u8>> {
+        let segments = [Segment::bytes(password)];
+        let version = select_version(&segments, ecc_level).unwrap();
+
+        build_matrix(&segments, version, ecc_level).export()
+    }
+
+    #[test]
+    fn scans_a_rotated_and_scaled_render() {
+        let password = b"correct horse battery staple";
+        let modules = symbol(password, EccLevel::M);
+
+        // About 4.6 pixels per module, turned by 30 degrees
+        let size = modules.len() as f64 * 4.6;
+        let (sin, cos) = 30f64.to_radians().sin_cos();
+        let center = (150.0, 150.0);
+        let corners = [(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)].map(|(x, y)| {
+            (
+                center.0 + size * (x * cos - y * sin),
+                center.1 + size * (x * sin + y * cos),
+            )
+        });
+
+        let decoded = scan(&render(&modules, corners, 300, 300)).unwrap();
+        assert_eq!(decoded.bytes(), password);
+    }
+
+    #[test]
+    fn scans_a_tilted_render_with_version_information() {
+        let password: Vec<u8> = (0..150).map(|n| (n * 7 + 3) as u8).collect();
+        let modules = symbol(&password, EccLevel::L);
+        assert!(modules.len() >= 45 + 8);
+
+        // Seen at an angle, the right edge farther away than the left one
+        let corners = [(40.0, 30.0), (330.0, 45.0), (325.0, 335.0), (45.0, 350.0)];
+
+        let decoded = scan(&render(&modules, corners, 380, 400)).unwrap();
+        assert_eq!(decoded.bytes(), password);
+    }
+}
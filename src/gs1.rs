@@ -0,0 +1,477 @@
+// GS1 element strings: a sequence of Application Identifiers (AIs), each
+// followed by its data field, e.g. (01)09501101530003(21)A1B2C3
+// In a QR code, the AIs and data fields are written without brackets,
+// behind an FNC1 first position mode indicator. Data fields of variable
+// length are ended by a group separator (GS, 0x1D), which alphanumeric
+// mode writes as %.
+
+use Charset::{Cset82, Numeric};
+
+// Group separator between a variable length data field and the next AI
+pub const GROUP_SEPARATOR: u8 = 0x1D;
+
+// An Application Identifier and its data field
+#[derive(Clone, Debug, PartialEq)]
+pub struct Element {
+    pub ai: String,
+    pub value: String,
+}
+
+// Characters allowed in a data field
+#[derive(Clone, Copy, PartialEq)]
+enum Charset {
+    // Digits only
+    Numeric,
+    // GS1 character set 82: digits, letters, and some punctuation
+    Cset82,
+}
+
+// Data field formats: AI, character set, minimum and maximum length, and
+// the number of leading digits that end with a check digit (0 for none)
+// 'n' in an AI matches any digit, for AIs that encode a decimal point
+// position or a range of similar AIs. The first matching entry counts.
+#[rustfmt::skip]
+const AI_TABLE: &[(&str, Charset, usize, usize, usize)] = &[
+    ("00", Numeric, 18, 18, 18),   // Serial shipping container code
+    ("01", Numeric, 14, 14, 14),   // Global trade item number (GTIN)
+    ("02", Numeric, 14, 14, 14),   // GTIN of contained trade items
+    ("10", Cset82, 1, 20, 0),      // Batch or lot number
+    ("11", Numeric, 6, 6, 0),      // Production date (YYMMDD)
+    ("12", Numeric, 6, 6, 0),      // Due date
+    ("13", Numeric, 6, 6, 0),      // Packaging date
+    ("15", Numeric, 6, 6, 0),      // Best before date
+    ("16", Numeric, 6, 6, 0),      // Sell by date
+    ("17", Numeric, 6, 6, 0),      // Expiration date
+    ("20", Numeric, 2, 2, 0),      // Internal product variant
+    ("21", Cset82, 1, 20, 0),      // Serial number
+    ("22", Cset82, 1, 20, 0),      // Consumer product variant
+    ("235", Cset82, 1, 28, 0),     // Third party controlled serial
+    ("240", Cset82, 1, 30, 0),     // Additional product identification
+    ("241", Cset82, 1, 30, 0),     // Customer part number
+    ("242", Numeric, 1, 6, 0),     // Made-to-order variation number
+    ("243", Cset82, 1, 20, 0),     // Packaging component number
+    ("250", Cset82, 1, 30, 0),     // Secondary serial number
+    ("251", Cset82, 1, 30, 0),     // Reference to source entity
+    ("253", Cset82, 13, 30, 13),   // Global document type identifier
+    ("254", Cset82, 1, 20, 0),     // GLN extension component
+    ("255", Numeric, 13, 25, 13),  // Global coupon number
+    ("30", Numeric, 1, 8, 0),      // Variable count of items
+    ("310n", Numeric, 6, 6, 0),    // Trade measures, last AI digit is
+    ("311n", Numeric, 6, 6, 0),    // the number of decimals
+    ("312n", Numeric, 6, 6, 0),
+    ("313n", Numeric, 6, 6, 0),
+    ("314n", Numeric, 6, 6, 0),
+    ("315n", Numeric, 6, 6, 0),
+    ("316n", Numeric, 6, 6, 0),
+    ("32nn", Numeric, 6, 6, 0),
+    ("330n", Numeric, 6, 6, 0),    // Logistic measures
+    ("331n", Numeric, 6, 6, 0),
+    ("332n", Numeric, 6, 6, 0),
+    ("333n", Numeric, 6, 6, 0),
+    ("334n", Numeric, 6, 6, 0),
+    ("335n", Numeric, 6, 6, 0),
+    ("336n", Numeric, 6, 6, 0),
+    ("337n", Numeric, 6, 6, 0),
+    ("34nn", Numeric, 6, 6, 0),
+    ("350n", Numeric, 6, 6, 0),
+    ("351n", Numeric, 6, 6, 0),
+    ("352n", Numeric, 6, 6, 0),
+    ("353n", Numeric, 6, 6, 0),
+    ("354n", Numeric, 6, 6, 0),
+    ("355n", Numeric, 6, 6, 0),
+    ("356n", Numeric, 6, 6, 0),
+    ("357n", Numeric, 6, 6, 0),
+    ("36nn", Numeric, 6, 6, 0),
+    ("37", Numeric, 1, 8, 0),      // Count of trade items
+    ("390n", Numeric, 1, 15, 0),   // Amount payable
+    ("391n", Numeric, 4, 18, 0),   // Amount payable with currency
+    ("392n", Numeric, 1, 15, 0),   // Amount payable, single item
+    ("393n", Numeric, 4, 18, 0),   // Same, with currency
+    ("400", Cset82, 1, 30, 0),     // Customer purchase order number
+    ("401", Cset82, 1, 30, 0),     // Global identification number for consignment
+    ("402", Numeric, 17, 17, 17),  // Global shipment identification number
+    ("403", Cset82, 1, 30, 0),     // Routing code
+    ("410", Numeric, 13, 13, 13),  // Global location numbers (GLN)
+    ("411", Numeric, 13, 13, 13),
+    ("412", Numeric, 13, 13, 13),
+    ("413", Numeric, 13, 13, 13),
+    ("414", Numeric, 13, 13, 13),
+    ("415", Numeric, 13, 13, 13),
+    ("416", Numeric, 13, 13, 13),
+    ("417", Numeric, 13, 13, 13),
+    ("420", Cset82, 1, 20, 0),     // Ship to postal code
+    ("421", Cset82, 4, 12, 0),     // Ship to postal code with country
+    ("422", Numeric, 3, 3, 0),     // Country of origin
+    ("423", Numeric, 3, 15, 0),    // Countries of initial processing
+    ("424", Numeric, 3, 3, 0),     // Country of processing
+    ("425", Numeric, 3, 15, 0),    // Countries of disassembly
+    ("426", Numeric, 3, 3, 0),     // Country covering full process chain
+    ("7001", Numeric, 13, 13, 0),  // NATO stock number
+    ("7002", Cset82, 1, 30, 0),    // UN/ECE meat carcasses classification
+    ("7003", Numeric, 10, 10, 0),  // Expiration date and time
+    ("7004", Numeric, 1, 4, 0),    // Active potency
+    ("7005", Cset82, 1, 12, 0),    // Catch area
+    ("7006", Numeric, 6, 6, 0),    // First freeze date
+    ("7007", Numeric, 6, 12, 0),   // Harvest date
+    ("7008", Cset82, 1, 3, 0),     // Species for fishery purposes
+    ("7009", Cset82, 1, 10, 0),    // Fishing gear type
+    ("7010", Cset82, 1, 2, 0),     // Production method
+    ("7020", Cset82, 1, 20, 0),    // Refurbishment lot
+    ("7021", Cset82, 1, 20, 0),    // Functional status
+    ("7022", Cset82, 1, 20, 0),    // Revision status
+    ("7023", Cset82, 1, 30, 0),    // Global individual asset identifier of an assembly
+    ("7240", Cset82, 1, 20, 0),    // Protocol ID
+    ("8001", Numeric, 14, 14, 0),  // Roll products
+    ("8002", Cset82, 1, 20, 0),    // Cellular mobile telephone identifier
+    ("8003", Cset82, 14, 30, 14),  // Global returnable asset identifier
+    ("8004", Cset82, 1, 30, 0),    // Global individual asset identifier
+    ("8005", Numeric, 6, 6, 0),    // Price per unit of measure
+    ("8006", Numeric, 18, 18, 14), // Identification of an individual trade item piece
+    ("8007", Cset82, 1, 34, 0),    // International bank account number
+    ("8008", Numeric, 8, 12, 0),   // Date and time of production
+    ("8009", Cset82, 1, 50, 0),    // Optically readable sensor indicator
+    ("8010", Cset82, 1, 30, 0),    // Component/part identifier
+    ("8011", Numeric, 1, 12, 0),   // Component/part identifier serial number
+    ("8012", Cset82, 1, 20, 0),    // Software version
+    ("8013", Cset82, 1, 25, 0),    // Global model number
+    ("8017", Numeric, 18, 18, 18), // Global service relation number (provider)
+    ("8018", Numeric, 18, 18, 18), // Global service relation number (recipient)
+    ("8019", Numeric, 1, 10, 0),   // Service relation instance number
+    ("8020", Cset82, 1, 25, 0),    // Payment slip reference number
+    ("8026", Numeric, 18, 18, 14), // Identification of pieces of a trade item contained
+    ("8200", Cset82, 1, 70, 0),    // Extended packaging URL
+    ("90", Cset82, 1, 30, 0),      // Information agreed between trading partners
+    ("9n", Cset82, 1, 90, 0),      // Company internal information
+];
+
+// AIs starting with these two digits have data fields of a predefined
+// length, so no group separator is needed after them
+const PREDEFINED_LENGTH: [&str; 22] = [
+    "00", "01", "02", "03", "04", "11", "12", "13", "14", "15", "16", "17", "18", "19", "20", "31",
+    "32", "33", "34", "35", "36", "41",
+];
+
+// Punctuation in GS1 character set 82, next to digits and letters
+const CSET82_PUNCTUATION: &[u8] = b"!\"%&'()*+,-./:;<=>?_";
+
+// Find the format of the AI at the start of a string of digits
+// Returns the length of the AI and its format
+fn find_format(digits: &[u8]) -> Option<(usize, Charset, usize, usize, usize)> {
+    AI_TABLE
+        .iter()
+        .find(|(pattern, ..)| {
+            digits.len() >= pattern.len()
+                && pattern
+                    .bytes()
+                    .zip(digits)
+                    .all(|(p, digit)| digit.is_ascii_digit() && (p == b'n' || p == *digit))
+        })
+        .map(|&(pattern, charset, min, max, check)| (pattern.len(), charset, min, max, check))
+}
+
+// GS1 check digit: digits are weighted 3 and 1 alternately, starting with
+// 3 at the rightmost digit (the check digit excluded). The check digit
+// brings the weighted sum up to a multiple of 10.
+pub fn check_digit(digits: &[u8]) -> u8 {
+    let sum: usize = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(n, digit)| (digit - b'0') as usize * if n % 2 == 0 { 3 } else { 1 })
+        .sum();
+
+    b'0' + ((10 - sum % 10) % 10) as u8
+}
+
+// Check a data field against the format of its AI
+fn validate(ai: &str, value: &str) -> Result<(), &'static str> {
+    let (charset, min, max, check) = match find_format(ai.as_bytes()) {
+        Some((length, charset, min, max, check)) if length == ai.len() => {
+            (charset, min, max, check)
+        }
+        _ => return Err("Unknown GS1 Application Identifier"),
+    };
+
+    let value = value.as_bytes();
+    if value.len() < min || value.len() > max {
+        return Err("GS1 data field has the wrong length");
+    }
+
+    let valid_character = |byte: &u8| match charset {
+        Numeric => byte.is_ascii_digit(),
+        Cset82 => byte.is_ascii_alphanumeric() || CSET82_PUNCTUATION.contains(byte),
+    };
+    if !value.iter().all(valid_character) {
+        return Err("GS1 data field contains invalid characters");
+    }
+
+    if check > 0 {
+        let digits = &value[..check];
+        if !digits.iter().all(|byte| byte.is_ascii_digit())
+            || check_digit(&digits[..check - 1]) != digits[check - 1]
+        {
+            return Err("GS1 check digit is wrong");
+        }
+    }
+
+    Ok(())
+}
+
+// Read a human readable element string, with every AI in brackets:
+// (01)09501101530003(21)A1B2C3
+// Data fields can't contain brackets themselves
+pub fn parse_bracketed(text: &str) -> Result<Vec<Element>, &'static str> {
+    let text = text.trim_end_matches(['\r', '\n']);
+    if !text.starts_with('(') {
+        return Err("GS1 element string must start with an AI in brackets");
+    }
+
+    let mut elements: Vec<Element> = vec![];
+
+    for part in text.split('(').skip(1) {
+        let (ai, value) = match part.split_once(')') {
+            Some(pair) => pair,
+            None => return Err("GS1 element string has an unclosed bracket"),
+        };
+
+        validate(ai, value)?;
+        elements.push(Element {
+            ai: ai.to_string(),
+            value: value.to_string(),
+        });
+    }
+
+    Ok(elements)
+}
+
+// Write elements the way they are stored in a QR code: AIs and data fields
+// in a row, with a group separator after every data field of variable
+// length (except the last one)
+pub fn element_string(elements: &[Element]) -> Vec<u8> {
+    let mut data: Vec<u8> = vec![];
+
+    for (n, element) in elements.iter().enumerate() {
+        data.extend_from_slice(element.ai.as_bytes());
+        data.extend_from_slice(element.value.as_bytes());
+
+        if n + 1 < elements.len() && !PREDEFINED_LENGTH.contains(&&element.ai[..2]) {
+            data.push(GROUP_SEPARATOR);
+        }
+    }
+
+    data
+}
+
+// Split an element string, as read from a QR code, back into AIs and
+// data fields and check each of them
+pub fn parse_element_string(data: &[u8]) -> Result<Vec<Element>, &'static str> {
+    let mut elements: Vec<Element> = vec![];
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        let (ai_length, _, _, max, _) = match find_format(rest) {
+            Some(format) => format,
+            None => return Err("Unknown GS1 Application Identifier"),
+        };
+        let (ai, field) = rest.split_at(ai_length);
+
+        // Predefined lengths are fixed, other data fields end at the next
+        // group separator or the end of the data
+        let (value, next) = if PREDEFINED_LENGTH.iter().any(|p| p.as_bytes() == &ai[..2]) {
+            if field.len() < max {
+                return Err("GS1 data field has the wrong length");
+            }
+            (&field[..max], &field[max..])
+        } else {
+            match field.iter().position(|byte| *byte == GROUP_SEPARATOR) {
+                Some(end) => (&field[..end], &field[end + 1..]),
+                None => (field, &field[field.len()..]),
+            }
+        };
+
+        let ai = String::from_utf8_lossy(ai).into_owned();
+        let value = match std::str::from_utf8(value) {
+            Ok(value) => value.to_string(),
+            Err(_) => return Err("GS1 data field contains invalid characters"),
+        };
+
+        validate(&ai, &value)?;
+        elements.push(Element { ai, value });
+        rest = next;
+    }
+
+    Ok(elements)
+}
+
+// Human readable form of an element string, with every AI in brackets
+pub fn to_bracketed(elements: &[Element]) -> String {
+    elements
+        .iter()
+        .map(|element| format!("({}){}", element.ai, element.value))
+        .collect()
+}
+
+// Undo the alphanumeric mode escaping behind an FNC1 mode indicator:
+// % is a group separator, %% a literal %
+pub fn from_alphanumeric(text: &[u8]) -> Vec<u8> {
+    let mut data: Vec<u8> = Vec::with_capacity(text.len());
+    let mut characters = text.iter().peekable();
+
+    while let Some(&character) = characters.next() {
+        if character != b'%' {
+            data.push(character);
+        } else if characters.peek() == Some(&&b'%') {
+            characters.next();
+            data.push(b'%');
+        } else {
+            data.push(GROUP_SEPARATOR);
+        }
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::decode_matrix;
+    use crate::qr_code::{EccLevel, Mode, Segment, build_matrix, select_version_optimal};
+
+    fn element(ai: &str, value: &str) -> Element {
+        Element {
+            ai: ai.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn check_digits_of_published_numbers() {
+        // EAN-13, GTIN-14, SSCC and GLN
+        assert_eq!(check_digit(b"400638133393"), b'1');
+        assert_eq!(check_digit(b"0950110153000"), b'3');
+        assert_eq!(check_digit(b"10614141123456789"), b'7');
+        assert_eq!(check_digit(b"950110153001"), b'0');
+    }
+
+    #[test]
+    fn finds_ais_of_every_length() {
+        let ai_length = |digits: &[u8]| find_format(digits).map(|format| format.0);
+
+        assert_eq!(ai_length(b"0109501101530003"), Some(2));
+        assert_eq!(ai_length(b"235ABC"), Some(3));
+        assert_eq!(ai_length(b"7003"), Some(4));
+        // 'n' stands for any digit, the first match counts
+        assert_eq!(ai_length(b"3103000189"), Some(4));
+        assert_eq!(ai_length(b"3299000189"), Some(4));
+        assert_eq!(ai_length(b"90ABC"), Some(2));
+        assert_eq!(ai_length(b"91ABC"), Some(2));
+        // Unknown, too short, or not digits
+        assert_eq!(ai_length(b"05123"), None);
+        assert_eq!(ai_length(b"31"), None);
+        assert_eq!(ai_length(b"9A"), None);
+    }
+
+    #[test]
+    fn validates_length_characters_and_check_digit() {
+        assert_eq!(validate("01", "09501101530003"), Ok(()));
+        assert_eq!(validate("10", &"A".repeat(20)), Ok(()));
+        assert_eq!(validate("3103", "000189"), Ok(()));
+
+        assert_eq!(
+            validate("05", "1"),
+            Err("Unknown GS1 Application Identifier")
+        );
+        assert_eq!(
+            validate("310", "000189"),
+            Err("Unknown GS1 Application Identifier")
+        );
+        for (ai, value) in [
+            ("01", "0950110153000"),
+            ("10", ""),
+            ("10", &"A".repeat(21)),
+            ("11", "2512"),
+        ] {
+            assert_eq!(
+                validate(ai, value),
+                Err("GS1 data field has the wrong length")
+            );
+        }
+        for (ai, value) in [("11", "25123A"), ("21", "A B"), ("21", "A#B")] {
+            assert_eq!(
+                validate(ai, value),
+                Err("GS1 data field contains invalid characters")
+            );
+        }
+        assert_eq!(
+            validate("01", "09501101530004"),
+            Err("GS1 check digit is wrong")
+        );
+        // Only the leading digits of the GDTI end with a check digit
+        assert_eq!(validate("253", "9501101530010ABC"), Ok(()));
+    }
+
+    #[test]
+    fn bracketed_and_element_strings_round_trip() {
+        let text = "(01)09501101530003(10)ABC-123(3103)000189(21)A1B2C3";
+        let elements = parse_bracketed(&format!("{text}\n")).unwrap();
+        assert_eq!(
+            elements,
+            [
+                element("01", "09501101530003"),
+                element("10", "ABC-123"),
+                element("3103", "000189"),
+                element("21", "A1B2C3"),
+            ]
+        );
+        assert_eq!(to_bracketed(&elements), text);
+
+        // Group separators only after variable length fields, and not at
+        // the end
+        let data = element_string(&elements);
+        assert_eq!(data, b"010950110153000310ABC-123\x1D310300018921A1B2C3");
+        assert_eq!(parse_element_string(&data), Ok(elements));
+    }
+
+    #[test]
+    fn rejects_malformed_element_strings() {
+        assert_eq!(
+            parse_bracketed("01)09501101530003"),
+            Err("GS1 element string must start with an AI in brackets")
+        );
+        assert_eq!(
+            parse_bracketed("(01)09501101530003(21"),
+            Err("GS1 element string has an unclosed bracket")
+        );
+        assert_eq!(
+            parse_element_string(b"01095011015300"),
+            Err("GS1 data field has the wrong length")
+        );
+        assert_eq!(
+            parse_element_string(b"05123"),
+            Err("Unknown GS1 Application Identifier")
+        );
+    }
+
+    #[test]
+    fn percent_signs_and_group_separators_in_alphanumeric_mode() {
+        assert_eq!(from_alphanumeric(b"10AB%21X"), b"10AB\x1D21X");
+        assert_eq!(from_alphanumeric(b"10A%%B"), b"10A%B");
+        assert_eq!(from_alphanumeric(b"%%%"), b"%\x1D");
+
+        // Through a QR code, where alphanumeric mode escapes them
+        let elements = [element("10", "ABCD%EFGH%IJKL"), element("21", "MNOPQRST%")];
+        let header = [Segment::fnc1_first()];
+        let (segments, version) =
+            select_version_optimal(&header, &element_string(&elements), EccLevel::M).unwrap();
+        let decoded = decode_matrix(&build_matrix(&segments, version, EccLevel::M)).unwrap();
+
+        assert!(decoded.gs1);
+        assert!(
+            decoded
+                .segments
+                .iter()
+                .any(|segment| segment.mode == Mode::Alphanumeric)
+        );
+        assert_eq!(decoded.gs1_elements(), Ok(elements.to_vec()));
+    }
+}
@@ -2,58 +2,341 @@ use std::env;
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::prelude::*;
 use std::io::BufReader;
+use std::io::prelude::*;
 use std::path::PathBuf;
 
+pub mod decoder;
+pub mod detector;
+pub mod gs1;
+pub mod micro_qr;
+pub mod png;
+pub mod qr_code;
+pub mod rmqr;
+mod shift_jis;
+
+use decoder::Decoded;
+use qr_code::{
+    Base45, DefaultPenalty, EccLevel, IsoPenalty, MaskSelection, MaskStrategy, Mode, Transform,
+};
+
+// How the password is divided into segments
+pub enum Encoding {
+    // Numeric for passwords of digits only, base-45 alphanumeric otherwise
+    Automatic,
+    // The whole password in a single mode
+    Single(Mode),
+    // Mixed modes with the smallest total length
+    Optimal,
+    // A GS1 element string in brackets, validated and written behind an
+    // FNC1 mode indicator, in mixed modes
+    Gs1,
+}
+
+// What the program is asked to do
+pub enum Command {
+    // Write the password as a QR code
+    Encode,
+    // Report which version the password needs at each error correction
+    // level, and how much space it takes up
+    Capacity,
+    // Turn the text a scanner shows for an alphanumeric QR code back into
+    // the password
+    Recover,
+}
+
+// Settings collected from the command line
+pub struct Options {
+    pub command: Command,
+    // Password file, optional when only a length is given, and unused when
+    // recovering a password
+    pub path: Option<PathBuf>,
+    // Length of a hypothetical password, in characters of the mode given by
+    // --mode, for the capacity command
+    pub length: Option<usize>,
+    pub ecc_level: EccLevel,
+    pub encoding: Encoding,
+    // How a binary password is turned into alphanumeric characters
+    pub base45: Base45,
+    // ECI designator declaring the character set of the password
+    pub eci: Option<u32>,
+    // Largest version allowed when splitting across several QR codes
+    pub split: Option<usize>,
+    // Produce a Micro QR code instead of a regular one
+    pub micro: bool,
+    // Produce a rectangular Micro QR code (rMQR) instead of a regular one
+    pub rmqr: bool,
+    // How the mask pattern of a regular QR code is chosen
+    pub mask: MaskSelection,
+    // Penalty scoring used to evaluate the masks
+    pub penalty: &'static dyn MaskStrategy,
+    // Colors and orientation of the output
+    pub transform: Transform,
+    // Raise the error correction level as far as the symbol size allows
+    pub boost_ecc: bool,
+    // Read the written QR codes back and compare them with the password
+    pub verify: bool,
+}
+
 // Read arguments from command line
-// Check for exactly one
-// Transform that one into canonical filepath
-pub fn read_args() -> Result<PathBuf, &'static str> {
-    if env::args().nth(2).is_some() {
-        return Err("Too many arguments");
+// An optional command comes first:
+//   capacity         report the smallest version at each error correction
+//                    level, bits used and available, and padding, instead
+//                    of writing a QR code
+//   recover          read the text a scanner shows for a QR code in
+//                    alphanumeric mode from stdin, and write the original
+//                    password to stdout
+// Check for exactly one password file, plus optional settings:
+//   --length <n>     capacity only: size a password of n characters of
+//                    the mode given by --mode, instead of reading a file
+//   --ecc L|M|Q|H    error correction level (default: L)
+//   --mode numeric|alphanumeric|byte|kanji|optimal
+//                    how the password is stored (default: numeric for
+//                    passwords of digits only, alphanumeric otherwise)
+//                    kanji expects UTF-8 text, optimal mixes all modes
+//   --base45 number|rfc9285
+//                    turn a password into alphanumeric characters as one
+//                    large number, or two bytes at a time as standard
+//                    Base45 tools do (default: number)
+//   --gs1            the password file holds a GS1 element string, e.g.
+//                    (21)SN12345(91)password
+//   --eci utf-8|iso-8859-1|shift_jis|<number>
+//                    declare the character set, so scanners show
+//                    non-ASCII passwords correctly
+//   --split <1-40>   split the password across up to 16 QR codes of at
+//                    most this version (structured append), every part
+//                    encoded like a whole password; not for GS1 element
+//                    strings or kanji mode
+//   --micro          produce a Micro QR code (M1 to M4) for short
+//                    passwords
+//   --boost-ecc      raise the error correction level as far as possible
+//                    without growing the symbol
+//   --mask <0-7>|fast
+//                    use the given mask, or skip evaluation and use mask 0
+//                    (regular QR codes only, default: lowest penalty)
+//   --penalty default|iso
+//                    score masks the way this program always has, or
+//                    following ISO 18004 like reference encoders
+//   --invert         light modules on a dark background
+//   --mirror         mirrored left to right
+//   --rotate 90|180|270
+//                    rotated clockwise
+//   --rmqr           produce a rectangular Micro QR code (R7x43 to
+//                    R17x139) for narrow labels, ECC level M or H
+//   --no-verify      don't read the written QR codes back to check that
+//                    they hold the password
+// Transform the password file into canonical filepath
+pub fn read_args() -> Result<Options, &'static str> {
+    let mut args = env::args().skip(1).peekable();
+    let mut filepath_from_args: Option<String> = None;
+    let mut length = None;
+
+    let command = match args.peek().map(|arg| arg.as_str()) {
+        Some("capacity") => {
+            args.next();
+            Command::Capacity
+        }
+        Some("recover") => {
+            args.next();
+            Command::Recover
+        }
+        _ => Command::Encode,
+    };
+    let mut ecc_level = EccLevel::L;
+    let mut encoding = Encoding::Automatic;
+    let mut base45 = Base45::default();
+    let mut eci = None;
+    let mut split = None;
+    let mut micro = false;
+    let mut rmqr = false;
+    let mut mask = MaskSelection::Evaluate;
+    let mut penalty: &'static dyn MaskStrategy = &DefaultPenalty;
+    let mut transform = Transform::default();
+    let mut boost_ecc = false;
+    let mut verify = true;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ecc" => {
+                ecc_level = match args.next().as_deref() {
+                    Some("L") | Some("l") => EccLevel::L,
+                    Some("M") | Some("m") => EccLevel::M,
+                    Some("Q") | Some("q") => EccLevel::Q,
+                    Some("H") | Some("h") => EccLevel::H,
+                    _ => return Err("Error correction level must be one of L, M, Q, H"),
+                }
+            }
+            "--mode" => {
+                encoding = match args.next().as_deref() {
+                    Some("numeric") => Encoding::Single(Mode::Numeric),
+                    Some("alphanumeric") => Encoding::Single(Mode::Alphanumeric),
+                    Some("byte") => Encoding::Single(Mode::Byte),
+                    Some("kanji") => Encoding::Single(Mode::Kanji),
+                    Some("optimal") => Encoding::Optimal,
+                    _ => {
+                        return Err(
+                            "Mode must be one of numeric, alphanumeric, byte, kanji, optimal",
+                        );
+                    }
+                }
+            }
+            "--base45" => {
+                base45 = match args.next().as_deref() {
+                    Some("number") => Base45::Number,
+                    Some("rfc9285") => Base45::Rfc9285,
+                    _ => return Err("Base45 conversion must be one of number, rfc9285"),
+                }
+            }
+            "--eci" => {
+                eci = match args.next().as_deref() {
+                    Some("utf-8") => Some(26),
+                    Some("iso-8859-1") => Some(3),
+                    Some("shift_jis") => Some(20),
+                    Some(number) => match number.parse::<u32>() {
+                        Ok(designator) if designator <= 999999 => Some(designator),
+                        _ => return Err("ECI designator must be a number up to 999999"),
+                    },
+                    None => return Err("ECI designator must be a number up to 999999"),
+                }
+            }
+            "--split" => {
+                split = match args.next().map(|number| number.parse::<usize>()) {
+                    Some(Ok(version)) if (1..=40).contains(&version) => Some(version),
+                    _ => return Err("Largest version to split into must be from 1 to 40"),
+                }
+            }
+            "--gs1" => encoding = Encoding::Gs1,
+            "--micro" => micro = true,
+            "--rmqr" => rmqr = true,
+            "--mask" => {
+                mask = match args.next().as_deref() {
+                    Some("fast") => MaskSelection::Fast,
+                    Some(number) => match number.parse::<usize>() {
+                        Ok(mask) if mask < 8 => MaskSelection::Forced(mask),
+                        _ => return Err("Mask must be a number from 0 to 7, or fast"),
+                    },
+                    None => return Err("Mask must be a number from 0 to 7, or fast"),
+                }
+            }
+            "--length" => {
+                length = match args.next().map(|number| number.parse::<usize>()) {
+                    Some(Ok(number)) => Some(number),
+                    _ => return Err("Length must be a number of characters"),
+                }
+            }
+            "--boost-ecc" => boost_ecc = true,
+            "--no-verify" => verify = false,
+            "--invert" => transform.invert = true,
+            "--mirror" => transform.mirror = true,
+            "--rotate" => {
+                transform.quarter_turns = match args.next().as_deref() {
+                    Some("0") => 0,
+                    Some("90") => 1,
+                    Some("180") => 2,
+                    Some("270") => 3,
+                    _ => return Err("Rotation must be one of 90, 180, 270"),
+                }
+            }
+            "--penalty" => {
+                penalty = match args.next().as_deref() {
+                    Some("default") => &DefaultPenalty,
+                    Some("iso") => &IsoPenalty,
+                    _ => return Err("Penalty must be one of default, iso"),
+                }
+            }
+            _ => {
+                if filepath_from_args.is_some() {
+                    return Err("Too many arguments");
+                }
+                filepath_from_args = Some(arg);
+            }
+        }
     }
 
-    let filepath_from_args = match env::args().nth(1) {
-        Some(string) => string,
+    // A length alone is enough to size a password of a single mode
+    if length.is_some() {
+        if !matches!(command, Command::Capacity) || filepath_from_args.is_some() {
+            return Err("A length replaces the password file for the capacity command only");
+        }
+        if !matches!(encoding, Encoding::Single(_)) {
+            return Err("A length needs a mode: numeric, alphanumeric, byte or kanji");
+        }
+    }
+
+    // Only regular QR codes can be split, and a symbol is either a Micro QR
+    // or an rMQR code
+    if micro && rmqr {
+        return Err("Micro QR and rMQR codes can't be combined");
+    }
+    if split.is_some() && (micro || rmqr) {
+        return Err("Micro QR and rMQR codes can't be split");
+    }
+
+    // GS1 element strings need their FNC1 header in one piece, and kanji
+    // characters could be cut in half between QR codes
+    if split.is_some() && matches!(encoding, Encoding::Gs1 | Encoding::Single(Mode::Kanji)) {
+        return Err("Splitting supports neither GS1 element strings nor kanji mode");
+    }
+
+    // The scanned text comes from stdin
+    if matches!(command, Command::Recover) && filepath_from_args.is_some() {
+        return Err("The recover command reads the scanned text from stdin");
+    }
+
+    let path = match filepath_from_args {
+        Some(string) => match fs::canonicalize(string) {
+            Ok(path) => Some(path),
+            Err(_) => return Err("No such file or directory"),
+        },
+        None if length.is_some() || matches!(command, Command::Recover) => None,
         None => return Err("Please provide a password file"),
     };
 
-    match fs::canonicalize(filepath_from_args) {
-        Ok(path) => {
-            return Ok(path);
-        }
-        Err(_) => return Err("No such file or directory"),
-    }
+    Ok(Options {
+        command,
+        path,
+        length,
+        ecc_level,
+        encoding,
+        base45,
+        eci,
+        split,
+        micro,
+        rmqr,
+        mask,
+        penalty,
+        transform,
+        boost_ecc,
+        verify,
+    })
 }
 
 // Read a given number of bytes from a given filepath
-pub fn read_bits(path: PathBuf, length: &u8) -> io::Result<Vec<u8>> {
+pub fn read_bits(path: PathBuf, length: usize) -> io::Result<Vec<u8>> {
     let f = BufReader::new(File::open(path)?);
-    let mut bits: Vec<u8> = vec![];
-    let mut i = 0;
+    let mut bits: Vec<u8> = Vec::with_capacity(length);
 
-    for byte in f.bytes() {
+    for byte in f.bytes().take(length) {
         bits.push(byte?);
-        i += 1;
-        if i == *length {
-            break;
-        }
     }
 
-    return Ok(bits);
+    Ok(bits)
 }
 
 // Transform a QR matrix into PNG file
-pub fn form_png(qr_matrix: [[u8; 33]; 33]) -> Vec<u8> {
+pub fn form_png(qr_matrix: Vec<Vec<u8>>) -> Vec<u8> {
     // Prepare the data:
     // Write the array of rows into one long stream of bits and insert a filter
     // type byte before every row.
     // Turn each QR module into an 8x8 square of pixels: Invert the color
     // representation (QR black: "1" to PNG black: "0"), inflate each module
     // to 8 pixels in a row, and copy each row seven times.
+    // Light-on-dark symbols arrive with their modules already inverted
+    // (see qr_code::Transform), so dark modules are always "1" here.
     let mut temp: Vec<u8>;
     let mut image_serial: Vec<u8> = vec![];
+    let width = qr_matrix[0].len() as u32 * 8; // Image width in pixels
+    let height = qr_matrix.len() as u32 * 8; // Image height in pixels
 
     for row in qr_matrix {
         // Invert color, expand pixels
@@ -72,7 +355,7 @@ pub fn form_png(qr_matrix: [[u8; 33]; 33]) -> Vec<u8> {
     append = png_signature();
     png_image.append(&mut append);
 
-    append = png_ihdr();
+    append = png_ihdr(width, height);
     png_image.append(&mut append);
 
     append = png_idat(image_serial);
@@ -84,31 +367,83 @@ pub fn form_png(qr_matrix: [[u8; 33]; 33]) -> Vec<u8> {
     png_image
 }
 
+// Read a QR, Micro QR or rMQR code back from a PNG written by form_png,
+// 8 pixels per module, and undo the transformation it was exported with
+pub fn read_png(png: &[u8], transform: &Transform) -> Result<Decoded, &'static str> {
+    let image = png::decode_png(png)?;
+
+    // Sample the center pixel of every module
+    let modules: Vec<Vec<u8>> = (0..image.height / 8)
+        .map(|row| {
+            (0..image.width / 8)
+                .map(|col| (image.pixel(col * 8 + 4, row * 8 + 4) < 128) as u8)
+                .collect()
+        })
+        .collect();
+
+    decoder::decode_any(&transform.undo(modules))
+}
+
+// Recover the password from a decoded QR code, undoing the way it was
+// encoded: base-45 digits back into bytes, kanji back into UTF-8
+// GS1 element strings come back without brackets
+pub fn read_password(
+    decoded: &Decoded,
+    encoding: &Encoding,
+    base45: Base45,
+) -> Result<Vec<u8>, &'static str> {
+    if decoded.gs1 {
+        return Ok(decoded.bytes());
+    }
+
+    let mut password: Vec<u8> = vec![];
+
+    for segment in &decoded.segments {
+        match segment.mode {
+            // Automatic and single mode encodings store the password in
+            // base 45, other encodings store its characters
+            Mode::Alphanumeric if matches!(encoding, Encoding::Automatic | Encoding::Single(_)) => {
+                password.extend(qr_code::recover_bits(&segment.data, base45)?);
+            }
+            Mode::Kanji => match segment.text() {
+                Some(text) => password.extend_from_slice(text.as_bytes()),
+                None => return Err("Invalid Shift JIS characters in kanji segment"),
+            },
+            _ => password.extend_from_slice(&segment.data),
+        }
+    }
+
+    Ok(password)
+}
+
 // Fixed start of every PNG file
 fn png_signature() -> Vec<u8> {
     vec![0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A]
 }
 
 // IHDR chunk
-fn png_ihdr() -> Vec<u8> {
-    let crc: u32;
+// Width and height differ for rectangular (rMQR) codes
+fn png_ihdr(width: u32, height: u32) -> Vec<u8> {
     let mut ihdr: Vec<u8> = vec![
         0, 0, 0, 0x0D, // Length of data
         0x49, 0x48, 0x44, 0x52, // "IHDR"
-        0, 0, 1, 8, // Image width: 264px
-        0, 0, 1, 8, // Image height: 264px
+    ];
+
+    ihdr.extend_from_slice(&width.to_be_bytes()); // Image width
+    ihdr.extend_from_slice(&height.to_be_bytes()); // Image height
+    ihdr.extend_from_slice(&[
         1, // Bit depth
         0, // Color type
         0, // Compression
         0, // Filter
         0, // Enlacement
-    ];
+    ]);
 
     // CRC calculation excludes length field
-    crc = calculate_crc(&ihdr[4..]);
+    let crc = calculate_crc(&ihdr[4..]);
     ihdr.push((crc >> 24) as u8);
     ihdr.push(((crc & 0x00FF_0000) >> 16) as u8);
-    ihdr.push(((crc & 0x0000_FF00) >> 08) as u8);
+    ihdr.push(((crc & 0x0000_FF00) >> 8) as u8);
     ihdr.push((crc & 0x0000_00FF) as u8);
 
     ihdr
@@ -118,8 +453,6 @@ fn png_ihdr() -> Vec<u8> {
 fn png_idat(image: Vec<u8>) -> Vec<u8> {
     // Deflate algorithm is mandatory
     let mut image_compressed = deflate(image);
-    let crc: u32;
-    let length: u32;
     // Preform IDAT chunk start
     let mut idat: Vec<u8> = vec![
         0, 0, 0, 0, // Length of data
@@ -128,19 +461,19 @@ fn png_idat(image: Vec<u8>) -> Vec<u8> {
 
     // Insert length of data field
     // (Exclude length field, chunk type, and CRC)
-    length = image_compressed.len() as u32;
+    let length = image_compressed.len() as u32;
     idat[0] = (length >> 24) as u8;
     idat[1] = ((length & 0x00FF_0000) >> 16) as u8;
-    idat[2] = ((length & 0x0000_FF00) >> 08) as u8;
+    idat[2] = ((length & 0x0000_FF00) >> 8) as u8;
     idat[3] = (length & 0x0000_00FF) as u8;
 
     idat.append(&mut image_compressed);
 
     // CRC calculation excludes length field
-    crc = calculate_crc(&idat[4..]);
+    let crc = calculate_crc(&idat[4..]);
     idat.push((crc >> 24) as u8);
     idat.push(((crc & 0x00FF_0000) >> 16) as u8);
-    idat.push(((crc & 0x0000_FF00) >> 08) as u8);
+    idat.push(((crc & 0x0000_FF00) >> 8) as u8);
     idat.push((crc & 0x0000_00FF) as u8);
 
     idat
@@ -158,32 +491,44 @@ fn png_iend() -> Vec<u8> {
 // Deflate the image data
 // Allows for uncompressed data, to avoid inflating already compressed
 // data. Used here for simplicity.
-fn deflate(mut data: Vec<u8>) -> Vec<u8> {
-    let length: u16 = data.len() as u16; // Length of uncompressed data
+// An uncompressed block holds at most 65535 bytes, so larger images are
+// split into several blocks.
+fn deflate(data: Vec<u8>) -> Vec<u8> {
     let adler32 = calculate_adler32(&data); // Compute checksum
     let mut deflate_block: Vec<u8> = vec![
-        0x78,                         // Deflate header: Compression method
-        0x01,                         // Deflate header: No compr., checksum
-        0x01,                         // Block header: No compression, last block
-        (length & 255) as u8,         // Length in two bytes
-        (length >> 8) as u8,          // Little-endian order
-        ((length & 255) as u8) ^ 255, // Length's one's complement
-        ((length >> 8) as u8) ^ 255,  // Also little-endian
+        0x78, // Deflate header: Compression method
+        0x01, // Deflate header: No compr., checksum
     ];
 
-    deflate_block.append(&mut data); // Append unaltered data
+    let block_count = data.len().div_ceil(65535).max(1);
+
+    for n in 0..block_count {
+        let block = &data[(n * 65535).min(data.len())..((n + 1) * 65535).min(data.len())];
+        let length: u16 = block.len() as u16; // Length of uncompressed data
+        let last_block = (n == block_count - 1) as u8;
+
+        deflate_block.extend_from_slice(&[
+            last_block,                   // Block header: No compression, last block?
+            (length & 255) as u8,         // Length in two bytes
+            (length >> 8) as u8,          // Little-endian order
+            ((length & 255) as u8) ^ 255, // Length's one's complement
+            ((length >> 8) as u8) ^ 255,  // Also little-endian
+        ]);
+
+        deflate_block.extend_from_slice(block); // Append unaltered data
+    }
 
     // Append Adler32 checksum in big-endian order
     deflate_block.push((adler32 >> 24) as u8);
     deflate_block.push(((adler32 & 0x00FF_0000) >> 16) as u8);
-    deflate_block.push(((adler32 & 0x0000_FF00) >> 08) as u8);
+    deflate_block.push(((adler32 & 0x0000_FF00) >> 8) as u8);
     deflate_block.push((adler32 & 0x0000_00FF) as u8);
 
     deflate_block
 }
 
 // Calculate deflate checksum
-fn calculate_adler32(data: &Vec<u8>) -> u32 {
+pub(crate) fn calculate_adler32(data: &Vec<u8>) -> u32 {
     let mut s1: u32 = 1;
     let mut s2: u32 = 0;
 
@@ -198,7 +543,7 @@ fn calculate_adler32(data: &Vec<u8>) -> u32 {
 }
 
 // Calculate CRC32 for PNG chunks
-fn calculate_crc(data: &[u8]) -> u32 {
+pub(crate) fn calculate_crc(data: &[u8]) -> u32 {
     let mut crc: u32 = 0;
     // Generator polynom as specified
     // Leading 1 omitted
@@ -206,13 +551,13 @@ fn calculate_crc(data: &[u8]) -> u32 {
 
     // Pre-populate CRC
     // Computation starts from LSB -> reflect bytes
-    crc = crc ^ reflect_byte(data[0]) as u32;
-    crc = crc << 8;
-    crc = crc ^ reflect_byte(data[1]) as u32;
-    crc = crc << 8;
-    crc = crc ^ reflect_byte(data[2]) as u32;
-    crc = crc << 8;
-    crc = crc ^ reflect_byte(data[3]) as u32;
+    crc ^= reflect_byte(data[0]) as u32;
+    crc <<= 8;
+    crc ^= reflect_byte(data[1]) as u32;
+    crc <<= 8;
+    crc ^= reflect_byte(data[2]) as u32;
+    crc <<= 8;
+    crc ^= reflect_byte(data[3]) as u32;
 
     // Specs say to initialize CRC with all 1
     // Effect is to invert first 32 bits
@@ -225,13 +570,13 @@ fn calculate_crc(data: &[u8]) -> u32 {
             for m in 0..8 {
                 // If the bit-about-to-be-discarded is 1, divide
                 if crc & 0x80000000 == 0x80000000 {
-                    crc = crc << 1;
+                    crc <<= 1;
                     crc += next_bit(byte, m);
-                    crc = crc ^ gen_poly;
+                    crc ^= gen_poly;
 
                 // If not, simply shift
                 } else {
-                    crc = crc << 1;
+                    crc <<= 1;
                     crc += next_bit(byte, m);
                 }
             }
@@ -239,10 +584,10 @@ fn calculate_crc(data: &[u8]) -> u32 {
             // For the last iteration, don't add a new bit
             for _m in 0..8 {
                 if crc & 0x80000000 == 0x80000000 {
-                    crc = crc << 1;
-                    crc = crc ^ gen_poly;
+                    crc <<= 1;
+                    crc ^= gen_poly;
                 } else {
-                    crc = crc << 1;
+                    crc <<= 1;
                 }
             }
         }
@@ -252,7 +597,7 @@ fn calculate_crc(data: &[u8]) -> u32 {
     crc = mirror_crc(crc);
     crc = !crc;
 
-    crc as u32
+    crc
 }
 
 // Invert order of bits in a byte
@@ -1,73 +1,472 @@
+use password_display::qr_code::{EccLevel, Segment};
 use password_display::*;
-mod qr_code;
 use std::fs;
+use std::io;
+use std::io::prelude::*;
 
 fn main() {
     // This program reads a password from a file and displays it as a QR code.
-    // The maximum length allowed is 256 bits.
+    // Passwords too long for a single QR code are split across several.
 
     // Check for the right number of arguments
     // Give a warning if there are too many or not enough
-    let path = match read_args() {
-        Ok(path) => path,
+    let options = match read_args() {
+        Ok(options) => options,
         Err(err) => {
-            println!("{err}");
-            return;
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    // Turn scanned text back into the password
+    // The password goes to stdout as it is, so errors go to stderr
+    if let Command::Recover = options.command {
+        let mut text: Vec<u8> = vec![];
+        let password = io::stdin()
+            .read_to_end(&mut text)
+            .map_err(|_| "Unable to read the scanned text")
+            .and_then(|_| qr_code::recover_bits(&text, options.base45));
+        match password {
+            Ok(password) => io::stdout()
+                .write_all(&password)
+                .expect("Unable to write password"),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Size a password of a given length and mode, without reading one
+    if let (Some(length), Encoding::Single(mode)) = (options.length, &options.encoding) {
+        let mut segments = vec![Segment::blank(*mode, length)];
+        if let Some(segment) = options.eci.and_then(Segment::eci) {
+            segments.insert(0, segment);
+        }
+        report_capacity(|_| Ok(Some(segments.clone())));
+        return;
+    }
+
+    let path = match &options.path {
+        Some(path) => path.clone(),
+        None => {
+            eprintln!("Please provide a password file");
+            std::process::exit(1);
         }
     };
 
     // Read bits from file (assumes that all passwords are full bytes)
-    // Store the length of password for later use
-    let file_length = fs::metadata(&path).unwrap().len();
-    let password_length_bytes: u8;
-
-    if file_length > 32 {
-        println!(
-            "File length: {} bits\nOnly first 256 bits will be processed",
-            file_length * 8
+    // Refuse files that wouldn't even fit into 16 QR codes of the largest
+    // version, rather than cutting them short
+    let file_length = match fs::metadata(&path) {
+        Ok(metadata) => metadata.len() as usize,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    let max_length = 16 * qr_code::data_codewords(40, options.ecc_level);
+    if file_length > max_length {
+        eprintln!(
+            "Password file too long: {file_length} bytes, at most {max_length} bytes fit into 16 QR codes at this error correction level"
         );
-        password_length_bytes = 32;
-    } else {
-        password_length_bytes = file_length as u8;
+        std::process::exit(1);
     }
 
-    let bits: Vec<u8> = match read_bits(path, &password_length_bytes) {
+    let bits: Vec<u8> = match read_bits(path, file_length) {
         Ok(vec) => vec,
         Err(err) => {
-            println!("{err}");
-            return;
+            eprintln!("{err}");
+            std::process::exit(1);
         }
     };
 
+    // Split the password across several QR codes, if asked to
+    if let Some(max_version) = options.split {
+        if let Encoding::Single(qr_code::Mode::Numeric) = options.encoding
+            && !qr_code::is_numeric(&bits)
+        {
+            eprintln!("Numeric mode requires a password of digits only");
+            std::process::exit(1);
+        }
+        if !write_split(&bits, &options, max_version) {
+            eprintln!("Password too long for 16 QR codes of version {max_version}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Transform raw bits into a fully formed QR code
 
-    // Encode the binary stream in base45 / alphanumeric
-    let encoded_bits = qr_code::encode_bits(bits, 45);
+    // GS1 element strings are checked against the AI table and written
+    // without brackets, behind an FNC1 mode indicator
+    let (header, bits) = match options.encoding {
+        Encoding::Gs1 => {
+            let elements = match std::str::from_utf8(&bits)
+                .map_err(|_| "GS1 element string must be text")
+                .and_then(gs1::parse_bracketed)
+            {
+                Ok(elements) => elements,
+                Err(err) => {
+                    eprintln!("{err}");
+                    std::process::exit(1);
+                }
+            };
+            (vec![Segment::fnc1_first()], gs1::element_string(&elements))
+        }
+        _ => (vec![], bits),
+    };
 
-    // Add mode indicator, length indicator, padding, etc.
-    let data_bits = qr_code::encapsulate_data(encoded_bits);
+    // Report the space the password needs at every error correction level
+    // instead of writing a QR code
+    if let Command::Capacity = options.command {
+        report_capacity(|ecc_level| {
+            let segments = password_segments(&bits, &options, &header, ecc_level)?;
+            Ok(segments.map(|mut segments| {
+                if let Some(segment) = options.eci.and_then(Segment::eci) {
+                    segments.insert(0, segment);
+                }
+                segments
+            }))
+        });
+        return;
+    }
 
-    // Add 10 error correction codewords
-    let data_ecc = qr_code::apply_ecc(data_bits);
+    // Divide the password into segments and choose the smallest QR
+    // version that holds all of them
+    let ecc_level = options.ecc_level;
+    let mut segments = match password_segments(&bits, &options, &header, ecc_level) {
+        Ok(Some(segments)) => segments,
+        Ok(None) => {
+            split_long_password(&bits, &options, &header);
+            return;
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    // Micro QR codes hold at most 35 digits, but take up far less space
+    if options.micro {
+        if options.eci.is_some() || !header.is_empty() {
+            eprintln!("Micro QR codes support neither ECI nor GS1");
+            std::process::exit(1);
+        }
+
+        let version = match micro_qr::select_version(&segments, ecc_level) {
+            Some(version) => version,
+            None => {
+                eprintln!("Password too long for a Micro QR code at this error correction level");
+                std::process::exit(1);
+            }
+        };
+
+        let ecc_level = if options.boost_ecc {
+            let level = micro_qr::boost_ecc_level(&segments, version, ecc_level);
+            println!("Error correction level: {level:?}");
+            level
+        } else {
+            ecc_level
+        };
+
+        let matrix = micro_qr::build_matrix(&segments, version, ecc_level);
+        let png = form_png(matrix.export_transformed(&options.transform));
+        fs::write("./qr_code.png", png).expect("Unable to write file");
+
+        if options.verify {
+            let files = ["./qr_code.png".to_string()];
+            verify_written(&files, &bits, &options.encoding, &options);
+        }
+
+        return;
+    }
+
+    // Declare the character set ahead of all other segments
+    if let Some(segment) = options.eci.and_then(Segment::eci) {
+        segments.insert(0, segment);
+    }
+
+    // rMQR codes are long and thin, for labels where a square doesn't fit
+    if options.rmqr {
+        let version = match rmqr::select_version(&segments, rmqr::ecc_level(ecc_level)) {
+            Some(version) => version,
+            None => {
+                eprintln!("Password too long for an rMQR code at this error correction level");
+                std::process::exit(1);
+            }
+        };
 
-    // Start with an empty 25x25 matrix
-    let mut matrix = qr_code::Matrix::new();
+        println!("rMQR code of size {}", rmqr::name(version));
 
-    // Populate it with fixed patterns, data, and format information
-    matrix.place_finder_pattern();
-    matrix.place_alignment_pattern();
-    matrix.place_dark_module();
-    matrix.place_timing_pattern();
-    matrix.reserve_format_area();
-    matrix.fill_data(data_ecc);
-    matrix.mask_and_place_format_string();
+        let ecc_level = if options.boost_ecc {
+            let level = rmqr::boost_ecc_level(&segments, version, ecc_level);
+            println!("Error correction level: {level:?}");
+            level
+        } else {
+            ecc_level
+        };
+
+        let matrix = rmqr::build_matrix(&segments, version, ecc_level);
+        let png = form_png(matrix.export_transformed(&options.transform));
+        fs::write("./qr_code.png", png).expect("Unable to write file");
+
+        if options.verify {
+            let files = ["./qr_code.png".to_string()];
+            verify_written(&files, &bits, &options.encoding, &options);
+        }
+
+        return;
+    }
+
+    let version = match qr_code::select_version(&segments, ecc_level) {
+        Some(version) => version,
+        None => {
+            split_long_password(&bits, &options, &header);
+            return;
+        }
+    };
+
+    // Use spare capacity for more error correction instead of padding
+    let ecc_level = if options.boost_ecc {
+        let level = qr_code::boost_ecc_level(&segments, version, ecc_level);
+        println!("Error correction level: {level:?}");
+        level
+    } else {
+        ecc_level
+    };
+
+    // Add mode indicator, length indicator, padding, error correction,
+    // and place everything in a matrix of the chosen version's size
+    let (matrix, choice) = match qr_code::build_matrix_masked(
+        &segments,
+        version,
+        ecc_level,
+        options.mask,
+        options.penalty,
+    ) {
+        Ok(built) => built,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    println!("Mask: {}", choice.mask);
 
     // Save the final matrix of black and white modules and add four
     // modules of white space on all sides
-    let qr_final = matrix.export();
+    // Invert, mirror or rotate it if asked to
+    let qr_final = matrix.export_transformed(&options.transform);
 
     // Form a PNG and write it to disk
     let png = form_png(qr_final);
 
     fs::write("./qr_code.png", png).expect("Unable to write file");
+
+    // Read it back to make sure it holds the password
+    if options.verify {
+        let files = ["./qr_code.png".to_string()];
+        verify_written(&files, &bits, &options.encoding, &options);
+    }
+}
+
+// Divide the password into segments following the chosen encoding
+// The best mix of modes depends on the version, so it is chosen together
+// with the smallest version that holds it. None if no version does
+fn password_segments(
+    bits: &[u8],
+    options: &Options,
+    header: &[Segment],
+    ecc_level: EccLevel,
+) -> Result<Option<Vec<Segment>>, &'static str> {
+    let mode = match &options.encoding {
+        Encoding::Automatic if qr_code::is_numeric(bits) => qr_code::Mode::Numeric,
+        Encoding::Automatic => qr_code::Mode::Alphanumeric,
+        Encoding::Single(mode) => *mode,
+        Encoding::Optimal | Encoding::Gs1 => {
+            return Ok(qr_code::select_version_optimal(header, bits, ecc_level)
+                .map(|(segments, _version)| segments));
+        }
+    };
+
+    let segments = match mode {
+        // Passwords of digits only are stored in numeric mode
+        qr_code::Mode::Numeric => {
+            if !qr_code::is_numeric(bits) {
+                return Err("Numeric mode requires a password of digits only");
+            }
+            vec![Segment::numeric(bits)]
+        }
+        // Encode the binary stream in base45 / alphanumeric, as a single
+        // number or following RFC 9285
+        qr_code::Mode::Alphanumeric => {
+            vec![Segment::alphanumeric(&options.base45.encode(bits))]
+        }
+        // Read the password as UTF-8 text, split into kanji and byte
+        // segments
+        qr_code::Mode::Kanji => match std::str::from_utf8(bits) {
+            Ok(text) => qr_code::kanji_segments(text),
+            Err(_) => return Err("Kanji mode requires a UTF-8 encoded password"),
+        },
+        // Byte mode: keep the bytes as they are
+        _ => vec![Segment::bytes(bits)],
+    };
+
+    Ok(Some(segments))
+}
+
+// Print the smallest version for every error correction level, the space
+// the segments take up in it, and the modes they are written in
+fn report_capacity<F>(segments_for: F)
+where
+    F: Fn(EccLevel) -> Result<Option<Vec<Segment>>, &'static str>,
+{
+    println!("Level  Version  Bits used  Bits available  Pad codewords  Segments");
+
+    for ecc_level in [EccLevel::L, EccLevel::M, EccLevel::Q, EccLevel::H] {
+        let capacity = match segments_for(ecc_level) {
+            Ok(Some(segments)) => qr_code::capacity(&segments, ecc_level).map(|capacity| {
+                let modes: Vec<String> = segments
+                    .iter()
+                    .map(|segment| format!("{:?} ({})", segment.mode(), segment.character_count()))
+                    .collect();
+                (capacity, modes.join(", "))
+            }),
+            Ok(None) => None,
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        };
+
+        match capacity {
+            Some((capacity, modes)) => println!(
+                "{:<6} {:<8} {:<10} {:<15} {:<14} {}",
+                format!("{ecc_level:?}"),
+                capacity.version,
+                capacity.bits_used,
+                capacity.bits_available,
+                capacity.pad_codewords,
+                modes
+            ),
+            None => println!("{ecc_level:?}      too long for a single QR code"),
+        }
+    }
+}
+
+// Split the password across several QR codes of at most the given version
+// and number the files in the order a scanner expects them
+// Every part is encoded the way a whole password would be, so that it
+// scans the same
+// Returns false if 16 QR codes aren't enough
+fn write_split(bits: &[u8], options: &Options, max_version: usize) -> bool {
+    let matrices = match qr_code::structured_append_with(
+        bits,
+        options.ecc_level,
+        max_version,
+        options.eci,
+        options.mask,
+        options.penalty,
+        |header, part| part_segments(part, options, header),
+    ) {
+        Ok(Some(matrices)) => matrices,
+        Ok(None) => return false,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    let mut files: Vec<String> = vec![];
+    for (n, matrix) in matrices.iter().enumerate() {
+        let png = form_png(matrix.export_transformed(&options.transform));
+        let file = format!("./qr_code_{:02}.png", n + 1);
+        fs::write(&file, png).expect("Unable to write file");
+        files.push(file);
+    }
+
+    println!("Password split across {} QR codes", matrices.len());
+
+    if options.verify {
+        verify_written(&files, bits, &options.encoding, options);
+    }
+
+    true
+}
+
+// Segments of one part of a split password behind its structured append
+// header, and the smallest version that holds them
+fn part_segments(
+    part: &[u8],
+    options: &Options,
+    header: &[Segment],
+) -> Option<(Vec<Segment>, usize)> {
+    let ecc_level = options.ecc_level;
+    if let Encoding::Optimal = options.encoding {
+        return qr_code::select_version_optimal(header, part, ecc_level);
+    }
+
+    let mut segments = header.to_vec();
+    segments.extend(password_segments(part, options, &[], ecc_level).ok()??);
+    let version = qr_code::select_version(&segments, ecc_level)?;
+
+    Some((segments, version))
+}
+
+// A password that doesn't fit into a single QR code goes into several
+// ones of up to the largest version, never into a truncated one
+// GS1 element strings can't be split, they need their FNC1 header, and
+// neither can Micro QR and rMQR codes, nor kanji text, whose characters
+// could be cut in half
+fn split_long_password(bits: &[u8], options: &Options, header: &[Segment]) {
+    if options.micro || options.rmqr {
+        eprintln!("Password too long for a Micro QR or rMQR code");
+        std::process::exit(1);
+    }
+    if !header.is_empty() {
+        eprintln!("GS1 element string too long for a single QR code");
+        std::process::exit(1);
+    }
+    if let Encoding::Single(qr_code::Mode::Kanji) = options.encoding {
+        eprintln!("Kanji password too long for a single QR code");
+        std::process::exit(1);
+    }
+
+    println!(
+        "Password too long for a single QR code ({} bytes)",
+        bits.len()
+    );
+    if !write_split(bits, options, 40) {
+        eprintln!("Password too long even for 16 QR codes");
+        std::process::exit(1);
+    }
+}
+
+// Read the written QR codes back and compare the password they hold, part
+// after part, with the original one
+// On a mismatch, delete them and exit with an error: a QR code that
+// doesn't hold the password is worse than none
+fn verify_written(files: &[String], password: &[u8], encoding: &Encoding, options: &Options) {
+    let result = files.iter().try_fold(vec![], |mut message, file| {
+        let png = fs::read(file).map_err(|_| "Unable to read the written file")?;
+        message.extend(read_password(
+            &read_png(&png, &options.transform)?,
+            encoding,
+            options.base45,
+        )?);
+        Ok::<Vec<u8>, &'static str>(message)
+    });
+
+    let err = match result {
+        Ok(message) if message == password => return,
+        Ok(_) => "QR code doesn't hold the password",
+        Err(err) => err,
+    };
+
+    eprintln!("Verification failed: {err}");
+    for file in files {
+        fs::remove_file(file).expect("Unable to delete file");
+    }
+    std::process::exit(1);
 }
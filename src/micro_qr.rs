@@ -0,0 +1,658 @@
+use crate::qr_code::{
+    EccLevel, Mode, Segment, Transform, append_bits, bits_to_codewords, format_bch, mask_pattern,
+    place_zigzag, reed_solomon,
+};
+
+// Represents a Micro QR code of version M1 (11 x 11 modules) to M4
+// (17 x 17 modules). Each version adds 2 modules per side.
+// There is a single finder pattern and the timing patterns run along the
+// top and left edges. Reed-Solomon coding, data placement, and mask
+// patterns are shared with regular QR codes.
+// Matrix::mask defines areas where data cannot be written
+#[derive(Clone)]
+pub struct Matrix {
+    version: usize,
+    ecc_level: EccLevel,
+    size: usize,
+    data: Vec<Vec<u8>>,
+    mask: Vec<Vec<bool>>,
+}
+
+// Data capacity in bits for each version and ECC level L, M, Q
+// M1 and M3 end with a data codeword of only 4 bits
+// M1 only detects errors, it is listed as level L. 0 marks combinations
+// that don't exist. (index 0 is unused)
+const DATA_BITS: [[usize; 3]; 5] = [
+    [0, 0, 0],
+    [20, 0, 0],
+    [40, 32, 0],
+    [84, 68, 0],
+    [128, 112, 80],
+];
+
+// Error correction codewords for each version and ECC level L, M, Q
+// Every Micro QR code consists of a single block
+pub(crate) const ECC_CODEWORDS: [[usize; 3]; 5] =
+    [[0, 0, 0], [2, 0, 0], [5, 6, 0], [6, 8, 0], [8, 10, 14]];
+
+// QR masks 1, 4, 6 and 7 are the only ones used for Micro QR codes
+pub(crate) const MASKS: [usize; 4] = [1, 4, 6, 7];
+
+// Version and ECC level of each symbol number in the format information:
+// 0 for M1, 1-2 for M2-L/M, 3-4 for M3-L/M, 5-7 for M4-L/M/Q
+pub(crate) const SYMBOLS: [(usize, EccLevel); 8] = [
+    (1, EccLevel::L),
+    (2, EccLevel::L),
+    (2, EccLevel::M),
+    (3, EccLevel::L),
+    (3, EccLevel::M),
+    (4, EccLevel::L),
+    (4, EccLevel::M),
+    (4, EccLevel::Q),
+];
+
+// Data capacity of a version at an ECC level, if the combination exists
+pub(crate) fn data_bits(version: usize, ecc_level: EccLevel) -> Option<usize> {
+    match DATA_BITS[version].get(ecc_level as usize) {
+        Some(0) | None => None,
+        Some(bits) => Some(*bits),
+    }
+}
+
+// Width of the character count indicator for each mode and version
+// M1 only knows numeric mode, M2 numeric and alphanumeric mode
+pub(crate) fn character_count_bits(mode: Mode, version: usize) -> Option<usize> {
+    match (mode, version) {
+        (Mode::Numeric, _) => Some(version + 2),
+        (Mode::Alphanumeric, 2..=4) => Some(version + 1),
+        (Mode::Byte, 3..=4) => Some(version + 1),
+        (Mode::Kanji, 3..=4) => Some(version),
+        _ => None,
+    }
+}
+
+// Mode indicators are version - 1 bits wide, M1 has none at all
+fn mode_indicator(mode: Mode) -> u32 {
+    match mode {
+        Mode::Numeric => 0,
+        Mode::Alphanumeric => 1,
+        Mode::Byte => 2,
+        _ => 3,
+    }
+}
+
+// Mode of a mode indicator, the reverse of mode_indicator
+pub(crate) fn mode(indicator: u32) -> Mode {
+    match indicator {
+        0 => Mode::Numeric,
+        1 => Mode::Alphanumeric,
+        2 => Mode::Byte,
+        _ => Mode::Kanji,
+    }
+}
+
+// Number of bits a segment takes up in a given version, including mode and
+// length indicator
+// None if the version doesn't support the mode, or if there are more
+// characters than the length indicator can count
+fn segment_bits(segment: &Segment, version: usize) -> Option<usize> {
+    let count_bits = character_count_bits(segment.mode, version)?;
+    if segment.character_count >= 1 << count_bits {
+        return None;
+    }
+
+    Some(version - 1 + count_bits + segment.bits.len())
+}
+
+// Find the smallest version whose data capacity fits all segments
+// ECI and structured append don't exist in Micro QR codes
+pub fn select_version(segments: &[Segment], ecc_level: EccLevel) -> Option<usize> {
+    (1..=4).find(|&version| fits_version(segments, version, ecc_level))
+}
+
+// Raise the error correction level as far as the segments still fit into
+// the given version. Never lowers the level
+pub fn boost_ecc_level(segments: &[Segment], version: usize, ecc_level: EccLevel) -> EccLevel {
+    [EccLevel::Q, EccLevel::M]
+        .into_iter()
+        .find(|&level| {
+            level as usize > ecc_level as usize && fits_version(segments, version, level)
+        })
+        .unwrap_or(ecc_level)
+}
+
+// Check whether all segments fit into a given version, if the version
+// exists at that level at all
+fn fits_version(segments: &[Segment], version: usize, ecc_level: EccLevel) -> bool {
+    let capacity = match data_bits(version, ecc_level) {
+        Some(capacity) => capacity,
+        None => return false,
+    };
+
+    let mut total = 0;
+    for segment in segments {
+        match segment_bits(segment, version) {
+            Some(bits) => total += bits,
+            None => return false,
+        }
+    }
+
+    total <= capacity
+}
+
+// Take a list of segments and add everything needed to build a Micro QR
+// code
+// Terminator and padding follow the same scheme as in QR codes, except
+// that the terminator is longer the larger the version
+pub fn encapsulate_data(segments: &[Segment], version: usize, ecc_level: EccLevel) -> Vec<u8> {
+    let capacity = data_bits(version, ecc_level).unwrap_or(0);
+    let mut data: Vec<u8> = Vec::with_capacity(capacity);
+
+    for segment in segments {
+        append_bits(&mut data, mode_indicator(segment.mode), version - 1);
+        append_bits(
+            &mut data,
+            segment.character_count as u32,
+            character_count_bits(segment.mode, version).unwrap_or(0),
+        );
+        data.extend_from_slice(&segment.bits);
+    }
+
+    // If there's space left over, add terminator of 0s
+    // (3, 5, 7 or 9 bits)
+    for _n in 0..version * 2 + 1 {
+        if data.len() == capacity {
+            break;
+        }
+        data.push(0);
+    }
+
+    // Pad to full bytes
+    while !data.len().is_multiple_of(8) && data.len() < capacity {
+        data.push(0);
+    }
+
+    // Fill remaining space with alternating padding bytes
+    let mut pad_byte = 236;
+
+    while data.len() + 8 <= capacity {
+        append_bits(&mut data, pad_byte, 8);
+        pad_byte = if pad_byte == 236 { 17 } else { 236 };
+    }
+
+    // The short last codeword of M1 and M3 is filled with 0s
+    data.resize(capacity, 0);
+
+    data
+}
+
+// Calculate Reed-Solomon code words and append them to the data
+// The short last data codeword of M1 and M3 counts as a full byte with
+// 4 bits of 0s for the calculation, but only its 4 data bits are placed
+pub fn apply_ecc(data: Vec<u8>, version: usize, ecc_level: EccLevel) -> Vec<u8> {
+    let mut full_bytes = data.clone();
+    while !full_bytes.len().is_multiple_of(8) {
+        full_bytes.push(0);
+    }
+
+    let message = bits_to_codewords(&full_bytes);
+    let ecc = reed_solomon(&message, ECC_CODEWORDS[version][ecc_level as usize]);
+
+    let mut message_ecc = data;
+    for codeword in ecc {
+        append_bits(&mut message_ecc, codeword as u32, 8);
+    }
+
+    message_ecc
+}
+
+// Turn segments into a fully formed Micro QR code
+pub fn build_matrix(segments: &[Segment], version: usize, ecc_level: EccLevel) -> Matrix {
+    // Add mode indicator, length indicator, padding, etc.
+    let data_bits = encapsulate_data(segments, version, ecc_level);
+
+    // Add error correction codewords
+    let data_ecc = apply_ecc(data_bits, version, ecc_level);
+
+    // Start with an empty matrix of the chosen version's size
+    let mut matrix = Matrix::new(version, ecc_level);
+
+    // Populate it with fixed patterns, data, and format information
+    matrix.place_finder_pattern();
+    matrix.place_timing_pattern();
+    matrix.reserve_format_area();
+    matrix.fill_data(data_ecc);
+    matrix.mask_and_place_format_string();
+
+    matrix
+}
+
+// Modules taken up by fixed patterns and format information, where no
+// data goes
+pub(crate) fn function_modules(version: usize) -> Vec<Vec<bool>> {
+    let mut matrix = Matrix::new(version, EccLevel::L);
+
+    matrix.place_finder_pattern();
+    matrix.place_timing_pattern();
+    matrix.reserve_format_area();
+
+    matrix.mask
+}
+
+// Format string XOR mask, which keeps it from ever being all 0s
+pub(crate) const FORMAT_MASK: u16 = 0b100010001000101;
+
+// Module holding bit n of the format string
+// Bits 0 to 7 go down the column right of the separator, bits 8 to 14
+// leftward along the row below it
+pub(crate) fn format_position(n: usize) -> (usize, usize) {
+    if n < 8 { (1 + n, 8) } else { (8, 15 - n) }
+}
+
+// Representation of a 2D Micro QR code and methods for preparing,
+// populating, and extracting it
+impl Matrix {
+    // Every module is represented by one u8: 0 - white, 1 - black
+    // Keep track of prohibited areas, where data can't be written
+    pub fn new(version: usize, ecc_level: EccLevel) -> Matrix {
+        let size = version * 2 + 9;
+        Matrix {
+            version,
+            ecc_level,
+            size,
+            data: vec![vec![0; size]; size],
+            mask: vec![vec![false; size]; size],
+        }
+    }
+
+    // Only one finder pattern, in the upper left corner
+    pub fn place_finder_pattern(&mut self) {
+        // Apply pattern
+        for n in 0..7 {
+            self.data[0][n] = 1;
+            self.data[n][0] = 1;
+            self.data[6][n] = 1;
+            self.data[n][6] = 1;
+        }
+        for n in 0..3 {
+            for i in 0..3 {
+                self.data[2 + n][2 + i] = 1;
+            }
+        }
+
+        // Block area for data bits
+        // Include a separating line below and right of the finder pattern
+        for n in 0..8 {
+            for i in 0..8 {
+                self.mask[n][i] = true;
+            }
+        }
+    }
+
+    // Timing patterns run along the top and left edges, starting right
+    // after the separator
+    pub fn place_timing_pattern(&mut self) {
+        for n in 8..self.size {
+            if n % 2 == 0 {
+                self.data[0][n] = 1;
+                self.data[n][0] = 1;
+            }
+            self.mask[0][n] = true;
+            self.mask[n][0] = true;
+        }
+    }
+
+    // Reserve space for formatting information next to the separator
+    // Will be added at the last step
+    pub fn reserve_format_area(&mut self) {
+        for n in 1..9 {
+            self.mask[8][n] = true;
+            self.mask[n][8] = true;
+        }
+    }
+
+    // Data is placed in the same zig-zag pattern as in QR codes
+    // There's no timing pattern to skip, since it lies on the edge
+    pub fn fill_data(&mut self, data_bits: Vec<u8>) {
+        place_zigzag(&mut self.data, &self.mask, &data_bits, self.size - 1, None);
+    }
+
+    // 4 masking patterns exist. Apply each one in turn, evaluate all of
+    // them, then choose the one with the highest score.
+    pub fn mask_and_place_format_string(&mut self) {
+        let mut highest_score = (0, 0);
+        let mut best_data = self.data.clone();
+
+        for n in 0..4 {
+            let mut candidate = self.clone();
+            candidate.transform(n);
+            let score = candidate.evaluate();
+            if score > highest_score.1 || n == 0 {
+                highest_score = (n, score);
+                best_data = candidate.data;
+            }
+        }
+
+        self.data = best_data;
+        self.place_format_string(highest_score.0);
+    }
+
+    // Toggle data bits following one of the four Micro QR patterns
+    fn transform(&mut self, mask_no: usize) {
+        let eval = mask_pattern(MASKS[mask_no]);
+
+        for row in 0..self.size {
+            for col in 0..self.size {
+                if !self.mask[row][col] && eval(row, col) == 0 {
+                    self.data[row][col] ^= 1;
+                }
+            }
+        }
+    }
+
+    // Count dark modules along the right and bottom edges (without the
+    // timing patterns). A scanner needs these edges to find the symbol, so
+    // the more dark modules, the better. The lower of the two counts
+    // weighs more.
+    fn evaluate(&self) -> usize {
+        let last = self.size - 1;
+        let sum_right: usize = (1..self.size)
+            .map(|row| self.data[row][last] as usize)
+            .sum();
+        let sum_bottom: usize = (1..self.size)
+            .map(|col| self.data[last][col] as usize)
+            .sum();
+
+        if sum_right <= sum_bottom {
+            sum_right * 16 + sum_bottom
+        } else {
+            sum_bottom * 16 + sum_right
+        }
+    }
+
+    // The format string consists of the symbol number (version and error
+    // correction level), mask number, and 10 error correction bits
+    fn place_format_string(&mut self, mask_no: usize) {
+        let symbol_number = SYMBOLS
+            .iter()
+            .position(|symbol| *symbol == (self.version, self.ecc_level))
+            .unwrap_or(0) as u16;
+        let format_data = symbol_number << 2 | mask_no as u16;
+        let format_string = format_bch(format_data) ^ FORMAT_MASK;
+
+        for n in 0..15 {
+            let (row, col) = format_position(n);
+            self.data[row][col] = ((format_string >> n) & 1) as u8;
+        }
+    }
+
+    // Return 2D matrix of modules, inverted, mirrored or rotated
+    pub fn export_transformed(&self, transform: &Transform) -> Vec<Vec<u8>> {
+        transform.apply(self.export())
+    }
+
+    // Return 2D matrix of modules
+    pub fn export(&self) -> Vec<Vec<u8>> {
+        // Add 2 modules of whitespace on all sides
+        let mut qr_final: Vec<Vec<u8>> = vec![vec![0; self.size + 4]; self.size + 4];
+
+        for row in 0..self.size {
+            for col in 0..self.size {
+                qr_final[row + 2][col + 2] = self.data[row][col];
+            }
+        }
+
+        qr_final
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rows of a symbol without quiet zone, '#' for dark modules
+    fn rows(matrix: &Matrix) -> Vec<String> {
+        matrix
+            .data
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|&bit| if bit == 1 { '#' } else { '.' })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn codewords(bits: &[u8]) -> Vec<String> {
+        bits_to_codewords(bits)
+            .iter()
+            .map(|codeword| format!("{codeword:08b}"))
+            .collect()
+    }
+
+    // Format string as placed, bit 0 first
+    fn format_string(matrix: &Matrix) -> u16 {
+        (0..15).fold(0, |format_string, n| {
+            let (row, col) = format_position(n);
+            format_string | (matrix.data[row][col] as u16) << n
+        })
+    }
+
+    // Matrix with data in place, but not yet masked
+    fn unmasked(segments: &[Segment], version: usize, ecc_level: EccLevel) -> Matrix {
+        let data = apply_ecc(
+            encapsulate_data(segments, version, ecc_level),
+            version,
+            ecc_level,
+        );
+        let mut matrix = Matrix::new(version, ecc_level);
+        matrix.place_finder_pattern();
+        matrix.place_timing_pattern();
+        matrix.reserve_format_area();
+        matrix.fill_data(data);
+
+        matrix
+    }
+
+    #[test]
+    fn m2_l_codewords_match_the_iso_example() {
+        let segments = [Segment::numeric(b"01234567")];
+        let data = encapsulate_data(&segments, 2, EccLevel::L);
+
+        assert_eq!(
+            codewords(&data),
+            ["01000000", "00011000", "10101100", "11000011", "00000000"]
+        );
+        assert_eq!(
+            codewords(&apply_ecc(data, 2, EccLevel::L)[40..]),
+            ["10000110", "00001101", "00100010", "10101110", "00110000"]
+        );
+    }
+
+    #[test]
+    fn m2_l_reference_symbol() {
+        let matrix = build_matrix(&[Segment::numeric(b"01234567")], 2, EccLevel::L);
+
+        assert_eq!(
+            rows(&matrix),
+            [
+                "#######.#.#.#",
+                "#.....#.###.#",
+                "#.###.#..##.#",
+                "#.###.#..####",
+                "#.###.#.###..",
+                "#.....#.#...#",
+                "#######..####",
+                ".........##..",
+                "##.#....#...#",
+                ".##.#.#.#.#.#",
+                "###..#######.",
+                "...#.#....##.",
+                "###.#..##.###",
+            ]
+        );
+    }
+
+    #[test]
+    fn m4_l_reference_symbol() {
+        // "AC-42"
+        let matrix = build_matrix(
+            &[Segment::alphanumeric(&[10, 12, 41, 4, 2])],
+            4,
+            EccLevel::L,
+        );
+
+        assert_eq!(
+            rows(&matrix),
+            [
+                "#######.#.#.#.#.#",
+                "#.....#.####.....",
+                "#.###.#..#.###..#",
+                "#.###.#.####....#",
+                "#.###.#..######..",
+                "#.....#.#........",
+                "#######.#.#..##..",
+                "............#..#.",
+                "#..#.###..#..##..",
+                ".#.#########.###.",
+                "#.####.###.###.#.",
+                ".##.#.######.####",
+                "#..#.##..########",
+                ".#.#.#.#.......#.",
+                "#...#.#.#.#...#.#",
+                ".###....#...#...#",
+                "######.##.#...###",
+            ]
+        );
+    }
+
+    #[test]
+    fn m3_m_reference_symbol_ends_data_with_a_half_codeword() {
+        let matrix = build_matrix(&[Segment::bytes(b"ab")], 3, EccLevel::M);
+
+        assert_eq!(
+            rows(&matrix),
+            [
+                "#######.#.#.#.#",
+                "#.....#.###.###",
+                "#.###.#....#...",
+                "#.###.#..##.#..",
+                "#.###.#.#....#.",
+                "#.....#...##.##",
+                "#######.#.##..#",
+                "........##.#.#.",
+                "#.....#######.#",
+                ".#....#.#...###",
+                "#..#....###...#",
+                ".#####..#.#.##.",
+                "#.....###....#.",
+                "..###..###.#.##",
+                "###...#.####..#",
+            ]
+        );
+    }
+
+    #[test]
+    fn m1_and_m3_ecc_counts_the_half_codeword_as_a_full_one() {
+        for (version, ecc_level) in [(1, EccLevel::L), (3, EccLevel::L), (3, EccLevel::M)] {
+            let capacity = data_bits(version, ecc_level).unwrap();
+            assert_eq!(capacity % 8, 4);
+
+            let data = encapsulate_data(&[Segment::numeric(b"1")], version, ecc_level);
+            assert_eq!(data.len(), capacity);
+
+            // Only the 4 data bits of the last codeword are placed, the
+            // error correction codewords follow right after them
+            let ecc_length = ECC_CODEWORDS[version][ecc_level as usize];
+            let data_ecc = apply_ecc(data.clone(), version, ecc_level);
+            assert_eq!(data_ecc.len(), capacity + ecc_length * 8);
+
+            let mut full_bytes = data.clone();
+            full_bytes.extend([0; 4]);
+            let ecc = reed_solomon(&bits_to_codewords(&full_bytes), ecc_length);
+            assert_eq!(bits_to_codewords(&data_ecc[capacity..]), ecc);
+        }
+    }
+
+    #[test]
+    fn format_string_is_masked_with_0x4445() {
+        assert_eq!(FORMAT_MASK, 0x4445);
+
+        // M2-L with mask 1 in the reference symbol above
+        let matrix = build_matrix(&[Segment::numeric(b"01234567")], 2, EccLevel::L);
+        assert_eq!(format_string(&matrix), 0b101000010011001);
+        assert_eq!(format_string(&matrix) ^ 0x4445, format_bch(1 << 2 | 1));
+
+        // Bits 0 to 7 down column 8, bits 8 to 14 leftward along row 8
+        assert_eq!(format_position(0), (1, 8));
+        assert_eq!(format_position(7), (8, 8));
+        assert_eq!(format_position(8), (8, 7));
+        assert_eq!(format_position(14), (8, 1));
+
+        // Every symbol number lands in the top 3 data bits
+        for (symbol_number, (version, ecc_level)) in SYMBOLS.into_iter().enumerate() {
+            let matrix = build_matrix(&[Segment::numeric(b"1")], version, ecc_level);
+            let format_data = (0..32)
+                .find(|&data| format_bch(data) ^ 0x4445 == format_string(&matrix))
+                .unwrap();
+            assert_eq!(format_data as usize >> 2, symbol_number);
+        }
+    }
+
+    #[test]
+    fn masks_are_qr_masks_1_4_6_and_7() {
+        // Micro QR mask conditions, by row i and column j
+        let conditions: [fn(usize, usize) -> bool; 4] = [
+            |i, _j| i % 2 == 0,
+            |i, j| (i / 2 + j / 3) % 2 == 0,
+            |i, j| ((i * j) % 2 + (i * j) % 3) % 2 == 0,
+            |i, j| ((i + j) % 2 + (i * j) % 3) % 2 == 0,
+        ];
+        let blank = unmasked(&[Segment::numeric(b"1")], 4, EccLevel::L);
+
+        for (mask_no, condition) in conditions.iter().enumerate() {
+            let mut masked = blank.clone();
+            masked.transform(mask_no);
+            for row in 0..blank.size {
+                for col in 0..blank.size {
+                    let toggled = masked.data[row][col] != blank.data[row][col];
+                    assert_eq!(toggled, !blank.mask[row][col] && condition(row, col));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mask_with_the_best_edges_is_chosen() {
+        for text in [&b"01234567"[..], b"5", b"99999"] {
+            let segments = [Segment::numeric(text)];
+            let blank = unmasked(&segments, 2, EccLevel::L);
+
+            // Highest score wins, the lowest mask number among equals
+            let scores: Vec<usize> = (0..4)
+                .map(|mask_no| {
+                    let mut candidate = blank.clone();
+                    candidate.transform(mask_no);
+                    candidate.evaluate()
+                })
+                .collect();
+            let best = (0..4).rev().max_by_key(|&n| scores[n]).unwrap();
+
+            let matrix = build_matrix(&segments, 2, EccLevel::L);
+            assert_eq!(
+                format_string(&matrix) ^ 0x4445,
+                format_bch(1 << 2 | best as u16)
+            );
+        }
+
+        // Dark modules along the weaker edge count 16 times
+        let mut matrix = Matrix::new(1, EccLevel::L);
+        for row in 1..10 {
+            matrix.data[row][10] = 1;
+        }
+        assert_eq!(matrix.evaluate(), 9);
+        matrix.data[10][1] = 1;
+        matrix.data[10][2] = 1;
+        assert_eq!(matrix.evaluate(), 2 * 16 + 9);
+    }
+}
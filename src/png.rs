@@ -0,0 +1,1060 @@
+use crate::{calculate_adler32, calculate_crc};
+
+// A grayscale image, one byte per pixel from 0 (black) to 255 (white),
+// row by row from the top
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrayImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl GrayImage {
+    // Brightness of the pixel in a given column and row
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+}
+
+// Image properties from the IHDR chunk
+struct Header {
+    width: usize,
+    height: usize,
+    bit_depth: u8,
+    color_type: u8,
+    interlaced: bool,
+}
+
+impl Header {
+    // Number of samples per pixel: gray, gray and alpha, RGB, RGBA, or a
+    // palette index
+    fn channels(&self) -> usize {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+
+    // Number of bytes a row of the given width takes up, without the filter
+    // type byte
+    // None if it doesn't fit into memory
+    fn line_length(&self, width: usize) -> Option<usize> {
+        Some(
+            width
+                .checked_mul(self.channels() * self.bit_depth as usize)?
+                .div_ceil(8),
+        )
+    }
+
+    // Distance to the corresponding byte of the pixel to the left, as used
+    // by the filters: at least one byte, even for bit depths below 8
+    fn filter_distance(&self) -> usize {
+        (self.channels() * self.bit_depth as usize).div_ceil(8)
+    }
+}
+
+// Starting column and row, and the distance between pixels, of each of the
+// seven Adam7 passes
+const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+// Decode a PNG file into a grayscale image
+// Supports every color type and bit depth, as well as interlacing.
+// Colors are converted to their luminance, transparent pixels are put on a
+// white background.
+pub fn decode_png(file: &[u8]) -> Result<GrayImage, &'static str> {
+    if file.len() < 8 || file[..8] != [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A] {
+        return Err("Not a PNG file");
+    }
+
+    // Collect the chunks the image is made of
+    // Every chunk consists of the length of its data, a type, the data and
+    // a CRC of type and data
+    let mut header: Option<Header> = None;
+    let mut palette: Vec<[u8; 3]> = vec![];
+    let mut transparency: Vec<u8> = vec![];
+    let mut compressed: Vec<u8> = vec![];
+    let mut position = 8;
+
+    loop {
+        if position + 12 > file.len() {
+            return Err("PNG file ends before the IEND chunk");
+        }
+        let length = u32::from_be_bytes([
+            file[position],
+            file[position + 1],
+            file[position + 2],
+            file[position + 3],
+        ]) as usize;
+        if position + 12 + length > file.len() {
+            return Err("PNG file ends in the middle of a chunk");
+        }
+
+        let chunk = &file[position + 4..position + 8 + length];
+        let crc = &file[position + 8 + length..position + 12 + length];
+        if calculate_crc(chunk).to_be_bytes() != crc {
+            return Err("PNG chunk has a wrong CRC");
+        }
+
+        let (chunk_type, data) = chunk.split_at(4);
+        match chunk_type {
+            b"IHDR" => header = Some(read_header(data)?),
+            b"PLTE" => {
+                palette = data
+                    .chunks_exact(3)
+                    .map(|color| [color[0], color[1], color[2]])
+                    .collect()
+            }
+            b"tRNS" => transparency = data.to_vec(),
+            b"IDAT" => compressed.extend_from_slice(data),
+            b"IEND" => break,
+            // Ancillary chunks start with a lowercase letter and may be
+            // skipped, all others are needed to display the image
+            _ if chunk_type[0].is_ascii_lowercase() => {}
+            _ => return Err("PNG file contains an unknown critical chunk"),
+        }
+
+        position += 12 + length;
+    }
+
+    let header = match header {
+        Some(header) => header,
+        None => return Err("PNG file has no IHDR chunk"),
+    };
+    if header.color_type == 3 && palette.is_empty() {
+        return Err("PNG file has no palette");
+    }
+
+    // Without a palette, a tRNS chunk names a single fully transparent gray
+    // value or color, with 16 bits per sample
+    let transparent_color: Option<Vec<u16>> = (header.color_type != 3 && !transparency.is_empty())
+        .then(|| {
+            transparency
+                .chunks_exact(2)
+                .map(|value| u16::from_be_bytes([value[0], value[1]]))
+                .collect()
+        });
+    let colors = Colors {
+        palette,
+        palette_alpha: transparency,
+        transparent_color,
+    };
+
+    let filtered = inflate_zlib(&compressed)?;
+    let passes: &[(usize, usize, usize, usize)] = if header.interlaced {
+        &ADAM7
+    } else {
+        &[(0, 0, 1, 1)]
+    };
+
+    // Width, height and line length of every pass, and the length of the
+    // filtered data they add up to
+    // Empty passes have no filter type bytes either
+    let mut sizes: Vec<(usize, usize, usize)> = Vec::with_capacity(passes.len());
+    let mut expected_length: usize = 0;
+    for (x_start, y_start, x_step, y_step) in passes {
+        let width = (header.width + x_step - 1 - x_start) / x_step;
+        let height = (header.height + y_step - 1 - y_start) / y_step;
+        let length = header.line_length(width).ok_or("PNG image is too large")?;
+        if width > 0 && height > 0 {
+            expected_length = length
+                .checked_add(1)
+                .and_then(|line| line.checked_mul(height))
+                .and_then(|pass| pass.checked_add(expected_length))
+                .ok_or("PNG image is too large")?;
+        }
+        sizes.push((width, height, length));
+    }
+
+    // The header alone can't be trusted: only set aside memory for an
+    // image whose data is all there
+    if filtered.len() < expected_length {
+        return Err("PNG image data is too short");
+    }
+    let pixel_count = header
+        .width
+        .checked_mul(header.height)
+        .ok_or("PNG image is too large")?;
+
+    // Undo the filters pass by pass, then place each pixel where it belongs
+    let mut image = GrayImage {
+        width: header.width,
+        height: header.height,
+        pixels: vec![255; pixel_count],
+    };
+    let mut rest: &[u8] = &filtered;
+
+    for ((x_start, y_start, x_step, y_step), &(width, height, length)) in passes.iter().zip(&sizes)
+    {
+        if width == 0 || height == 0 {
+            continue;
+        }
+
+        let (pass, next) = rest.split_at((length + 1) * height);
+        rest = next;
+
+        let lines = unfilter(pass, length, header.filter_distance())?;
+        for (n, line) in lines.chunks(length).enumerate() {
+            for m in 0..width {
+                let gray = gray_value(&header, &colors, line, m)?;
+                image.pixels[(y_start + n * y_step) * header.width + x_start + m * x_step] = gray;
+            }
+        }
+    }
+
+    Ok(image)
+}
+
+// Largest width and height accepted, far more than any photo of a QR code
+// needs. Larger images would take gigabytes of memory.
+const MAX_SIZE: usize = 1 << 14;
+
+// Read and check width, height, bit depth, color type and interlace method
+fn read_header(data: &[u8]) -> Result<Header, &'static str> {
+    if data.len() != 13 {
+        return Err("PNG IHDR chunk has the wrong length");
+    }
+
+    let header = Header {
+        width: u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize,
+        height: u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize,
+        bit_depth: data[8],
+        color_type: data[9],
+        interlaced: data[12] == 1,
+    };
+
+    // Bit depths allowed for each color type
+    let bit_depths: &[u8] = match header.color_type {
+        0 => &[1, 2, 4, 8, 16],
+        3 => &[1, 2, 4, 8],
+        2 | 4 | 6 => &[8, 16],
+        _ => return Err("PNG file has an unknown color type"),
+    };
+    if !bit_depths.contains(&header.bit_depth) {
+        return Err("PNG file has a bit depth not allowed for its color type");
+    }
+    if data[10] != 0 || data[11] != 0 || data[12] > 1 {
+        return Err("PNG file uses an unknown compression, filter or interlace method");
+    }
+    if header.width == 0 || header.height == 0 {
+        return Err("PNG image is empty");
+    }
+    if header.width > MAX_SIZE || header.height > MAX_SIZE {
+        return Err("PNG image is too large");
+    }
+
+    Ok(header)
+}
+
+// Reverse the filter of every line: each line starts with a filter type,
+// which tells how its bytes were predicted from the bytes to the left and
+// above
+fn unfilter(data: &[u8], length: usize, distance: usize) -> Result<Vec<u8>, &'static str> {
+    let mut lines: Vec<u8> = Vec::with_capacity(data.len());
+    let mut previous: Vec<u8> = vec![0; length];
+
+    for filtered in data.chunks(length + 1) {
+        let mut line: Vec<u8> = Vec::with_capacity(length);
+
+        for n in 0..length {
+            let left = if n >= distance { line[n - distance] } else { 0 };
+            let above = previous[n];
+            let upper_left = if n >= distance {
+                previous[n - distance]
+            } else {
+                0
+            };
+
+            let prediction = match filtered[0] {
+                0 => 0,
+                1 => left,
+                2 => above,
+                3 => ((left as u16 + above as u16) / 2) as u8,
+                4 => paeth(left, above, upper_left),
+                _ => return Err("PNG image data has an unknown filter type"),
+            };
+            line.push(filtered[n + 1].wrapping_add(prediction));
+        }
+
+        lines.extend_from_slice(&line);
+        previous = line;
+    }
+
+    Ok(lines)
+}
+
+// Of the bytes to the left, above, and upper left, choose the one closest
+// to left + above - upper left
+fn paeth(left: u8, above: u8, upper_left: u8) -> u8 {
+    let estimate = left as i16 + above as i16 - upper_left as i16;
+    let distance_left = (estimate - left as i16).abs();
+    let distance_above = (estimate - above as i16).abs();
+    let distance_upper_left = (estimate - upper_left as i16).abs();
+
+    if distance_left <= distance_above && distance_left <= distance_upper_left {
+        left
+    } else if distance_above <= distance_upper_left {
+        above
+    } else {
+        upper_left
+    }
+}
+
+// Value of the n-th sample of a line, for any bit depth
+// Samples below 8 bits are packed into bytes from the MSB, 16-bit samples
+// are stored in big-endian order
+fn sample(line: &[u8], n: usize, bit_depth: u8) -> u16 {
+    match bit_depth {
+        16 => u16::from_be_bytes([line[n * 2], line[n * 2 + 1]]),
+        8 => line[n] as u16,
+        _ => {
+            let per_byte = 8 / bit_depth as usize;
+            let shift = 8 - bit_depth as usize * (n % per_byte + 1);
+            (line[n / per_byte] as u16 >> shift) & ((1 << bit_depth) - 1)
+        }
+    }
+}
+
+// Palette and transparency information from the PLTE and tRNS chunks
+struct Colors {
+    palette: Vec<[u8; 3]>,
+    // Alpha value of each palette entry, entries beyond are opaque
+    palette_alpha: Vec<u8>,
+    // Gray value or color that is fully transparent
+    transparent_color: Option<Vec<u16>>,
+}
+
+// Brightness of the n-th pixel of a line
+// Samples are scaled to 8 bits, colors weighted by their share in the
+// perceived brightness (ITU-R BT.601), and transparency blends the pixel
+// with a white background
+fn gray_value(header: &Header, colors: &Colors, line: &[u8], n: usize) -> Result<u8, &'static str> {
+    let channels = header.channels();
+    let depth = header.bit_depth;
+    let mut samples = [0; 4];
+    for (channel, value) in samples.iter_mut().take(channels).enumerate() {
+        *value = sample(line, n * channels + channel, depth);
+    }
+
+    let scale = |value: u16| -> u32 {
+        match depth {
+            16 => value as u32 >> 8,
+            _ => value as u32 * 255 / ((1 << depth) - 1),
+        }
+    };
+    let luminance = |red: u32, green: u32, blue: u32| (299 * red + 587 * green + 114 * blue) / 1000;
+    let keyed_alpha = match &colors.transparent_color {
+        Some(color) if color[..] == samples[..channels] => 0,
+        _ => 255,
+    };
+
+    let (gray, alpha) = match header.color_type {
+        0 => (scale(samples[0]), keyed_alpha),
+        2 => (
+            luminance(scale(samples[0]), scale(samples[1]), scale(samples[2])),
+            keyed_alpha,
+        ),
+        3 => {
+            let index = samples[0] as usize;
+            let color = match colors.palette.get(index) {
+                Some(color) => color,
+                None => return Err("PNG image uses a color missing from its palette"),
+            };
+            let alpha = colors.palette_alpha.get(index).copied().unwrap_or(255) as u32;
+            (
+                luminance(color[0] as u32, color[1] as u32, color[2] as u32),
+                alpha,
+            )
+        }
+        4 => (scale(samples[0]), scale(samples[1])),
+        _ => (
+            luminance(scale(samples[0]), scale(samples[1]), scale(samples[2])),
+            scale(samples[3]),
+        ),
+    };
+
+    Ok(((gray * alpha + 255 * (255 - alpha)) / 255) as u8)
+}
+
+// Base lengths and number of extra bits of length codes 257 to 285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+// Base distances and number of extra bits of distance codes 0 to 29
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// Order in which the code lengths of the code length alphabet are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+// Read a deflate stream bit by bit, starting from the LSB of every byte
+struct BitStream<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl BitStream<'_> {
+    // Next `length` bits as a number, the first bit read being the LSB
+    fn read(&mut self, length: usize) -> Result<u32, &'static str> {
+        let mut value = 0;
+
+        for n in 0..length {
+            let byte = match self.data.get(self.position / 8) {
+                Some(byte) => byte,
+                None => return Err("Compressed PNG data ends too early"),
+            };
+            value |= ((byte >> (self.position % 8)) as u32 & 1) << n;
+            self.position += 1;
+        }
+
+        Ok(value)
+    }
+
+    // Skip the remaining bits of the current byte
+    fn align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+}
+
+// Canonical Huffman code, defined by the code length of every symbol
+// Codes of the same length are numbered in the order of their symbols, and
+// come after all shorter codes
+struct Huffman {
+    // Number of codes of each length
+    counts: [u16; 16],
+    // Symbols ordered by code length, then by value
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, &'static str> {
+        let mut counts = [0; 16];
+        for length in lengths {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // There can't be more codes of a length than are left over by
+        // the shorter ones. Fewer codes are allowed.
+        let mut left: i32 = 1;
+        for count in &counts[1..] {
+            left = left * 2 - *count as i32;
+            if left < 0 {
+                return Err("Compressed PNG data has an invalid Huffman code");
+            }
+        }
+
+        let mut symbols: Vec<u16> = Vec::with_capacity(lengths.len());
+        for length in 1..16 {
+            for (symbol, symbol_length) in lengths.iter().enumerate() {
+                if *symbol_length as usize == length {
+                    symbols.push(symbol as u16);
+                }
+            }
+        }
+
+        Ok(Huffman { counts, symbols })
+    }
+
+    // Read a code bit by bit, until it falls into the range of codes of
+    // its length
+    fn decode(&self, stream: &mut BitStream) -> Result<u16, &'static str> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+
+        for count in &self.counts[1..] {
+            code |= stream.read(1)? as i32;
+            let count = *count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err("Compressed PNG data has an invalid Huffman code")
+    }
+}
+
+// Unpack a zlib stream: a two byte header, deflate blocks, and an Adler32
+// checksum of the uncompressed data
+fn inflate_zlib(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if data.len() < 6 {
+        return Err("Compressed PNG data is too short");
+    }
+    // Compression method 8 (deflate), no preset dictionary, and a header
+    // divisible by 31
+    if data[0] & 0x0F != 8
+        || data[1] & 0x20 != 0
+        || !(data[0] as u16 * 256 + data[1] as u16).is_multiple_of(31)
+    {
+        return Err("Compressed PNG data has an invalid zlib header");
+    }
+
+    let mut stream = BitStream {
+        data: &data[2..],
+        position: 0,
+    };
+    let output = inflate(&mut stream)?;
+
+    stream.align();
+    let end = 2 + stream.position / 8;
+    if data.len() < end + 4 {
+        return Err("Compressed PNG data has no checksum");
+    }
+    let adler32 = u32::from_be_bytes([data[end], data[end + 1], data[end + 2], data[end + 3]]);
+    if calculate_adler32(&output) != adler32 {
+        return Err("Compressed PNG data has a wrong checksum");
+    }
+
+    Ok(output)
+}
+
+// Unpack deflate blocks until the last one
+// Every block starts with a bit telling whether it is the last one, and two
+// bits for its type: stored, compressed with fixed Huffman codes, or with
+// Huffman codes that come with the block
+fn inflate(stream: &mut BitStream) -> Result<Vec<u8>, &'static str> {
+    let mut output: Vec<u8> = vec![];
+
+    loop {
+        let last_block = stream.read(1)? == 1;
+
+        match stream.read(2)? {
+            0 => inflate_stored(stream, &mut output)?,
+            1 => {
+                let (literals, distances) = fixed_codes()?;
+                inflate_block(stream, &mut output, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(stream)?;
+                inflate_block(stream, &mut output, &literals, &distances)?;
+            }
+            _ => return Err("Compressed PNG data has an invalid block type"),
+        }
+
+        if last_block {
+            return Ok(output);
+        }
+    }
+}
+
+// Stored blocks start at the next full byte with their length and its
+// one's complement, both in little-endian order, followed by the bytes
+fn inflate_stored(stream: &mut BitStream, output: &mut Vec<u8>) -> Result<(), &'static str> {
+    stream.align();
+    let length = stream.read(16)?;
+    let complement = stream.read(16)?;
+    if length ^ 0xFFFF != complement {
+        return Err("Compressed PNG data has an invalid stored block");
+    }
+
+    let start = stream.position / 8;
+    match stream.data.get(start..start + length as usize) {
+        Some(bytes) => output.extend_from_slice(bytes),
+        None => return Err("Compressed PNG data ends too early"),
+    }
+    stream.position += length as usize * 8;
+
+    Ok(())
+}
+
+// Fixed codes: 8 bits for literals 0-143 and 280-287, 9 bits for 144-255,
+// 7 bits for 256-279, and 5 bits for all distances
+fn fixed_codes() -> Result<(Huffman, Huffman), &'static str> {
+    let mut lengths = [0; 288];
+    for (symbol, length) in lengths.iter_mut().enumerate() {
+        *length = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+// Dynamic codes are described by the code lengths of all literals and
+// distances, which are compressed themselves: with a Huffman code for the
+// lengths 0-15 and three codes repeating lengths
+fn dynamic_codes(stream: &mut BitStream) -> Result<(Huffman, Huffman), &'static str> {
+    let literal_count = stream.read(5)? as usize + 257;
+    let distance_count = stream.read(5)? as usize + 1;
+    let length_count = stream.read(4)? as usize + 4;
+
+    let mut length_lengths = [0; 19];
+    for symbol in &CODE_LENGTH_ORDER[..length_count] {
+        length_lengths[*symbol] = stream.read(3)? as u8;
+    }
+    let length_code = Huffman::new(&length_lengths)?;
+
+    let mut lengths: Vec<u8> = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match length_code.decode(stream)? {
+            // Repeat the previous length 3-6 times
+            16 => match lengths.last() {
+                Some(previous) => (*previous, 3 + stream.read(2)?),
+                None => return Err("Compressed PNG data repeats a missing code length"),
+            },
+            // Repeat a length of 0 for 3-10 or 11-138 times
+            17 => (0, 3 + stream.read(3)?),
+            18 => (0, 11 + stream.read(7)?),
+            length => (length as u8, 1),
+        };
+
+        for _n in 0..repeat {
+            lengths.push(length);
+        }
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err("Compressed PNG data has too many code lengths");
+    }
+    if lengths[256] == 0 {
+        return Err("Compressed PNG data has no end of block code");
+    }
+
+    let (literal_lengths, distance_lengths) = lengths.split_at(literal_count);
+
+    Ok((
+        Huffman::new(literal_lengths)?,
+        Huffman::new(distance_lengths)?,
+    ))
+}
+
+// Decode literals and back references until the end of the block
+// Symbols below 256 are literal bytes, 256 ends the block, and all others
+// copy a run of earlier output, given by a length and a distance
+fn inflate_block(
+    stream: &mut BitStream,
+    output: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), &'static str> {
+    loop {
+        let symbol = literals.decode(stream)? as usize;
+
+        if symbol < 256 {
+            output.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+
+        let code = symbol - 257;
+        if code >= 29 {
+            return Err("Compressed PNG data has an invalid length code");
+        }
+        let length =
+            LENGTH_BASE[code] as usize + stream.read(LENGTH_EXTRA[code] as usize)? as usize;
+
+        let code = distances.decode(stream)? as usize;
+        if code >= 30 {
+            return Err("Compressed PNG data has an invalid distance code");
+        }
+        let distance =
+            DISTANCE_BASE[code] as usize + stream.read(DISTANCE_EXTRA[code] as usize)? as usize;
+        if distance > output.len() {
+            return Err("Compressed PNG data refers back too far");
+        }
+
+        // Runs may overlap with the bytes they produce
+        let start = output.len() - distance;
+        for n in 0..length {
+            output.push(output[start + n]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate;
+
+    // A chunk with its length and CRC
+    fn chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(chunk_type);
+        chunk.extend_from_slice(data);
+        let crc = calculate_crc(&chunk[4..]);
+        chunk.extend_from_slice(&crc.to_be_bytes());
+
+        chunk
+    }
+
+    // A PNG file of the given properties around a zlib stream, with any
+    // chunks to go between IHDR and IDAT
+    fn png(
+        size: (u32, u32),
+        bit_depth: u8,
+        color_type: u8,
+        interlaced: bool,
+        chunks: &[Vec<u8>],
+        zlib: &[u8],
+    ) -> Vec<u8> {
+        let mut ihdr = size.0.to_be_bytes().to_vec();
+        ihdr.extend_from_slice(&size.1.to_be_bytes());
+        ihdr.extend_from_slice(&[bit_depth, color_type, 0, 0, interlaced as u8]);
+
+        let mut file = vec![0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+        file.extend(chunk(b"IHDR", &ihdr));
+        for extra in chunks {
+            file.extend_from_slice(extra);
+        }
+        file.extend(chunk(b"IDAT", zlib));
+        file.extend(chunk(b"IEND", &[]));
+
+        file
+    }
+
+    // Lines of raw bytes, each behind filter type 0
+    fn unfiltered(lines: &[Vec<u8>]) -> Vec<u8> {
+        lines
+            .iter()
+            .flat_map(|line| std::iter::once(0).chain(line.iter().copied()))
+            .collect()
+    }
+
+    // Pack samples of less than 8 bits into bytes, from the MSB
+    fn pack(samples: &[u8], bit_depth: u8) -> Vec<u8> {
+        let per_byte = 8 / bit_depth as usize;
+        samples
+            .chunks(per_byte)
+            .map(|chunk| {
+                chunk.iter().enumerate().fold(0, |byte, (n, sample)| {
+                    byte | sample << (8 - bit_depth as usize * (n + 1))
+                })
+            })
+            .collect()
+    }
+
+    // The gray value of a test pixel, 8 bits
+    fn gray(x: usize, y: usize) -> u8 {
+        (x * 37 + y * 91 + x * y) as u8
+    }
+
+    // Append bits to a stream: Huffman codes start from their MSB, all
+    // other values from their LSB
+    fn write_bits(bits: &mut Vec<u8>, value: u32, length: usize, huffman: bool) {
+        for n in 0..length {
+            let shift = if huffman { length - 1 - n } else { n };
+            bits.push((value >> shift & 1) as u8);
+        }
+    }
+
+    // Deflate with fixed Huffman codes, as a single block
+    // Runs of a repeated byte are written as matches at distance 1
+    fn deflate_fixed(data: &[u8]) -> Vec<u8> {
+        let mut bits: Vec<u8> = vec![];
+
+        // Last block, fixed codes
+        write_bits(&mut bits, 1, 1, false);
+        write_bits(&mut bits, 1, 2, false);
+
+        let mut n = 0;
+        while n < data.len() {
+            let byte = data[n];
+            if byte < 144 {
+                write_bits(&mut bits, 0x30 + byte as u32, 8, true);
+            } else {
+                write_bits(&mut bits, 0x190 + byte as u32 - 144, 9, true);
+            }
+            n += 1;
+
+            // Lengths 3 to 10 are codes 257 to 264, distance 1 is code 0
+            let run = data[n..]
+                .iter()
+                .take(10)
+                .take_while(|next| **next == byte)
+                .count();
+            if run >= 3 {
+                write_bits(&mut bits, run as u32 - 2, 7, true);
+                write_bits(&mut bits, 0, 5, true);
+                n += run;
+            }
+        }
+
+        // End of block
+        write_bits(&mut bits, 0, 7, true);
+
+        let mut zlib = vec![0x78, 0x01];
+        zlib.extend(bits.chunks(8).map(|byte| {
+            byte.iter()
+                .enumerate()
+                .fold(0, |packed, (n, bit)| packed | bit << n)
+        }));
+        zlib.extend_from_slice(&calculate_adler32(&data.to_vec()).to_be_bytes());
+
+        zlib
+    }
+
+    #[test]
+    fn reverses_all_filter_types() {
+        let (width, height) = (7, 10);
+        let lines: Vec<Vec<u8>> = (0..height)
+            .map(|y| (0..width).map(|x| gray(x, y)).collect())
+            .collect();
+
+        // Filter every line with type y % 5
+        let mut data: Vec<u8> = vec![];
+        for y in 0..height {
+            let filter = (y % 5) as u8;
+            data.push(filter);
+            for x in 0..width {
+                let left = if x > 0 { lines[y][x - 1] } else { 0 };
+                let above = if y > 0 { lines[y - 1][x] } else { 0 };
+                let upper_left = if x > 0 && y > 0 {
+                    lines[y - 1][x - 1]
+                } else {
+                    0
+                };
+                let prediction = match filter {
+                    0 => 0,
+                    1 => left,
+                    2 => above,
+                    3 => ((left as u16 + above as u16) / 2) as u8,
+                    _ => paeth(left, above, upper_left),
+                };
+                data.push(lines[y][x].wrapping_sub(prediction));
+            }
+        }
+
+        let file = png((7, 10), 8, 0, false, &[], &deflate(data));
+        let image = decode_png(&file).unwrap();
+        assert_eq!(image.pixels, lines.concat());
+
+        // Filter types above 4 don't exist
+        let file = png((1, 1), 8, 0, false, &[], &deflate(vec![5, 0]));
+        assert!(decode_png(&file).is_err());
+    }
+
+    #[test]
+    fn inflates_stored_fixed_and_dynamic_blocks() {
+        // Two stored blocks, one of 65535 bytes and one for the rest
+        let (width, height) = (300, 300);
+        let lines: Vec<Vec<u8>> = (0..height)
+            .map(|y| (0..width).map(|x| gray(x, y)).collect())
+            .collect();
+        let file = png((300, 300), 8, 0, false, &[], &deflate(unfiltered(&lines)));
+        assert_eq!(decode_png(&file).unwrap().pixels, lines.concat());
+
+        // Fixed codes, with matches for the runs of the lower half
+        let lines: Vec<Vec<u8>> = (0..16)
+            .map(|y| {
+                (0..20)
+                    .map(|x| if y < 8 { gray(x, y) } else { x as u8 / 5 * 60 })
+                    .collect()
+            })
+            .collect();
+        let file = png(
+            (20, 16),
+            8,
+            0,
+            false,
+            &[],
+            &deflate_fixed(&unfiltered(&lines)),
+        );
+        assert_eq!(decode_png(&file).unwrap().pixels, lines.concat());
+
+        // Dynamic codes, as written by zlib at level 9
+        let lines: Vec<Vec<u8>> = (0..24)
+            .map(|y| (0..24).map(|x| ((x * x + y * 3) % 7 * 36) as u8).collect())
+            .collect();
+        let zlib = [
+            0x78, 0xDA, 0xED, 0xCE, 0x31, 0x11, 0xC0, 0x40, 0x10, 0x02, 0x40, 0xC4, 0x20, 0xE6,
+            0xC4, 0x20, 0x06, 0x31, 0x88, 0x41, 0xCE, 0x0B, 0x40, 0x41, 0x66, 0x52, 0x6D, 0xBB,
+            0x00, 0x7D, 0x67, 0x0E, 0x90, 0x91, 0xC0, 0x03, 0x0A, 0x91, 0x5A, 0x70, 0xAA, 0x5D,
+            0x0D, 0x48, 0x0F, 0xB8, 0x2E, 0xBC, 0x48, 0xB9, 0x01, 0x0E, 0x5B, 0x66, 0xC0, 0xDF,
+            0xFD, 0x46, 0xF7, 0x01, 0xE2, 0xB0, 0xEC, 0x41,
+        ];
+        assert_eq!((zlib[2] >> 1) & 3, 2);
+        let file = png((24, 24), 8, 0, false, &[], &zlib);
+        assert_eq!(decode_png(&file).unwrap().pixels, lines.concat());
+
+        // A damaged checksum
+        let mut zlib = zlib.to_vec();
+        *zlib.last_mut().unwrap() ^= 1;
+        assert!(decode_png(&png((24, 24), 8, 0, false, &[], &zlib)).is_err());
+    }
+
+    #[test]
+    fn scales_sub_byte_gray_and_palette_samples() {
+        // Widths that leave bits unused at the end of every line
+        for bit_depth in [1, 2, 4] {
+            let max = (1 << bit_depth) - 1;
+            let samples: Vec<Vec<u8>> = (0..3)
+                .map(|y| (0..7).map(|x| (x + y) as u8 & max).collect())
+                .collect();
+            let lines: Vec<Vec<u8>> = samples.iter().map(|line| pack(line, bit_depth)).collect();
+
+            let file = png(
+                (7, 3),
+                bit_depth,
+                0,
+                false,
+                &[],
+                &deflate(unfiltered(&lines)),
+            );
+            let expected: Vec<u8> = samples
+                .concat()
+                .iter()
+                .map(|sample| (*sample as u32 * 255 / max as u32) as u8)
+                .collect();
+            assert_eq!(decode_png(&file).unwrap().pixels, expected);
+        }
+
+        // 2-bit palette indices: black, white, pure red and pure blue
+        let palette = chunk(b"PLTE", &[0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 0, 255]);
+        let lines = vec![pack(&[0, 1, 2, 3, 1], 2)];
+        let file = png(
+            (5, 1),
+            2,
+            3,
+            false,
+            &[palette],
+            &deflate(unfiltered(&lines)),
+        );
+        assert_eq!(decode_png(&file).unwrap().pixels, vec![0, 255, 76, 29, 255]);
+    }
+
+    #[test]
+    fn reduces_16_bit_samples() {
+        // Gray
+        let lines = vec![vec![0x12, 0x34, 0xFF, 0xFF, 0x00, 0xFF]];
+        let file = png((3, 1), 16, 0, false, &[], &deflate(unfiltered(&lines)));
+        assert_eq!(decode_png(&file).unwrap().pixels, vec![0x12, 0xFF, 0x00]);
+
+        // RGB, green at full and half intensity
+        let lines = vec![vec![0, 0, 0xFF, 0xFF, 0, 0, 0, 0, 0x80, 0x00, 0, 0]];
+        let file = png((2, 1), 16, 2, false, &[], &deflate(unfiltered(&lines)));
+        assert_eq!(decode_png(&file).unwrap().pixels, vec![149, 75]);
+
+        // Gray and alpha, half transparent black on white
+        let lines = vec![vec![0, 0, 0x80, 0x00]];
+        let file = png((1, 1), 16, 4, false, &[], &deflate(unfiltered(&lines)));
+        assert_eq!(decode_png(&file).unwrap().pixels, vec![127]);
+    }
+
+    #[test]
+    fn puts_transparent_pixels_on_white() {
+        // A transparent gray value
+        let transparency = chunk(b"tRNS", &[0, 20]);
+        let lines = vec![vec![10, 20, 30]];
+        let file = png(
+            (3, 1),
+            8,
+            0,
+            false,
+            &[transparency],
+            &deflate(unfiltered(&lines)),
+        );
+        assert_eq!(decode_png(&file).unwrap().pixels, vec![10, 255, 30]);
+
+        // A transparent 16-bit color
+        let transparency = chunk(b"tRNS", &[0, 0, 0, 0, 0, 0]);
+        let lines = vec![vec![0; 12]];
+        let mut opaque = lines.clone();
+        opaque[0][11] = 1;
+        let file = png(
+            (2, 1),
+            16,
+            2,
+            false,
+            &[transparency],
+            &deflate(unfiltered(&opaque)),
+        );
+        assert_eq!(decode_png(&file).unwrap().pixels, vec![255, 0]);
+
+        // Palette entries with alpha, the last one opaque without a value
+        let palette = chunk(b"PLTE", &[0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let transparency = chunk(b"tRNS", &[0, 128]);
+        let lines = vec![vec![0, 1, 2]];
+        let file = png(
+            (3, 1),
+            8,
+            3,
+            false,
+            &[palette, transparency],
+            &deflate(unfiltered(&lines)),
+        );
+        assert_eq!(decode_png(&file).unwrap().pixels, vec![255, 127, 0]);
+    }
+
+    #[test]
+    fn places_adam7_passes() {
+        // Sizes that leave some passes empty or short
+        for (width, height) in [(1, 1), (3, 2), (10, 9), (17, 13)] {
+            let mut data: Vec<u8> = vec![];
+            for (x_start, y_start, x_step, y_step) in ADAM7 {
+                for y in (y_start..height).step_by(y_step) {
+                    let line: Vec<u8> = (x_start..width)
+                        .step_by(x_step)
+                        .map(|x| gray(x, y))
+                        .collect();
+                    if !line.is_empty() {
+                        data.push(0);
+                        data.extend(line);
+                    }
+                }
+            }
+
+            let file = png(
+                (width as u32, height as u32),
+                8,
+                0,
+                true,
+                &[],
+                &deflate(data),
+            );
+            let expected: Vec<u8> = (0..height)
+                .flat_map(|y| (0..width).map(move |x| gray(x, y)))
+                .collect();
+            assert_eq!(decode_png(&file).unwrap().pixels, expected);
+        }
+    }
+
+    #[test]
+    fn rejects_sizes_the_data_doesnt_back() {
+        let zlib = deflate(unfiltered(&[vec![0x80]]));
+
+        // Beyond the size limit, and beyond what fits into memory
+        for size in [(16385, 1), (1, 16385), (u32::MAX, u32::MAX)] {
+            let file = png(size, 8, 0, false, &[], &zlib);
+            assert_eq!(decode_png(&file), Err("PNG image is too large"));
+        }
+
+        // Within the limit, but with data for a single pixel only
+        for interlaced in [false, true] {
+            let file = png((16384, 16384), 16, 6, interlaced, &[], &zlib);
+            assert_eq!(decode_png(&file), Err("PNG image data is too short"));
+        }
+
+        let file = png((1, 1), 8, 0, false, &[], &zlib);
+        assert_eq!(decode_png(&file).unwrap().pixels, vec![0x80]);
+    }
+}
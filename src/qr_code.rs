@@ -1,14 +1,149 @@
-// Represents a QR code, version 2: 25 x 25 modules
+use crate::shift_jis::{from_shift_jis, to_shift_jis};
+
+// Represents a QR code of any version from 1 (21 x 21 modules) to 40
+// (177 x 177 modules). Each version adds 4 modules per side.
 // Matrix::mask defines areas where data cannot be written
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct Matrix {
-    data: [[u8; 25]; 25],
-    mask: [[bool; 25]; 25],
+    version: usize,
+    ecc_level: EccLevel,
+    size: usize,
+    data: Vec<Vec<u8>>,
+    mask: Vec<Vec<bool>>,
 }
 
 // Used as Point(row, column) in Matrix
 struct Point(usize, usize);
 
+// Center coordinates of the alignment patterns for each version. Patterns
+// are placed at every combination of two values (as row and column),
+// except where they would overlap a finder pattern.
+const ALIGNMENT_POSITIONS: [&[usize]; 41] = [
+    &[],
+    &[],
+    &[6, 18],
+    &[6, 22],
+    &[6, 26],
+    &[6, 30],
+    &[6, 34],
+    &[6, 22, 38],
+    &[6, 24, 42],
+    &[6, 26, 46],
+    &[6, 28, 50],
+    &[6, 30, 54],
+    &[6, 32, 58],
+    &[6, 34, 62],
+    &[6, 26, 46, 66],
+    &[6, 26, 48, 70],
+    &[6, 26, 50, 74],
+    &[6, 30, 54, 78],
+    &[6, 30, 56, 82],
+    &[6, 30, 58, 86],
+    &[6, 34, 62, 90],
+    &[6, 28, 50, 72, 94],
+    &[6, 26, 50, 74, 98],
+    &[6, 30, 54, 78, 102],
+    &[6, 28, 54, 80, 106],
+    &[6, 32, 58, 84, 110],
+    &[6, 30, 58, 86, 114],
+    &[6, 34, 62, 90, 118],
+    &[6, 26, 50, 74, 98, 122],
+    &[6, 30, 54, 78, 102, 126],
+    &[6, 26, 52, 78, 104, 130],
+    &[6, 30, 56, 82, 108, 134],
+    &[6, 34, 60, 86, 112, 138],
+    &[6, 30, 58, 86, 114, 142],
+    &[6, 34, 62, 90, 118, 146],
+    &[6, 30, 54, 78, 102, 126, 150],
+    &[6, 24, 50, 76, 102, 128, 154],
+    &[6, 28, 54, 80, 106, 132, 158],
+    &[6, 32, 58, 84, 110, 136, 162],
+    &[6, 26, 54, 82, 110, 138, 166],
+    &[6, 30, 58, 86, 114, 142, 170],
+];
+
+// Error correction level: the share of codewords that can be restored
+// L: ~7%, M: ~15%, Q: ~25%, H: ~30%
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EccLevel {
+    L,
+    M,
+    Q,
+    H,
+}
+
+impl EccLevel {
+    // Two bits identifying the level in the format string
+    fn format_bits(self) -> u16 {
+        match self {
+            EccLevel::L => 0b01,
+            EccLevel::M => 0b00,
+            EccLevel::Q => 0b11,
+            EccLevel::H => 0b10,
+        }
+    }
+
+    // Level identified by the two bits of a format string
+    pub(crate) fn from_format_bits(bits: u16) -> EccLevel {
+        match bits & 0b11 {
+            0b01 => EccLevel::L,
+            0b00 => EccLevel::M,
+            0b11 => EccLevel::Q,
+            _ => EccLevel::H,
+        }
+    }
+}
+
+// Error correction codewords per block for each ECC level and version
+// (index 0 is unused)
+pub(crate) const ECC_CODEWORDS_PER_BLOCK: [[usize; 41]; 4] = [
+    // L
+    [
+        0, 7, 10, 15, 20, 26, 18, 20, 24, 30, 18, 20, 24, 26, 30, 22, 24, 28, 30, 28, 28, 28, 28,
+        30, 30, 26, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+    ],
+    // M
+    [
+        0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28,
+        28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    ],
+    // Q
+    [
+        0, 13, 22, 18, 26, 18, 24, 18, 22, 20, 24, 28, 26, 24, 20, 30, 24, 28, 28, 26, 30, 28, 30,
+        30, 30, 30, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+    ],
+    // H
+    [
+        0, 17, 28, 22, 16, 22, 28, 26, 26, 24, 28, 24, 28, 22, 24, 24, 30, 28, 28, 26, 28, 30, 24,
+        30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+    ],
+];
+
+// Number of error correction blocks for each ECC level and version
+// (index 0 is unused)
+pub(crate) const ECC_BLOCKS: [[usize; 41]; 4] = [
+    // L
+    [
+        0, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 4, 4, 6, 6, 6, 6, 7, 8, 8, 9, 9, 10, 12, 12, 12, 13,
+        14, 15, 16, 17, 18, 19, 19, 20, 21, 22, 24, 25,
+    ],
+    // M
+    [
+        0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21,
+        23, 25, 26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49,
+    ],
+    // Q
+    [
+        0, 1, 1, 2, 2, 4, 4, 6, 6, 8, 8, 8, 10, 12, 16, 12, 17, 16, 18, 21, 20, 23, 23, 25, 27, 29,
+        34, 34, 35, 38, 40, 43, 45, 48, 51, 53, 56, 59, 62, 65, 68,
+    ],
+    // H
+    [
+        0, 1, 1, 2, 4, 4, 4, 5, 6, 8, 8, 11, 11, 16, 16, 18, 16, 19, 21, 25, 25, 25, 34, 30, 32,
+        35, 37, 40, 42, 45, 48, 51, 54, 57, 60, 63, 66, 70, 74, 77, 81,
+    ],
+];
+
 // Encodes a binary stream in alphanumeric representation by treating the
 // input as a single large number and repeatedly dividing it mod 45, saving
 // the remainder as the new representation.
@@ -17,7 +152,7 @@ pub fn encode_bits(mut bits: Vec<u8>, base: u8) -> Vec<u8> {
     let input_length = bits.len();
 
     // divmod until the input is empty
-    while bits.is_empty() == false {
+    while !bits.is_empty() {
         let divmod = divmod(bits, base);
         encoded_bits.insert(0, divmod.1); // populate encoded vector from LSB
         bits = divmod.0;
@@ -25,11 +160,10 @@ pub fn encode_bits(mut bits: Vec<u8>, base: u8) -> Vec<u8> {
 
     // If the bitstream starts with bytes of zero, the encoded vector may be
     // too short.
-    // Expected length: Input_length * 8 bits/byte * 2 alphanumeric
-    // characters / 11 input bits
+    // Expected length: as many digits as the largest number of
+    // input_length bytes, so that the length alone tells input_length
     // Pad the result if necessary
-    let temp = input_length * 8 * 2;
-    let expected_length = temp.div_ceil(11);
+    let expected_length = encoded_length(input_length, base);
 
     while encoded_bits.len() < expected_length {
         encoded_bits.insert(0, 0);
@@ -38,6 +172,216 @@ pub fn encode_bits(mut bits: Vec<u8>, base: u8) -> Vec<u8> {
     encoded_bits
 }
 
+// Number of digits encode_bits writes for an input of a given length: the
+// exponent of the smallest power of the base with more bytes than that
+// Every added byte takes at least one more digit, so no two lengths share
+// a number of digits
+fn encoded_length(input_length: usize, base: u8) -> usize {
+    let mut power = vec![1];
+    let mut digits = 0;
+    while power.len() <= input_length {
+        power = multiply_add(power, base, 0);
+        digits += 1;
+    }
+
+    digits
+}
+
+// Input length encode_bits writes a given number of digits for, if any
+fn decoded_length(encoded_length: usize, base: u8) -> Option<usize> {
+    if encoded_length == 0 {
+        return Some(0);
+    }
+
+    let mut power = vec![1];
+    for _n in 1..encoded_length {
+        power = multiply_add(power, base, 0);
+    }
+    let length = power.len();
+
+    // Powers of a base below 256 grow by at most one byte at a time
+    (multiply_add(power, base, 0).len() > length).then_some(length)
+}
+
+// Decode a binary stream from the representation produced by encode_bits,
+// by multiplying the digits back into a single large number
+// The number of digits tells the length of the input, which restores
+// bytes of zero at its start
+// Versions before this one padded to input_length * 16 / 11 digits,
+// rounded up, and only numbers shorter than that. Digit counts that don't
+// fit the current lengths are read that way, so that printed codes still
+// come back
+pub fn decode_bits(encoded: &[u8], base: u8) -> Result<Vec<u8>, &'static str> {
+    if encoded.iter().any(|digit| *digit >= base) {
+        return Err("Digit out of range for the base");
+    }
+
+    let mut bits: Vec<u8> = vec![];
+    for digit in encoded {
+        bits = multiply_add(bits, base, *digit);
+    }
+
+    let length = match decoded_length(encoded.len(), base) {
+        Some(length) => length,
+        None => legacy_length(encoded, bits.len())?,
+    };
+
+    if bits.len() > length {
+        return Err("Number too large for the number of digits");
+    }
+    while bits.len() < length {
+        bits.insert(0, 0);
+    }
+
+    Ok(bits)
+}
+
+// Input length of digits padded the way earlier versions did
+// Only padded numbers start with a zero digit, others take up as many
+// bytes as the input did
+fn legacy_length(encoded: &[u8], number_length: usize) -> Result<usize, &'static str> {
+    let padded_length = |length: usize| (length * 8 * 2).div_ceil(11);
+
+    match encoded.first() {
+        Some(0) if padded_length(encoded.len() * 11 / (8 * 2)) == encoded.len() => {
+            Ok(encoded.len() * 11 / (8 * 2))
+        }
+        Some(0) => Err("Number of digits doesn't match any input length"),
+        _ if padded_length(number_length) > encoded.len() => {
+            Err("Number of digits doesn't match any input length")
+        }
+        _ => Ok(number_length),
+    }
+}
+
+// How a binary password is turned into alphanumeric characters
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Base45 {
+    // The whole password as a single large number in base 45 (encode_bits)
+    #[default]
+    Number,
+    // Two bytes at a time into three characters, following RFC 9285, as
+    // understood by other Base45 tools
+    Rfc9285,
+}
+
+impl Base45 {
+    // Character values from 0 to 44 for a binary stream
+    pub fn encode(&self, bits: &[u8]) -> Vec<u8> {
+        match self {
+            Base45::Number => encode_bits(bits.to_vec(), 45),
+            Base45::Rfc9285 => encode_rfc9285(bits),
+        }
+    }
+
+    // The binary stream back from its character values
+    pub fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, &'static str> {
+        match self {
+            Base45::Number => decode_bits(encoded, 45),
+            Base45::Rfc9285 => decode_rfc9285(encoded),
+        }
+    }
+}
+
+// Encode a binary stream following RFC 9285: every two bytes form a number
+// n = c + d * 45 + e * 45^2, written as the three values c, d, e
+// A single byte left at the end is written as two values c, d
+// Runs in linear time, unlike encode_bits
+pub fn encode_rfc9285(bits: &[u8]) -> Vec<u8> {
+    let mut encoded: Vec<u8> = Vec::with_capacity(bits.len().div_ceil(2) * 3);
+
+    for pair in bits.chunks(2) {
+        let mut number = pair
+            .iter()
+            .fold(0, |number, byte| number * 256 + *byte as u32);
+        let digits = if pair.len() == 2 { 3 } else { 2 };
+        for _n in 0..digits {
+            encoded.push((number % 45) as u8);
+            number /= 45;
+        }
+    }
+
+    encoded
+}
+
+// Decode a binary stream from RFC 9285 values, three for every two bytes
+// and two for a single byte at the end
+// Refuses values that don't fit into the bytes they stand for
+pub fn decode_rfc9285(encoded: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if encoded.iter().any(|digit| *digit >= 45) {
+        return Err("Digit out of range for the base");
+    }
+    if encoded.len() % 3 == 1 {
+        return Err("Number of digits doesn't match any input length");
+    }
+
+    let mut bits: Vec<u8> = Vec::with_capacity(encoded.len() / 3 * 2 + 1);
+
+    for triple in encoded.chunks(3) {
+        let number = triple
+            .iter()
+            .rev()
+            .fold(0, |number, digit| number * 45 + *digit as u32);
+        if triple.len() == 3 {
+            if number > 0xFFFF {
+                return Err("Number too large for the number of digits");
+            }
+            bits.push((number >> 8) as u8);
+            bits.push((number & 255) as u8);
+        } else {
+            if number > 0xFF {
+                return Err("Number too large for the number of digits");
+            }
+            bits.push(number as u8);
+        }
+    }
+
+    Ok(bits)
+}
+
+// Turn the text a scanner shows for a QR code in alphanumeric mode back
+// into the original bytes, given how they were converted
+// Line breaks at the end, as left by copying the text, are ignored
+pub fn recover_bits(text: &[u8], base45: Base45) -> Result<Vec<u8>, &'static str> {
+    let mut text = text;
+    while let [rest @ .., b'\n' | b'\r'] = text {
+        text = rest;
+    }
+
+    let digits = text
+        .iter()
+        .map(|character| {
+            ALPHANUMERIC_CHARSET
+                .iter()
+                .position(|known| known == character)
+                .map(|digit| digit as u8)
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or("Text holds characters outside the alphanumeric character set")?;
+
+    base45.decode(&digits)
+}
+
+// Multiply a number of arbitrary length by a factor and add a summand,
+// the reverse of divmod
+fn multiply_add(number: Vec<u8>, factor: u8, summand: u8) -> Vec<u8> {
+    let mut carry = summand as u16;
+    let mut result: Vec<u8> = Vec::with_capacity(number.len() + 1);
+
+    // Multiply byte by byte, starting from the least significant one
+    for byte in number.iter().rev() {
+        let product = *byte as u16 * factor as u16 + carry;
+        result.push((product & 255) as u8);
+        carry = product >> 8;
+    }
+    if carry > 0 {
+        result.push(carry as u8);
+    }
+
+    result.reverse();
+    result
+}
+
 // Divide a number of arbitraty length modulo any base, return both quotient
 // and remainder
 fn divmod(number: Vec<u8>, base: u8) -> (Vec<u8>, u8) {
@@ -47,10 +391,10 @@ fn divmod(number: Vec<u8>, base: u8) -> (Vec<u8>, u8) {
 
     // Divide byte by byte
     for byte in number {
-        temp = temp << 8; // left-shift remainder
+        temp <<= 8; // left-shift remainder
         temp += byte as u16; // add next byte
         quotient = temp / base as u16; // calculate quotient
-        temp = temp % base as u16; // calculate remainder
+        temp %= base as u16; // calculate remainder
         if quotient == 0 && result.is_empty() {
             continue;
         } // remove leading empty bytes but keep ones in the middle
@@ -62,264 +406,1417 @@ fn divmod(number: Vec<u8>, base: u8) -> (Vec<u8>, u8) {
     (result, temp as u8)
 }
 
-// Take message data and add everything needed to build a QR code
-pub fn encapsulate_data(mut encoded_bits: Vec<u8>) -> [u8; 34 * 8] {
-    // Version 2 (25x25), error correction level L:
-    // 272 bits (34 bytes)
-    let mut data = [0; 34 * 8];
+// Total number of codewords (data and error correction) a version can hold:
+// all modules minus function patterns, format and version information,
+// divided into bytes. Leftover modules are remainder bits.
+pub fn total_codewords(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+
+    if version >= 2 {
+        let alignment_count = version / 7 + 2;
+        modules -= (25 * alignment_count - 10) * alignment_count - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+
+    modules / 8
+}
+
+// Number of codewords left for the message after error correction
+pub fn data_codewords(version: usize, ecc_level: EccLevel) -> usize {
+    let level = ecc_level as usize;
+    total_codewords(version) - ECC_CODEWORDS_PER_BLOCK[level][version] * ECC_BLOCKS[level][version]
+}
 
-    // Add mode indicator
-    // 0010 = alphanumeric mode
-    data[2] = 1;
+// Encoding modes a segment of message data can be written in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Numeric,
+    Alphanumeric,
+    Byte,
+    Kanji,
+    // Extended Channel Interpretation: declares the character set of the
+    // segments that follow
+    Eci,
+    // Links a symbol to the others a message was split across
+    StructuredAppend,
+    // FNC1 in first position: the data follows the GS1 Application
+    // Identifier standard
+    Fnc1First,
+    // FNC1 in second position: the data follows an industry standard
+    // named by an application indicator
+    Fnc1Second,
+}
 
-    // Add length indicator
-    // Count of alphanumeric characters, written into 9 bits
-    let mut character_count = encoded_bits.len() as u8;
-    let mut insert_bit: u8;
-    let no_of_bits = 9;
+impl Mode {
+    // 4-bit mode indicator preceding the character count
+    fn indicator(self) -> u32 {
+        match self {
+            Mode::Numeric => 0b0001,
+            Mode::Alphanumeric => 0b0010,
+            Mode::Byte => 0b0100,
+            Mode::Kanji => 0b1000,
+            Mode::Eci => 0b0111,
+            Mode::StructuredAppend => 0b0011,
+            Mode::Fnc1First => 0b0101,
+            Mode::Fnc1Second => 0b1001,
+        }
+    }
 
-    for n in 0..no_of_bits {
-        insert_bit = character_count & 1;
-        character_count = character_count >> 1;
-        data[3 + no_of_bits - n] = insert_bit;
+    // Mode of a 4-bit mode indicator, if it names one
+    pub(crate) fn from_indicator(indicator: u32) -> Option<Mode> {
+        [
+            Mode::Numeric,
+            Mode::Alphanumeric,
+            Mode::Byte,
+            Mode::Kanji,
+            Mode::Eci,
+            Mode::StructuredAppend,
+            Mode::Fnc1First,
+            Mode::Fnc1Second,
+        ]
+        .into_iter()
+        .find(|mode| mode.indicator() == indicator)
     }
 
-    // Add message characters
-    let mut index = 4 + no_of_bits;
-    let mut temp: u16;
-    let mut temp_vec: Vec<u8>;
+    // Width of the character count indicator, depending on the version
+    pub(crate) fn character_count_bits(self, version: usize) -> usize {
+        let version_range = match version {
+            1..=9 => 0,
+            10..=26 => 1,
+            _ => 2,
+        };
 
-    while encoded_bits.len() > 1 {
-        // Collect characters in pairs
-        temp_vec = encoded_bits.drain(0..2).collect();
-        // Convert them to binary
-        temp = temp_vec[0] as u16 * 45 + temp_vec[1] as u16;
+        match self {
+            Mode::Numeric => [10, 12, 14][version_range],
+            Mode::Alphanumeric => [9, 11, 13][version_range],
+            Mode::Byte => [8, 16, 16][version_range],
+            Mode::Kanji => [8, 10, 12][version_range],
+            // No character count, the header data follows right away
+            Mode::Eci | Mode::StructuredAppend | Mode::Fnc1First | Mode::Fnc1Second => 0,
+        }
+    }
+
+    // Number of bits needed for the characters themselves
+    // Numeric: 10 bits per group of three digits, 7 or 4 bits for two or
+    // one digits left over
+    // Alphanumeric: 11 bits per pair of characters and 6 bits for a
+    // single one left over
+    // Byte: 8 bits per byte
+    // Kanji: 13 bits per double-byte character
+    fn message_bits(self, character_count: usize) -> usize {
+        match self {
+            Mode::Numeric => character_count / 3 * 10 + [0, 4, 7][character_count % 3],
+            Mode::Alphanumeric => character_count / 2 * 11 + character_count % 2 * 6,
+            Mode::Byte => character_count * 8,
+            Mode::Kanji => character_count * 13,
+            Mode::Eci | Mode::StructuredAppend | Mode::Fnc1First | Mode::Fnc1Second => 0,
+        }
+    }
+}
+
+// Characters available in alphanumeric mode, in the order of their values
+pub(crate) const ALPHANUMERIC_CHARSET: &[u8; 45] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+// Value of a character in alphanumeric mode, if it has one
+fn alphanumeric_value(byte: u8) -> Option<u8> {
+    ALPHANUMERIC_CHARSET
+        .iter()
+        .position(|character| *character == byte)
+        .map(|position| position as u8)
+}
+
+// A run of message data written in a single mode
+// Holds the encoded characters only: mode and length indicator are added
+// when the version is known, since the width of the character count
+// depends on it
+#[derive(Clone)]
+pub struct Segment {
+    pub(crate) mode: Mode,
+    pub(crate) character_count: usize,
+    pub(crate) bits: Vec<u8>,
+}
 
-        // Add them in 11-bit groups
-        for _n in 0..11 {
-            if temp & 1024 == 1024 {
-                data[index] = 1
+impl Segment {
+    // ASCII digits
+    // Read groups of up to three digits as one decimal number and add them
+    // in 10-bit groups; a last group of two or one digits takes 7 or 4 bits
+    pub fn numeric(digits: &[u8]) -> Segment {
+        let mut bits: Vec<u8> = Vec::with_capacity(Mode::Numeric.message_bits(digits.len()));
+        for group in digits.chunks(3) {
+            let value = group
+                .iter()
+                .fold(0, |value, digit| value * 10 + (digit - b'0') as u32);
+            append_bits(&mut bits, value, [0, 4, 7, 10][group.len()]);
+        }
+
+        Segment {
+            mode: Mode::Numeric,
+            character_count: digits.len(),
+            bits,
+        }
+    }
+
+    // Character values from 0 to 44, as produced by encode_bits
+    // Characters are taken in pairs and converted to binary in 11-bit
+    // groups; a single character left over is added as a 6-bit group
+    pub fn alphanumeric(values: &[u8]) -> Segment {
+        let mut bits: Vec<u8> = Vec::with_capacity(Mode::Alphanumeric.message_bits(values.len()));
+        for pair in values.chunks(2) {
+            if pair.len() == 2 {
+                append_bits(&mut bits, pair[0] as u32 * 45 + pair[1] as u32, 11);
             } else {
-                data[index] = 0
+                append_bits(&mut bits, pair[0] as u32, 6);
             }
-            temp = temp << 1;
-            index += 1;
+        }
+
+        Segment {
+            mode: Mode::Alphanumeric,
+            character_count: values.len(),
+            bits,
+        }
+    }
+
+    // Raw bytes, 8 bits each
+    pub fn bytes(bytes: &[u8]) -> Segment {
+        let mut bits: Vec<u8> = Vec::with_capacity(bytes.len() * 8);
+        for byte in bytes {
+            append_bits(&mut bits, *byte as u32, 8);
+        }
+
+        Segment {
+            mode: Mode::Byte,
+            character_count: bytes.len(),
+            bits,
         }
     }
 
-    // If a single character's left over, add it as a 6-bit group
-    if !encoded_bits.is_empty() {
-        for _n in 0..6 {
-            if encoded_bits[0] & 32 == 32 {
-                data[index] = 1
+    // Shift JIS double-byte characters, 13 bits each
+    // Subtract the offset of the range a code lies in, then multiply the
+    // upper byte by 0xC0 and add the lower byte
+    pub fn kanji(codes: &[u16]) -> Segment {
+        let mut bits: Vec<u8> = Vec::with_capacity(codes.len() * 13);
+        for code in codes {
+            let code = if *code <= 0x9FFC {
+                code - 0x8140
             } else {
-                data[index] = 0
+                code - 0xC140
+            };
+            append_bits(&mut bits, ((code >> 8) * 0xC0 + (code & 0xFF)) as u32, 13);
+        }
+
+        Segment {
+            mode: Mode::Kanji,
+            character_count: codes.len(),
+            bits,
+        }
+    }
+
+    // ECI designator declaring the character set of the following segments,
+    // e.g. 26 for UTF-8 or 3 for ISO-8859-1
+    // Written in 1, 2 or 3 bytes, depending on its size; the leading bits
+    // give the number of bytes: 0xxxxxxx, 10xxxxxx ..., 110xxxxx ...
+    // Designators go up to 999999, larger ones return None
+    pub fn eci(designator: u32) -> Option<Segment> {
+        let mut bits: Vec<u8> = Vec::with_capacity(24);
+
+        match designator {
+            0..=127 => append_bits(&mut bits, designator, 8),
+            128..=16383 => append_bits(&mut bits, 0b10 << 14 | designator, 16),
+            16384..=999999 => append_bits(&mut bits, 0b110 << 21 | designator, 24),
+            _ => return None,
+        }
+
+        Some(Segment {
+            mode: Mode::Eci,
+            character_count: 0,
+            bits,
+        })
+    }
+
+    // Structured append header: position of this symbol (from 0), total
+    // number of symbols, and the parity byte of the whole message (all of
+    // its bytes XORed together), so a scanner can reassemble the parts
+    // 4 bits each for position and total minus one, 8 bits for parity
+    pub fn structured_append(index: usize, total: usize, parity: u8) -> Segment {
+        let mut bits: Vec<u8> = Vec::with_capacity(16);
+        append_bits(&mut bits, index as u32, 4);
+        append_bits(&mut bits, total as u32 - 1, 4);
+        append_bits(&mut bits, parity as u32, 8);
+
+        Segment {
+            mode: Mode::StructuredAppend,
+            character_count: 0,
+            bits,
+        }
+    }
+
+    // A segment of a given mode and number of characters, with all bits
+    // set to 0, to size a message before its content is known
+    pub fn blank(mode: Mode, character_count: usize) -> Segment {
+        Segment {
+            mode,
+            character_count,
+            bits: vec![0; mode.message_bits(character_count)],
+        }
+    }
+
+    // FNC1 in first position marks the message as a GS1 element string
+    // The mode indicator stands alone, without any header data
+    pub fn fnc1_first() -> Segment {
+        Segment {
+            mode: Mode::Fnc1First,
+            character_count: 0,
+            bits: vec![],
+        }
+    }
+
+    // FNC1 in second position, followed by an 8-bit application indicator:
+    // a two-digit number (00 to 99) as is, or a letter as its ASCII value
+    // plus 100
+    pub fn fnc1_second(application_indicator: u8) -> Segment {
+        let mut bits: Vec<u8> = Vec::with_capacity(8);
+        append_bits(&mut bits, application_indicator as u32, 8);
+
+        Segment {
+            mode: Mode::Fnc1Second,
+            character_count: 0,
+            bits,
+        }
+    }
+
+    // Mode the segment is written in
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    // Number of characters in the segment
+    pub fn character_count(&self) -> usize {
+        self.character_count
+    }
+
+    // Number of bits the segment takes up in a given version, including
+    // mode and length indicator
+    // None if there are more characters than the length indicator can count
+    fn total_bits(&self, version: usize) -> Option<usize> {
+        let count_bits = self.mode.character_count_bits(version);
+        if self.character_count >= 1 << count_bits {
+            return None;
+        }
+
+        Some(4 + count_bits + self.bits.len())
+    }
+}
+
+// Split text into segments for kanji mode and byte mode
+// Characters of JIS X 0208 are written as Shift JIS in kanji mode, all
+// others as UTF-8 in byte mode
+pub fn kanji_segments(text: &str) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = vec![];
+    let mut kanji: Vec<u16> = vec![];
+    let mut bytes: Vec<u8> = vec![];
+
+    for character in text.chars() {
+        match to_shift_jis(character) {
+            Some(code) => {
+                if !bytes.is_empty() {
+                    segments.push(Segment::bytes(&bytes));
+                    bytes.clear();
+                }
+                kanji.push(code);
+            }
+            None => {
+                if !kanji.is_empty() {
+                    segments.push(Segment::kanji(&kanji));
+                    kanji.clear();
+                }
+                let mut buffer = [0; 4];
+                bytes.extend_from_slice(character.encode_utf8(&mut buffer).as_bytes());
+            }
+        }
+    }
+
+    if !kanji.is_empty() {
+        segments.push(Segment::kanji(&kanji));
+    }
+    if !bytes.is_empty() {
+        segments.push(Segment::bytes(&bytes));
+    }
+
+    segments
+}
+
+// Split a message into numeric, alphanumeric, byte and kanji segments with
+// the smallest total number of bits for a given version
+// Works character by character (UTF-8 characters if the message is valid
+// UTF-8, single bytes otherwise), keeping the cheapest way to end up in
+// each of the four modes. Costs are counted in sixths of a bit, since
+// numeric and alphanumeric characters take 10/3 and 11/2 bits on average.
+// Switching modes costs a new mode and length indicator.
+pub fn optimal_segments(message: &[u8], version: usize) -> Vec<Segment> {
+    split_segments(message, version, false)
+}
+
+// Alphanumeric values a byte is written as
+// Behind an FNC1 mode indicator, % stands for the group separator (0x1D)
+// and a literal % is doubled
+fn alphanumeric_values(byte: u8, fnc1: bool) -> Vec<u8> {
+    match byte {
+        0x1D if fnc1 => vec![38],
+        b'%' if fnc1 => vec![38, 38],
+        _ => alphanumeric_value(byte).into_iter().collect(),
+    }
+}
+
+// Optimal split of a message, with or without FNC1 rules for the
+// alphanumeric mode
+fn split_segments(message: &[u8], version: usize, fnc1: bool) -> Vec<Segment> {
+    let modes = [Mode::Numeric, Mode::Alphanumeric, Mode::Byte, Mode::Kanji];
+
+    // Split message into characters: their bytes and their kanji code
+    let characters: Vec<(&[u8], Option<u16>)> = match std::str::from_utf8(message) {
+        Ok(text) => text
+            .char_indices()
+            .map(|(index, character)| {
+                (
+                    &message[index..index + character.len_utf8()],
+                    to_shift_jis(character),
+                )
+            })
+            .collect(),
+        Err(_) => message.chunks(1).map(|byte| (byte, None)).collect(),
+    };
+
+    if characters.is_empty() {
+        return vec![];
+    }
+
+    // Cost of a mode and length indicator for each mode
+    let header_costs: Vec<usize> = modes
+        .iter()
+        .map(|mode| (4 + mode.character_count_bits(version)) * 6)
+        .collect();
+
+    // For every character and every mode the path may end in: the mode the
+    // character itself is written in
+    let mut character_modes: Vec<[Option<usize>; 4]> = Vec::with_capacity(characters.len());
+    let mut costs = header_costs.clone();
+
+    for (bytes, kanji) in &characters {
+        let mut new_costs = [usize::MAX; 4];
+        let mut new_modes: [Option<usize>; 4] = [None; 4];
+
+        // Continue the current segment, if the character fits the mode
+        let is_single = bytes.len() == 1;
+        let alphanumeric_length = if is_single {
+            alphanumeric_values(bytes[0], fnc1).len()
+        } else {
+            0
+        };
+        let fits = [
+            is_single && bytes[0].is_ascii_digit(),
+            alphanumeric_length > 0,
+            true,
+            kanji.is_some(),
+        ];
+        let character_costs = [20, alphanumeric_length * 33, bytes.len() * 8 * 6, 13 * 6];
+
+        for m in 0..4 {
+            if fits[m] {
+                new_costs[m] = costs[m] + character_costs[m];
+                new_modes[m] = Some(m);
+            }
+        }
+
+        // Start a new segment after this character, rounding the current
+        // segment up to full bits
+        for to in 0..4 {
+            for from in 0..4 {
+                if new_modes[from].is_none() {
+                    continue;
+                }
+                let switch_cost = new_costs[from].div_ceil(6) * 6 + header_costs[to];
+                if switch_cost < new_costs[to] {
+                    new_costs[to] = switch_cost;
+                    new_modes[to] = Some(from);
+                }
+            }
+        }
+
+        character_modes.push(new_modes);
+        costs = new_costs.to_vec();
+    }
+
+    // Find the cheapest final mode and trace the path backwards
+    let mut current = (0..4).min_by_key(|m| costs[*m]).unwrap_or(2);
+    let mut chosen: Vec<usize> = vec![0; characters.len()];
+
+    for n in (0..characters.len()).rev() {
+        current = character_modes[n][current].unwrap_or(2);
+        chosen[n] = current;
+    }
+
+    // Group runs of characters with the same mode into segments
+    let mut segments: Vec<Segment> = vec![];
+    let mut start = 0;
+
+    for n in 1..=characters.len() {
+        if n < characters.len() && chosen[n] == chosen[start] {
+            continue;
+        }
+
+        let run = &characters[start..n];
+        let bytes: Vec<u8> = run.iter().flat_map(|(bytes, _)| bytes.to_vec()).collect();
+
+        segments.push(match modes[chosen[start]] {
+            Mode::Numeric => Segment::numeric(&bytes),
+            Mode::Alphanumeric => {
+                let values: Vec<u8> = bytes
+                    .iter()
+                    .flat_map(|b| alphanumeric_values(*b, fnc1))
+                    .collect();
+                Segment::alphanumeric(&values)
+            }
+            Mode::Kanji => {
+                let codes: Vec<u16> = run.iter().filter_map(|(_, kanji)| *kanji).collect();
+                Segment::kanji(&codes)
+            }
+            _ => Segment::bytes(&bytes),
+        });
+
+        start = n;
+    }
+
+    segments
+}
+
+// Find the smallest version whose data capacity fits all segments
+pub fn select_version(segments: &[Segment], ecc_level: EccLevel) -> Option<usize> {
+    (1..=40).find(|&version| fits_version(segments, version, ecc_level))
+}
+
+// Raise the error correction level as far as the segments still fit into
+// the given version, instead of filling the space with padding
+// Never lowers the level
+pub fn boost_ecc_level(segments: &[Segment], version: usize, ecc_level: EccLevel) -> EccLevel {
+    [EccLevel::H, EccLevel::Q, EccLevel::M]
+        .into_iter()
+        .find(|&level| {
+            level as usize > ecc_level as usize && fits_version(segments, version, level)
+        })
+        .unwrap_or(ecc_level)
+}
+
+// How a message fits into the smallest QR code at one error correction
+// level
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capacity {
+    pub ecc_level: EccLevel,
+    pub version: usize,
+    // Bits of all segments, including mode and length indicators
+    pub bits_used: usize,
+    // Data bits the version holds at this level
+    pub bits_available: usize,
+    // Codewords of 236/17 padding after the terminator
+    pub pad_codewords: usize,
+}
+
+// Find the smallest version that holds the segments at the given level,
+// and how much space they take up in it
+pub fn capacity(segments: &[Segment], ecc_level: EccLevel) -> Option<Capacity> {
+    let version = select_version(segments, ecc_level)?;
+    let bits_used: usize = segments
+        .iter()
+        .filter_map(|segment| segment.total_bits(version))
+        .sum();
+    let data_codewords = data_codewords(version, ecc_level);
+
+    // The terminator (up to 4 bits) and the bits up to the next full
+    // codeword come before the padding
+    let used_codewords = (bits_used + 4).min(data_codewords * 8).div_ceil(8);
+
+    Some(Capacity {
+        ecc_level,
+        version,
+        bits_used,
+        bits_available: data_codewords * 8,
+        pad_codewords: data_codewords - used_codewords,
+    })
+}
+
+// Check whether all segments fit into a given version
+fn fits_version(segments: &[Segment], version: usize, ecc_level: EccLevel) -> bool {
+    let mut total = 0;
+    for segment in segments {
+        match segment.total_bits(version) {
+            Some(bits) => total += bits,
+            None => return false,
+        }
+    }
+
+    total <= data_codewords(version, ecc_level) * 8
+}
+
+// Split a message optimally and find the smallest version it fits into
+// The best split depends on the width of the length indicators, so it is
+// calculated anew for each of the three version ranges
+// Header segments (ECI, structured append, FNC1) are put in front of the
+// message. Behind an FNC1 header, 0x1D bytes are group separators.
+pub fn select_version_optimal(
+    header: &[Segment],
+    message: &[u8],
+    ecc_level: EccLevel,
+) -> Option<(Vec<Segment>, usize)> {
+    let fnc1 = header
+        .iter()
+        .any(|segment| matches!(segment.mode, Mode::Fnc1First | Mode::Fnc1Second));
+
+    for versions in [1..=9, 10..=26, 27..=40] {
+        let mut segments = header.to_vec();
+        segments.append(&mut split_segments(message, *versions.start(), fnc1));
+        for version in versions {
+            if fits_version(&segments, version, ecc_level) {
+                return Some((segments, version));
+            }
+        }
+    }
+
+    None
+}
+
+// Split a message across up to 16 symbols, so that none of them is larger
+// than the given version
+// Every symbol holds an equal share of the message, split optimally into
+// segments, behind a structured append header and an optional ECI segment
+// Uses as few symbols as possible; returns None if 16 aren't enough
+pub fn structured_append(
+    message: &[u8],
+    ecc_level: EccLevel,
+    max_version: usize,
+    eci: Option<u32>,
+) -> Option<Vec<Matrix>> {
+    // Evaluating the masks never fails
+    structured_append_with(
+        message,
+        ecc_level,
+        max_version,
+        eci,
+        MaskSelection::Evaluate,
+        &DefaultPenalty,
+        |header, part| select_version_optimal(header, part, ecc_level),
+    )
+    .unwrap_or(None)
+}
+
+// Split a message across up to 16 symbols like structured_append, but
+// encode every part with the given function, and choose the mask of every
+// symbol as asked
+// `encode` takes the header segments and a part of the message, and
+// returns all segments of the symbol and the smallest version that holds
+// them, or None if none does
+// Fails for forced masks above 7
+pub fn structured_append_with<F>(
+    message: &[u8],
+    ecc_level: EccLevel,
+    max_version: usize,
+    eci: Option<u32>,
+    selection: MaskSelection,
+    strategy: &dyn MaskStrategy,
+    encode: F,
+) -> Result<Option<Vec<Matrix>>, &'static str>
+where
+    F: Fn(&[Segment], &[u8]) -> Option<(Vec<Segment>, usize)>,
+{
+    let parity = message.iter().fold(0, |parity, byte| parity ^ byte);
+
+    'totals: for total in 1..=16 {
+        let parts: Vec<&[u8]> = message
+            .chunks(message.len().div_ceil(total).max(1))
+            .collect();
+
+        // Small messages may not split into as many parts as asked for
+        if parts.len() != total && total > 1 {
+            continue;
+        }
+
+        let mut symbols: Vec<Matrix> = Vec::with_capacity(parts.len());
+
+        for (index, part) in parts.iter().enumerate() {
+            let mut header = vec![Segment::structured_append(index, parts.len(), parity)];
+            if let Some(segment) = eci.and_then(Segment::eci) {
+                header.push(segment);
+            }
+
+            match encode(&header, part) {
+                Some((segments, version)) if version <= max_version => {
+                    let (matrix, _choice) =
+                        build_matrix_masked(&segments, version, ecc_level, selection, strategy)?;
+                    symbols.push(matrix);
+                }
+                _ => continue 'totals,
             }
-            encoded_bits[0] = encoded_bits[0] << 1;
-            index += 1;
         }
+
+        return Ok(Some(symbols));
+    }
+
+    Ok(None)
+}
+
+// Turn segments into a fully formed QR code
+pub fn build_matrix(segments: &[Segment], version: usize, ecc_level: EccLevel) -> Matrix {
+    let mut matrix = unmasked_matrix(segments, version, ecc_level);
+    matrix.mask_and_place_format_string();
+
+    matrix
+}
+
+// Turn segments into a fully formed QR code, choosing the mask as asked
+// Also returns the chosen mask and the scores of all candidates
+// Fails for forced masks above 7
+pub fn build_matrix_masked(
+    segments: &[Segment],
+    version: usize,
+    ecc_level: EccLevel,
+    selection: MaskSelection,
+    strategy: &dyn MaskStrategy,
+) -> Result<(Matrix, MaskChoice), &'static str> {
+    let mut matrix = unmasked_matrix(segments, version, ecc_level);
+    let choice = matrix.select_mask(selection, strategy)?;
+
+    Ok((matrix, choice))
+}
+
+// Place all patterns and the data of a QR code, everything but the mask
+// and the format string
+fn unmasked_matrix(segments: &[Segment], version: usize, ecc_level: EccLevel) -> Matrix {
+    // Add mode indicator, length indicator, padding, etc.
+    let data_bits = encapsulate_data(segments, version, ecc_level);
+
+    // Add error correction codewords
+    let data_ecc = apply_ecc(data_bits, version, ecc_level);
+
+    // Start with an empty matrix of the chosen version's size
+    let mut matrix = Matrix::new(version, ecc_level);
+
+    // Populate it with fixed patterns, data, and format information
+    matrix.place_finder_pattern();
+    matrix.place_alignment_pattern();
+    matrix.place_dark_module();
+    matrix.place_timing_pattern();
+    matrix.reserve_format_area();
+    matrix.place_version_information();
+    matrix.fill_data(data_ecc);
+
+    matrix
+}
+
+// Modules taken up by function patterns, format and version information
+// in a given version, i.e. all modules that don't hold data
+pub(crate) fn function_modules(version: usize) -> Vec<Vec<bool>> {
+    let mut matrix = Matrix::new(version, EccLevel::L);
+
+    matrix.place_finder_pattern();
+    matrix.place_alignment_pattern();
+    matrix.place_dark_module();
+    matrix.place_timing_pattern();
+    matrix.reserve_format_area();
+    matrix.place_version_information();
+
+    matrix.mask
+}
+
+// Append the lowest `length` bits of a value, MSB first
+pub(crate) fn append_bits(data: &mut Vec<u8>, value: u32, length: usize) {
+    for n in (0..length).rev() {
+        data.push(((value >> n) & 1) as u8);
+    }
+}
+
+// Take a list of segments and add everything needed to build a QR code
+// Every segment gets its own mode and length indicator
+pub fn encapsulate_data(segments: &[Segment], version: usize, ecc_level: EccLevel) -> Vec<u8> {
+    let capacity = data_codewords(version, ecc_level) * 8;
+    let mut data: Vec<u8> = Vec::with_capacity(capacity);
+
+    for segment in segments {
+        append_bits(&mut data, segment.mode.indicator(), 4);
+        append_bits(
+            &mut data,
+            segment.character_count as u32,
+            segment.mode.character_count_bits(version),
+        );
+        data.extend_from_slice(&segment.bits);
+    }
+
+    append_padding(&mut data, capacity, 4);
+
+    data
+}
+
+// Convert the bytes of a byte mode segment back into text, following the
+// character set declared by an ECI designator
+// Without an ECI segment, QR codes default to ISO-8859-1
+// Returns None for unsupported designators or invalid byte sequences
+pub fn decode_text(bytes: &[u8], eci: Option<u32>) -> Option<String> {
+    match eci {
+        // ISO-8859-1: every byte is the Unicode code point of the same value
+        None | Some(1) | Some(3) => Some(bytes.iter().map(|byte| *byte as char).collect()),
+        // Shift JIS
+        Some(20) => from_shift_jis(bytes),
+        // UTF-8
+        Some(26) => String::from_utf8(bytes.to_vec()).ok(),
+        // US-ASCII
+        Some(27) | Some(170) if bytes.is_ascii() => {
+            Some(bytes.iter().map(|byte| *byte as char).collect())
+        }
+        _ => None,
     }
+}
+
+// Check whether a password consists of ASCII digits only, which allows
+// for the denser numeric mode
+pub fn is_numeric(bytes: &[u8]) -> bool {
+    !bytes.is_empty() && bytes.iter().all(|byte| byte.is_ascii_digit())
+}
 
+// Fill the data bits up to the capacity of the chosen version
+// The terminator is 4 bits long in QR codes, rMQR codes use 3 bits
+pub(crate) fn append_padding(data: &mut Vec<u8>, capacity: usize, terminator_length: usize) {
     // If there's space left over, add terminator of 0s
-    // (maximum of four)
-    for _n in 0..4 {
-        if index == data.len() {
+    for _n in 0..terminator_length {
+        if data.len() == capacity {
             break;
         }
-        index += 1;
+        data.push(0);
     }
 
     // Pad to full bytes
-    while index % 8 != 0 {
-        index += 1;
+    while !data.len().is_multiple_of(8) {
+        data.push(0);
+    }
+
+    // Fill remaining space with alternating padding bytes
+    let mut pad_byte = 236;
+
+    while data.len() < capacity {
+        append_bits(data, pad_byte, 8);
+        pad_byte = if pad_byte == 236 { 17 } else { 236 };
+    }
+}
+
+// Concatenate a stream of bits into 8-bit codewords
+pub(crate) fn bits_to_codewords(bits: &[u8]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|byte| byte.iter().fold(0, |codeword, bit| codeword << 1 | bit))
+        .collect()
+}
+
+// Calculate Reed-Solomon code words
+// The message is split into blocks, each of which gets its own error
+// correction codewords. Blocks are then interleaved codeword by codeword:
+// first all data codewords, then all error correction codewords.
+pub fn apply_ecc(data: Vec<u8>, version: usize, ecc_level: EccLevel) -> Vec<u8> {
+    apply_ecc_blocks(
+        data,
+        ECC_BLOCKS[ecc_level as usize][version],
+        ECC_CODEWORDS_PER_BLOCK[ecc_level as usize][version],
+    )
+}
+
+// Split the data into the given number of blocks, calculate the error
+// correction codewords of each one, and interleave everything
+pub(crate) fn apply_ecc_blocks(data: Vec<u8>, block_count: usize, ecc_length: usize) -> Vec<u8> {
+    let message = bits_to_codewords(&data);
+
+    // Short blocks come first, long blocks hold one more data codeword
+    let short_length = message.len() / block_count;
+    let long_blocks = message.len() % block_count;
+
+    let mut data_blocks: Vec<&[u8]> = Vec::with_capacity(block_count);
+    let mut ecc_blocks: Vec<Vec<u8>> = Vec::with_capacity(block_count);
+    let mut start = 0;
+
+    for n in 0..block_count {
+        let mut length = short_length;
+        if n >= block_count - long_blocks {
+            length += 1;
+        }
+        data_blocks.push(&message[start..start + length]);
+        ecc_blocks.push(reed_solomon(&message[start..start + length], ecc_length));
+        start += length;
     }
 
-    // Fill remaining space with padding bytes
-    let mut temp: u8;
+    // Interleave data codewords, skipping blocks that are already used up
+    let mut codewords: Vec<u8> = Vec::with_capacity(message.len() + block_count * ecc_length);
 
+    for n in 0..=short_length {
+        for block in &data_blocks {
+            if n < block.len() {
+                codewords.push(block[n]);
+            }
+        }
+    }
+
+    // Interleave error correction codewords
+    for n in 0..ecc_length {
+        for block in &ecc_blocks {
+            codewords.push(block[n]);
+        }
+    }
+
+    // Convert codewords back into bits
+    let mut message_ecc: Vec<u8> = Vec::with_capacity(codewords.len() * 8);
+    for codeword in codewords {
+        append_bits(&mut message_ecc, codeword as u32, 8);
+    }
+
+    message_ecc
+}
+
+// Divide a message by the generator polynom of the given degree, using
+// finite field arithmetic. The remainder is the Reed-Solomon code.
+pub(crate) fn reed_solomon(message: &[u8], degree: usize) -> Vec<u8> {
+    let gen_poly = generator_polynomial(degree);
+
+    // Set initial state
+    // The message is multiplied by x^degree, i.e. padded with zeros
+    let mut remainder: Vec<u8> = message.to_vec();
+    remainder.resize(message.len() + degree, 0);
+
+    // Discard the quotient, keep the remainder
+    for n in 0..message.len() {
+        // Multiply the generator polynom with the first coefficient of
+        // the current remainder and subtract (i.e. XOR) the result
+        let temp = gf_multiply(&gen_poly, remainder[n]);
+        for (m, coefficient) in temp.iter().enumerate() {
+            remainder[n + m] ^= coefficient;
+        }
+    }
+
+    remainder.split_off(message.len())
+}
+
+// Generator polynom for GF(256) of a given degree, as chosen by QR specs:
+// the product of (x - a^i) for i from 0 to degree - 1, with a = 2
+// Coefficients are ordered from the highest power down
+fn generator_polynomial(degree: usize) -> Vec<u8> {
+    let mut gen_poly: Vec<u8> = vec![1];
+    let mut root: u8 = 1;
+
+    for _n in 0..degree {
+        // Multiply by x, then add the product with the root
+        let mut product = gen_poly.clone();
+        product.push(0);
+        for (m, coefficient) in gf_multiply(&gen_poly, root).iter().enumerate() {
+            product[m + 1] ^= coefficient;
+        }
+        gen_poly = product;
+        root = gf_product(root, 2);
+    }
+
+    gen_poly
+}
+
+// Multiply every coefficient of a polynom using finite field arithmetic
+fn gf_multiply(gen_poly: &[u8], factor: u8) -> Vec<u8> {
+    gen_poly
+        .iter()
+        .map(|coefficient| gf_product(*coefficient, factor))
+        .collect()
+}
+
+// Multiplication function using finite field arithmetic
+fn gf_product(factor_1: u8, factor_2: u8) -> u8 {
+    let mut temp: u16 = 0;
+    let mut mask: u16 = 1;
+    let factor_1 = factor_1 as u16;
+    let factor_2 = factor_2 as u16;
+
+    // Multiply the factors, adding without carry (bitwise mod 2)
+    while mask < 255 {
+        temp ^= (factor_2 & mask) * factor_1;
+        mask <<= 1;
+    }
+
+    // Substitute bits > 255 according to log-antilog table and add together
+    for substitute in [29, 58, 116, 232, 205, 135, 19, 38] {
+        if temp & mask == mask {
+            temp ^= substitute;
+        }
+        mask <<= 1;
+    }
+
+    // Remove bits > 255
+    (temp & 255) as u8
+}
+
+// Powers of a = 2 in GF(256): GF_EXP[n] = a^n
+const GF_EXP: [u8; 255] = gf_exp_table();
+
+// Logarithms to the base a = 2: GF_LOG[a^n] = n (GF_LOG[0] is unused)
+const GF_LOG: [u8; 256] = gf_log_table();
+
+// Multiply by a = 2 over and over, substituting bits > 255 with the
+// same reducing polynom gf_product uses
+const fn gf_exp_table() -> [u8; 255] {
+    let mut table = [0; 255];
+    let mut value: u16 = 1;
+    let mut n = 0;
+
+    while n < 255 {
+        table[n] = value as u8;
+        value <<= 1;
+        if value > 255 {
+            value ^= 0b1_0001_1101;
+        }
+        n += 1;
+    }
+
+    table
+}
+
+const fn gf_log_table() -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+
+    while n < 255 {
+        table[GF_EXP[n] as usize] = n as u8;
+        n += 1;
+    }
+
+    table
+}
+
+// a to the power of any exponent, since a^255 = 1
+fn gf_power(exponent: usize) -> u8 {
+    GF_EXP[exponent % 255]
+}
+
+// Multiplicative inverse of a non-zero element
+fn gf_inverse(value: u8) -> u8 {
+    GF_EXP[(255 - GF_LOG[value as usize] as usize) % 255]
+}
+
+// Evaluate a polynom with coefficients ordered from the lowest power up
+fn gf_evaluate(poly: &[u8], x: u8) -> u8 {
+    poly.iter()
+        .rev()
+        .fold(0, |value, coefficient| gf_product(value, x) ^ coefficient)
+}
+
+// Product of two polynoms with coefficients ordered from the lowest power
+// up
+fn gf_poly_product(poly_1: &[u8], poly_2: &[u8]) -> Vec<u8> {
+    let mut product = vec![0; poly_1.len() + poly_2.len() - 1];
+    for (n, coefficient_1) in poly_1.iter().enumerate() {
+        for (m, coefficient_2) in poly_2.iter().enumerate() {
+            product[n + m] ^= gf_product(*coefficient_1, *coefficient_2);
+        }
+    }
+
+    product
+}
+
+// Sum (i.e. XOR) of two polynoms with coefficients ordered from the lowest
+// power up
+fn gf_poly_sum(poly_1: &[u8], poly_2: &[u8]) -> Vec<u8> {
+    let mut sum = vec![0; poly_1.len().max(poly_2.len())];
+    for (n, coefficient) in poly_1.iter().enumerate() {
+        sum[n] ^= coefficient;
+    }
+    for (n, coefficient) in poly_2.iter().enumerate() {
+        sum[n] ^= coefficient;
+    }
+
+    sum
+}
+
+// Syndromes of a block of data and error correction codewords: the block,
+// read as a polynom, evaluated at the roots of the generator polynom
+// All of them are 0 if the block is undamaged
+fn syndromes(block: &[u8], ecc_length: usize) -> Vec<u8> {
+    (0..ecc_length)
+        .map(|n| {
+            block.iter().fold(0, |value, codeword| {
+                gf_product(value, gf_power(n)) ^ codeword
+            })
+        })
+        .collect()
+}
+
+// Correct a block of data and error correction codewords, as produced by
+// reed_solomon
+// Erasures are positions in the block known to be damaged, such as
+// unreadable modules. Correcting an erasure takes one error correction
+// codeword, correcting an error at an unknown position takes two.
+// Returns the corrected data codewords and the number of codewords that
+// had to be changed
+pub fn correct_errors(
+    block: &[u8],
+    ecc_length: usize,
+    erasures: &[usize],
+) -> Result<(Vec<u8>, usize), &'static str> {
+    let length = block.len();
+    if length > 255 || ecc_length > length || erasures.iter().any(|position| *position >= length) {
+        return Err("Invalid Reed-Solomon block");
+    }
+    if erasures.len() > ecc_length {
+        return Err("Too many errors to correct");
+    }
+
+    let syndromes = syndromes(block, ecc_length);
+    if syndromes.iter().all(|syndrome| *syndrome == 0) {
+        return Ok((block[..length - ecc_length].to_vec(), 0));
+    }
+
+    // The codeword at a position is the coefficient of x^(length - 1 -
+    // position), which makes a^(length - 1 - position) its locator
+    let locator = |position: usize| gf_power(length - 1 - position);
+
+    // Berlekamp-Massey: find the shortest error locator polynom, whose
+    // roots are the inverse locators of all damaged positions
+    // Starts out with the erasure locator polynom, the product of
+    // (1 + X x) for the locators X of all erasures
+    // Polynoms here are ordered from the lowest power up
+    let mut error_locator: Vec<u8> = vec![1];
+    for position in erasures {
+        error_locator = gf_poly_product(&error_locator, &[1, locator(*position)]);
+    }
+    let mut previous = error_locator.clone();
+    let mut degree = erasures.len();
+
+    for n in erasures.len()..ecc_length {
+        // Difference between the next syndrome and the one the current
+        // polynom predicts
+        let discrepancy = error_locator
+            .iter()
+            .take(n + 1)
+            .enumerate()
+            .fold(0, |sum, (m, coefficient)| {
+                sum ^ gf_product(*coefficient, syndromes[n - m])
+            });
+
+        previous.insert(0, 0);
+        if discrepancy == 0 {
+            continue;
+        }
+
+        let corrected = gf_poly_sum(&error_locator, &gf_multiply(&previous, discrepancy));
+        if 2 * degree <= n + erasures.len() {
+            previous = gf_multiply(&error_locator, gf_inverse(discrepancy));
+            degree = n + 1 + erasures.len() - degree;
+        }
+        error_locator = corrected;
+    }
+
+    while error_locator.last() == Some(&0) {
+        error_locator.pop();
+    }
+    if error_locator.len() - 1 != degree || 2 * degree > ecc_length + erasures.len() {
+        return Err("Too many errors to correct");
+    }
+
+    // Chien search: try every position of the block as a root
+    let positions: Vec<usize> = (0..length)
+        .filter(|position| gf_evaluate(&error_locator, gf_inverse(locator(*position))) == 0)
+        .collect();
+    if positions.len() != degree {
+        return Err("Too many errors to correct");
+    }
+
+    // Forney: the error magnitude at locator X is
+    // X * Omega(X^-1) / Lambda'(X^-1), with the error evaluator
+    // Omega = syndromes * Lambda mod x^ecc_length and the formal derivative
+    // Lambda', which keeps only the odd powers
+    let mut evaluator = gf_poly_product(&syndromes, &error_locator);
+    evaluator.truncate(ecc_length);
+    let derivative: Vec<u8> = error_locator
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(n, coefficient)| if n % 2 == 1 { *coefficient } else { 0 })
+        .collect();
+
+    let mut corrected = block.to_vec();
+    let mut changed = 0;
+
+    for position in positions {
+        let x = locator(position);
+        let x_inverse = gf_inverse(x);
+        let denominator = gf_evaluate(&derivative, x_inverse);
+        if denominator == 0 {
+            return Err("Too many errors to correct");
+        }
+
+        let magnitude = gf_product(
+            gf_product(x, gf_evaluate(&evaluator, x_inverse)),
+            gf_inverse(denominator),
+        );
+        if magnitude != 0 {
+            corrected[position] ^= magnitude;
+            changed += 1;
+        }
+    }
+
+    // With too many errors, the result may not be a valid block either
+    let ecc = corrected.split_off(length - ecc_length);
+    if reed_solomon(&corrected, ecc_length) != ecc {
+        return Err("Too many errors to correct");
+    }
+
+    Ok((corrected, changed))
+}
+
+// Place data bits into all modules that aren't masked
+// Start at lower right corner of the matrix and go upward, alternately
+// filling two columns, then move over two columns and go downward, and so
+// on. A column of timing pattern in between is skipped entirely.
+// Modules left over stay as they are (remainder bits, all 0s).
+pub(crate) fn place_zigzag(
+    data: &mut [Vec<u8>],
+    mask: &[Vec<bool>],
+    data_bits: &[u8],
+    start_column: usize,
+    skip_column: Option<usize>,
+) {
+    // Set initial state
+    // Start at the bottom of the given column and at bit 0 of data
+    let height = data.len();
+    let mut col = start_column;
+    let mut upward = true;
+    let mut index = 0;
+
+    // Place bits one by one into modules
     loop {
-        if index == data.len() {
+        // Go upward or downward, alternately filling two columns
+        // Only fill a module if it isn't masked
+        for n in 0..height {
+            let row = if upward { height - 1 - n } else { n };
+            for c in [col, col - 1] {
+                if !mask[row][c] && index < data_bits.len() {
+                    data[row][c] = data_bits[index];
+                    index += 1;
+                }
+            }
+        }
+
+        if col <= 2 {
             break;
         }
 
-        temp = 236; // First pad byte
+        // Move over two columns and change direction
+        // Skip the vertical timing pattern entirely
+        col -= 2;
+        if Some(col) == skip_column {
+            col -= 1;
+        }
+        upward = !upward;
+    }
+}
 
-        for _n in 0..8 {
-            if temp & 128 == 128 {
-                data[index] = 1;
-            } else {
-                data[index] = 0;
+// Read data bits back from all modules that aren't masked, in the order
+// place_zigzag puts them in
+pub(crate) fn read_zigzag(
+    data: &[Vec<u8>],
+    mask: &[Vec<bool>],
+    start_column: usize,
+    skip_column: Option<usize>,
+) -> Vec<u8> {
+    let height = data.len();
+    let mut col = start_column;
+    let mut upward = true;
+    let mut data_bits: Vec<u8> = vec![];
+
+    loop {
+        for n in 0..height {
+            let row = if upward { height - 1 - n } else { n };
+            for c in [col, col - 1] {
+                if !mask[row][c] {
+                    data_bits.push(data[row][c]);
+                }
             }
-            temp = temp << 1;
-            index += 1;
         }
 
-        if index == data.len() {
+        if col <= 2 {
             break;
         }
 
-        temp = 17; // Second pad byte
+        col -= 2;
+        if Some(col) == skip_column {
+            col -= 1;
+        }
+        upward = !upward;
+    }
+
+    data_bits
+}
+
+// Masking patterns: a module is toggled where the function returns 0
+// Arguments are row and column
+pub(crate) fn mask_pattern(mask_no: usize) -> fn(usize, usize) -> usize {
+    match mask_no {
+        0 => |row, col| (row + col) % 2,
+        1 => |row, _col| row % 2,
+        2 => |_row, col| col % 3,
+        3 => |row, col| (row + col) % 3,
+        4 => |row, col| (row / 2 + col / 3) % 2,
+        5 => |row, col| (row * col) % 2 + (row * col) % 3,
+        6 => |row, col| ((row * col) % 2 + (row * col) % 3) % 2,
+        _ => |row, col| ((row + col) % 2 + (row * col) % 3) % 2,
+    }
+}
 
-        for _n in 0..8 {
-            if temp & 128 == 128 {
-                data[index] = 1;
-            } else {
-                data[index] = 0;
-            }
-            temp = temp << 1;
+// Add 10 BCH error correction bits to 5 bits of format information
+// The generator polynom is the same for QR and Micro QR codes, only the
+// final XOR mask differs
+pub(crate) fn format_bch(format_data: u16) -> u16 {
+    let mut format_string: u16 = format_data << 10;
+    let mut gen_poly: u16 = 0b10100110111;
+
+    // Prepare for first division
+    // Align with the highest possible bit of the format string
+    gen_poly <<= 4;
+
+    // XOR (i.e. divide) until 10 EC bits remain
+    while format_string.leading_zeros() < 6 {
+        while gen_poly.leading_zeros() != format_string.leading_zeros() {
+            gen_poly >>= 1;
         }
+        format_string ^= gen_poly;
     }
 
-    data
+    // Add EC bits to format string
+    format_string ^ format_data << 10
 }
 
-// Calculate Reed-Solomon code words
-pub fn apply_ecc(data: [u8; 34 * 8]) -> [u8; 44 * 8] {
-    // Version 2, ECC level L needs 10 EC code words
-    let mut index = 0;
-    let mut message: [u8; 44] = [0; 44];
-    let mut message_ecc: [u8; 44 * 8] = [0; 44 * 8];
-
-    // Concatenate input bits into 8-bit message codewords
-    for n in 0..34 {
-        message[n] = data[index] * 128
-            + data[index + 1] * 64
-            + data[index + 2] * 32
-            + data[index + 3] * 16
-            + data[index + 4] * 8
-            + data[index + 5] * 4
-            + data[index + 6] * 2
-            + data[index + 7];
-        index += 8;
+// Add 12 BCH error correction bits to 6 bits of version information
+// rMQR codes protect their format information the same way
+pub(crate) fn version_bch(version_data: u32) -> u32 {
+    let version_string: u32 = version_data << 12;
+    let gen_poly: u32 = 0b1_1111_0010_0101;
+
+    // XOR (i.e. divide) until 12 EC bits remain
+    let mut remainder = version_string;
+    while 32 - remainder.leading_zeros() > 12 {
+        let shift = (32 - remainder.leading_zeros()) - 13;
+        remainder ^= gen_poly << shift;
     }
 
-    // Set initial state
-    let mut temp: [u8; 11];
-    let mut remainder: [u8; 11] = [0; 11];
-    remainder.copy_from_slice(&message[..11]);
+    version_string | remainder
+}
 
-    // Generator polynom for GF(256), chosen by QR specs
-    let gen_poly: [u8; 11] = [1, 216, 194, 159, 111, 199, 94, 95, 113, 157, 193];
+// Orientation and colors of an exported symbol, for any kind of matrix
+// Mirroring comes first, then the rotation
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Transform {
+    // Light modules on a dark background, quiet zone included
+    pub invert: bool,
+    // Swap left and right, for reading through the back of a window
+    pub mirror: bool,
+    // Number of clockwise quarter turns (0 to 3)
+    pub quarter_turns: usize,
+}
 
-    // Divide message by generator polynom using finite field arithmetic
-    // Discard the quotient, keep the remainder
-    // Remainder of the last division is the Reed-Solomon code
-    for n in 0..34 {
-        // Multiply the generator polynom with the first coefficient of
-        // the current remainder
-        temp = gf_multiply(gen_poly, remainder[0]);
-        // Subtract (i.e. XOR) the result from the remainder, discard
-        // the first element (which is 0), and shift everything to the
-        // next position
-        for m in 0..10 {
-            remainder[m] = remainder[m + 1] ^ temp[m + 1];
+impl Transform {
+    // Apply the transformation to an exported matrix of modules
+    pub fn apply(&self, mut modules: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        if self.invert {
+            for module in modules.iter_mut().flatten() {
+                *module ^= 1;
+            }
+        }
+
+        if self.mirror {
+            for row in modules.iter_mut() {
+                row.reverse();
+            }
         }
-        // If this is not the last round, fill in the last place of the remainder
-        // with a new byte from the message to be divided
-        if n != 33 {
-            remainder[10] = message[n + 11];
+
+        // A clockwise quarter turn: the last row becomes the first column
+        for _n in 0..self.quarter_turns % 4 {
+            let height = modules.len();
+            let width = modules[0].len();
+            modules = (0..width)
+                .map(|col| (0..height).rev().map(|row| modules[row][col]).collect())
+                .collect();
         }
+
+        modules
     }
 
-    // Copy input data data to message_ecc, which has space for the
-    // error correction codes
-    message_ecc[..34 * 8].copy_from_slice(&data);
-    index = 34 * 8;
+    // Undo the transformation, turning an exported matrix back into its
+    // original orientation and colors
+    pub fn undo(&self, modules: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        // Turn back first, then mirror
+        let turn_back = Transform {
+            invert: self.invert,
+            mirror: false,
+            quarter_turns: (4 - self.quarter_turns % 4) % 4,
+        };
+        let mirror = Transform {
+            invert: false,
+            mirror: self.mirror,
+            quarter_turns: 0,
+        };
 
-    // Convert error correction codewords into bits and append each one
-    // Discard remainder[10], which is only used for computing
-    for n in 0..10 {
-        if remainder[n] & 128 == 128 {
-            message_ecc[index + 0] = 1;
-        }
-        if remainder[n] & 64 == 64 {
-            message_ecc[index + 1] = 1;
-        }
-        if remainder[n] & 32 == 32 {
-            message_ecc[index + 2] = 1;
-        }
-        if remainder[n] & 16 == 16 {
-            message_ecc[index + 3] = 1;
-        }
-        if remainder[n] & 8 == 8 {
-            message_ecc[index + 4] = 1;
-        }
-        if remainder[n] & 4 == 4 {
-            message_ecc[index + 5] = 1;
-        }
-        if remainder[n] & 2 == 2 {
-            message_ecc[index + 6] = 1;
-        }
-        if remainder[n] & 1 == 1 {
-            message_ecc[index + 7] = 1;
-        }
-        index += 8;
+        mirror.apply(turn_back.apply(modules))
     }
+}
 
-    message_ecc
+// How the mask pattern of a QR code is chosen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MaskSelection {
+    // Score all 8 masks and keep the one with the lowest penalty
+    Evaluate,
+    // Always use the given mask (0 to 7), e.g. for reproducible output
+    Forced(usize),
+    // Skip evaluation and use mask 0
+    Fast,
 }
 
-// Multiplication function using finite field arithmetic
-fn gf_multiply(gen_poly: [u8; 11], factor: u8) -> [u8; 11] {
-    let mut temp: u16;
-    let mut mask: u16;
-    let mut factor_1: u16;
-    let mut result: [u8; 11] = [0; 11];
+// Scores a masked candidate matrix
+// The mask with the lowest score wins, ties go to the lower mask number
+pub trait MaskStrategy {
+    fn score(&self, candidate: &Matrix) -> usize;
 
-    let factor_2 = factor as u16;
+    // Whether the candidate's format string is placed before scoring it
+    fn scores_format_string(&self) -> bool {
+        false
+    }
+}
 
-    // Multiply each coefficient in turn
-    for n in 0..11 {
-        temp = 0;
-        mask = 1;
-        factor_1 = gen_poly[n] as u16;
+// The penalty scoring this crate has always used, which scores the
+// candidates without their format string
+pub struct DefaultPenalty;
 
-        // Multiply the factors, adding without carry (bitwise mod 2)
-        while mask < 255 {
-            temp = ((factor_2 & mask) * factor_1) ^ temp;
-            mask = mask << 1;
-        }
+impl MaskStrategy for DefaultPenalty {
+    fn score(&self, candidate: &Matrix) -> usize {
+        candidate.default_penalty_rules().iter().sum()
+    }
+}
 
-        // Substitute bits > 255 according to log-antilog table and add together
-        if temp & mask == mask {
-            temp = temp ^ 29;
-        }
-        mask = mask << 1;
-        if temp & mask == mask {
-            temp = temp ^ 58;
-        }
-        mask = mask << 1;
-        if temp & mask == mask {
-            temp = temp ^ 116;
-        }
-        mask = mask << 1;
-        if temp & mask == mask {
-            temp = temp ^ 232;
-        }
-        mask = mask << 1;
-        if temp & mask == mask {
-            temp = temp ^ 205;
-        }
-        mask = mask << 1;
-        if temp & mask == mask {
-            temp = temp ^ 135;
-        }
-        mask = mask << 1;
-        if temp & mask == mask {
-            temp = temp ^ 19;
-        }
-        mask = mask << 1;
-        if temp & mask == mask {
-            temp = temp ^ 38;
-        }
+// Penalty scoring as defined by ISO 18004, which reference encoders use
+pub struct IsoPenalty;
 
-        // Remove bits > 255
-        temp = temp & 255;
+impl MaskStrategy for IsoPenalty {
+    fn score(&self, candidate: &Matrix) -> usize {
+        candidate.iso_penalty_rules().iter().sum()
+    }
 
-        result[n] = temp as u8;
+    // The standard scores the whole symbol, format string included
+    fn scores_format_string(&self) -> bool {
+        true
     }
+}
 
-    result
+// Result of the mask selection: the mask that was applied, and the score
+// of every mask if they were evaluated
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaskChoice {
+    pub mask: usize,
+    pub scores: Option<[usize; 8]>,
 }
 
 // Representation of a 2D QR code and methods for preparing, populating, and extracting it
@@ -327,31 +1824,29 @@ impl Matrix {
     // Every module (black or white square) in the final QR code is represented
     // by one u8: 0 - white, 1 - black
     // Keep track of prohibited areas, where data can't be written
-    pub fn new() -> Matrix {
+    pub fn new(version: usize, ecc_level: EccLevel) -> Matrix {
+        let size = version * 4 + 17;
         Matrix {
-            data: [[0; 25]; 25],
-            mask: [[false; 25]; 25],
+            version,
+            ecc_level,
+            size,
+            data: vec![vec![0; size]; size],
+            mask: vec![vec![false; size]; size],
         }
     }
 
     pub fn place_finder_pattern(&mut self) {
         let point_1 = Point(0, 0);
-        let point_2 = Point(18, 0);
-        let point_3 = Point(0, 18);
+        let point_2 = Point(self.size - 7, 0);
+        let point_3 = Point(0, self.size - 7);
         let points = [&point_1, &point_2, &point_3];
 
         for point in points {
             // Apply pattern
             for n in 0..7 {
-                self.data[point.0 + 0][point.1 + n] = 1;
-            }
-            for n in 0..7 {
-                self.data[point.0 + n][point.1 + 0] = 1;
-            }
-            for n in 0..7 {
+                self.data[point.0][point.1 + n] = 1;
+                self.data[point.0 + n][point.1] = 1;
                 self.data[point.0 + 6][point.1 + n] = 1;
-            }
-            for n in 0..7 {
                 self.data[point.0 + n][point.1 + 6] = 1;
             }
             for n in 0..3 {
@@ -366,66 +1861,59 @@ impl Matrix {
         for n in 0..8 {
             for i in 0..8 {
                 self.mask[point_1.0 + n][point_1.1 + i] = true;
-            }
-        }
-        for n in 0..8 {
-            for i in 0..8 {
                 self.mask[point_2.0 - 1 + n][point_2.1 + i] = true;
-            }
-        }
-        for n in 0..8 {
-            for i in 0..8 {
                 self.mask[point_3.0 + n][point_3.1 - 1 + i] = true;
             }
         }
     }
 
     pub fn place_alignment_pattern(&mut self) {
-        let point = Point(18, 18);
+        let positions = ALIGNMENT_POSITIONS[self.version];
+        let last = positions.len().saturating_sub(1);
 
-        // Place pattern
-        self.data[point.0][point.1] = 1;
-        for n in 0..5 {
-            self.data[point.0 - 2 + 0][point.1 - 2 + n] = 1;
-        }
-        for n in 0..5 {
-            self.data[point.0 - 2 + n][point.1 - 2 + 0] = 1;
-        }
-        for n in 0..5 {
-            self.data[point.0 + 2 + 0][point.1 - 2 + n] = 1;
-        }
-        for n in 0..5 {
-            self.data[point.0 - 2 + n][point.1 + 2 + 0] = 1;
-        }
+        for (r, row) in positions.iter().enumerate() {
+            for (c, col) in positions.iter().enumerate() {
+                // Skip the three corners occupied by finder patterns
+                if (r == 0 && (c == 0 || c == last)) || (r == last && c == 0) {
+                    continue;
+                }
 
-        // Block area for data bits
-        for n in 0..5 {
-            for i in 0..5 {
-                self.mask[point.0 - 2 + n][point.1 - 2 + i] = true;
+                let point = Point(*row, *col);
+
+                // Place pattern
+                self.data[point.0][point.1] = 1;
+                for n in 0..5 {
+                    self.data[point.0 - 2][point.1 - 2 + n] = 1;
+                    self.data[point.0 - 2 + n][point.1 - 2] = 1;
+                    self.data[point.0 + 2][point.1 - 2 + n] = 1;
+                    self.data[point.0 - 2 + n][point.1 + 2] = 1;
+                }
+
+                // Block area for data bits
+                for n in 0..5 {
+                    for i in 0..5 {
+                        self.mask[point.0 - 2 + n][point.1 - 2 + i] = true;
+                    }
+                }
             }
         }
     }
 
     // There's always one black module next to the lower left finder pattern
     pub fn place_dark_module(&mut self) {
-        let point = Point(17, 8);
+        let point = Point(self.size - 8, 8);
         self.data[point.0][point.1] = 1;
         self.mask[point.0][point.1] = true;
     }
 
     // One row and one column of alternating black and white modules
     pub fn place_timing_pattern(&mut self) {
-        for n in 0..9 {
+        for n in 0..self.size - 16 {
             if n % 2 == 0 {
                 self.data[6][8 + n] = 1;
-            }
-            self.mask[6][8 + n] = true;
-        }
-
-        for n in 0..9 {
-            if n % 2 == 0 {
                 self.data[8 + n][6] = 1;
             }
+            self.mask[6][8 + n] = true;
             self.mask[8 + n][6] = true;
         }
     }
@@ -434,129 +1922,127 @@ impl Matrix {
     // Includes which masking pattern was used, which will be determined later
     // Will be added at the last step
     pub fn reserve_format_area(&mut self) {
-        for n in 0..25 {
-            if n <= 8 || n >= 17 {
+        for n in 0..self.size {
+            if n <= 8 || n >= self.size - 8 {
                 self.mask[8][n] = true;
                 self.mask[n][8] = true;
             }
         }
     }
 
-    // After all the fixed modules have been placed, fill remainder with data
-    // As calculated, the data array has 7 bits fewer than there are empty
-    // modules. Per QR specs, these should be filled with 0s. Since the array
-    // was initialized as all 0s, they don't have to be explicitly added to
-    // the input data.
-    pub fn fill_data(&mut self, data_bits: [u8; 44 * 8]) {
-        // Set initial state
-        // Start at lower right corner of the matrix and at bit 0 of data
-        let mut col = 24;
-        let mut row = 24;
-        let mut index = 0;
-
-        // Place bits one by one into modules
-        while index < data_bits.len() {
-            // Go upward, alternately filling two columns
-            // Only fill a module if it isn't masked
-            loop {
-                if !self.mask[row][col] {
-                    self.data[row][col] = data_bits[index];
-                    index += 1;
-                }
-                if index == data_bits.len() {
-                    break;
-                }
-                if !self.mask[row][col - 1] {
-                    self.data[row][col - 1] = data_bits[index];
-                    index += 1;
-                }
-                if index == data_bits.len() {
-                    break;
-                }
-                if row == 0 {
-                    break;
-                }
-                row -= 1;
-            }
+    // From version 7 on, the version number is stored twice: in a 6x3 block
+    // above the lower left finder pattern and in a 3x6 block left of the
+    // upper right finder pattern.
+    // 6 bits of version number, 12 BCH error correction bits
+    pub fn place_version_information(&mut self) {
+        if self.version < 7 {
+            return;
+        }
 
-            // Move over two columns
-            // Skip the vertical timing pattern entirely
-            col -= 2;
-            if col == 6 {
-                col -= 1;
-            }
+        let version_string = version_bch(self.version as u32);
 
-            // Go downward, alternately filling two columns
-            // Only fill a module if it isn't masked
-            loop {
-                if !self.mask[row][col] {
-                    self.data[row][col] = data_bits[index];
-                    index += 1;
-                }
-                if index == data_bits.len() {
-                    break;
-                }
-                if !self.mask[row][col - 1] {
-                    self.data[row][col - 1] = data_bits[index];
-                    index += 1;
-                }
-                if index == data_bits.len() {
-                    break;
-                }
-                if row == 24 {
-                    break;
-                }
-                row += 1;
-            }
+        // Least significant bit goes into the corner nearest the
+        // upper left finder pattern
+        for n in 0..18 {
+            let bit = ((version_string >> n) & 1) as u8;
+            let a = self.size - 11 + n % 3;
+            let b = n / 3;
 
-            if col > 1 {
-                col -= 2;
-            }
-            if col == 6 {
-                col -= 1;
-            }
+            self.data[a][b] = bit;
+            self.data[b][a] = bit;
+            self.mask[a][b] = true;
+            self.mask[b][a] = true;
         }
     }
 
+    // After all the fixed modules have been placed, fill remainder with data
+    // Depending on the version, there are up to 7 more empty modules than
+    // data bits. Per QR specs, these should be filled with 0s. Since the
+    // matrix was initialized as all 0s, they don't have to be explicitly
+    // added to the input data.
+    pub fn fill_data(&mut self, data_bits: Vec<u8>) {
+        place_zigzag(
+            &mut self.data,
+            &self.mask,
+            &data_bits,
+            self.size - 1,
+            Some(6),
+        );
+    }
+
     // Masks flip certain modules to reduce areas which are difficult
     // to scan correctly.
     // 8 masking patterns exist. Apply each one in turn, evaluate all of
     // them, then choose the one with the lowest penalty score.
     // Use the chosen pattern for the matrix.
-    pub fn mask_and_place_format_string(&mut self) {
-        let mut all_masks = [self.clone(); 8];
-        let mut lowest_score = (0, usize::MAX);
+    pub fn mask_and_place_format_string(&mut self) -> MaskChoice {
+        let choice = self.evaluate_masks(&DefaultPenalty);
+        self.transform(choice.mask);
+        self.place_format_string(choice.mask);
 
-        for n in 0..8 {
-            all_masks[n].transform(n);
-            if all_masks[n].evaluate() < lowest_score.1 {
-                lowest_score.0 = n;
-                lowest_score.1 = all_masks[n].evaluate();
+        choice
+    }
+
+    // Apply a mask chosen by the given selection and place the matching
+    // format string
+    // Fails for forced masks above 7
+    pub fn select_mask(
+        &mut self,
+        selection: MaskSelection,
+        strategy: &dyn MaskStrategy,
+    ) -> Result<MaskChoice, &'static str> {
+        let choice = match selection {
+            MaskSelection::Forced(mask) if mask > 7 => {
+                return Err("Mask must be a number from 0 to 7");
+            }
+            MaskSelection::Forced(mask) => MaskChoice { mask, scores: None },
+            MaskSelection::Fast => MaskChoice {
+                mask: 0,
+                scores: None,
+            },
+            MaskSelection::Evaluate => self.evaluate_masks(strategy),
+        };
+
+        self.transform(choice.mask);
+        self.place_format_string(choice.mask);
+
+        Ok(choice)
+    }
+
+    // Score all 8 masks and choose the one with the lowest penalty
+    fn evaluate_masks(&self, strategy: &dyn MaskStrategy) -> MaskChoice {
+        let mut scores = [0; 8];
+        for (n, score) in scores.iter_mut().enumerate() {
+            let mut candidate = self.clone();
+            candidate.transform(n);
+            if strategy.scores_format_string() {
+                candidate.place_format_string(n);
             }
+            *score = strategy.score(&candidate);
         }
 
-        self.data = all_masks[lowest_score.0].data;
-        self.place_format_string(lowest_score.0);
+        let mut mask = 0;
+        for n in 1..8 {
+            if scores[n] < scores[mask] {
+                mask = n;
+            }
+        }
+
+        MaskChoice {
+            mask,
+            scores: Some(scores),
+        }
     }
 
     // Toggle bits in a matrix following a predefined pattern
     fn transform(&mut self, mask_no: usize) {
         // Choose masking pattern
-        let eval = match mask_no {
-            0 => |row, col| (row + col) % 2,
-            1 => |row, _col| row % 2,
-            2 => |_row, col| col % 3,
-            3 => |row, col| (row + col) % 3,
-            4 => |row: usize, col: usize| (row.div_euclid(2) + col.div_euclid(3)) % 2,
-            5 => |row, col| (row * col) % 2 + (row * col) % 3,
-            6 => |row, col| ((row * col) % 2 + (row * col) % 3) % 2,
-            _ => |row, col| ((row + col) % 2 + (row * col) % 3) % 2,
-        };
+        let eval = mask_pattern(mask_no);
 
         // Apply masking pattern
         // Only toggle data bits
-        for row in 0..25 {
-            for col in 0..25 {
+        for row in 0..self.size {
+            for col in 0..self.size {
                 if self.mask[row][col] {
                     continue;
                 }
@@ -569,7 +2055,7 @@ impl Matrix {
     }
 
     fn flip_bit(&mut self, row: usize, col: usize) {
-        if self.mask[row][col] == true {
+        if self.mask[row][col] {
             return;
         }
 
@@ -580,27 +2066,32 @@ impl Matrix {
         }
     }
 
-    // Search for certain patterns and tally a penalty score
-    fn evaluate(&self) -> usize {
-        let mut score: usize = 0;
+    // Search for certain patterns and tally a penalty score for each of
+    // the four rules, the way this crate always has
+    // Deviates from ISO 18004: rule 1 counts overlapping windows of 5
+    // modules and carries the run across line ends, rule 3 neither looks
+    // for light modules next to the pattern nor at the quiet zone
+    pub fn default_penalty_rules(&self) -> [usize; 4] {
+        let size = self.size;
+        let mut rules: [usize; 4] = [0; 4];
 
         // Rule 1: Five or more same-colored modules
         let mut pattern = [0; 5];
         let mut continuous = false;
 
         // Rule 1 in rows
-        for row in 0..25 {
-            for col in 0..21 {
-                for n in 0..5 {
-                    pattern[n] = self.data[row][col + n];
+        for row in 0..size {
+            for col in 0..size - 4 {
+                for (n, module) in pattern.iter_mut().enumerate() {
+                    *module = self.data[row][col + n];
                 }
 
                 if pattern == [0; 5] || pattern == [1; 5] {
                     if !continuous {
-                        score += 3;
+                        rules[0] += 3;
                         continuous = true;
                     } else {
-                        score += 1
+                        rules[0] += 1
                     }
                 } else {
                     continuous = false;
@@ -610,18 +2101,18 @@ impl Matrix {
 
         // Rule 1 in columns
         continuous = false;
-        for row in 0..21 {
-            for col in 0..25 {
-                for n in 0..5 {
-                    pattern[n] = self.data[row + n][col];
+        for row in 0..size - 4 {
+            for col in 0..size {
+                for (n, module) in pattern.iter_mut().enumerate() {
+                    *module = self.data[row + n][col];
                 }
 
                 if pattern == [0; 5] || pattern == [1; 5] {
                     if !continuous {
-                        score += 3;
+                        rules[0] += 3;
                         continuous = true;
                     } else {
-                        score += 1
+                        rules[0] += 1
                     }
                 } else {
                     continuous = false;
@@ -632,15 +2123,15 @@ impl Matrix {
         // Rule 2: same-coloured modules in a 2x2 square
         let mut pattern = [0; 4];
 
-        for row in 0..23 {
-            for col in 0..23 {
-                pattern[0] = self.data[row + 0][col + 0];
-                pattern[1] = self.data[row + 1][col + 0];
-                pattern[2] = self.data[row + 0][col + 1];
+        for row in 0..size - 1 {
+            for col in 0..size - 1 {
+                pattern[0] = self.data[row][col];
+                pattern[1] = self.data[row + 1][col];
+                pattern[2] = self.data[row][col + 1];
                 pattern[3] = self.data[row + 1][col + 1];
 
                 if pattern == [0; 4] || pattern == [1; 4] {
-                    score += 3
+                    rules[1] += 3
                 }
             }
         }
@@ -651,27 +2142,25 @@ impl Matrix {
         let search_ptn_2 = [1, 0, 1, 1, 1, 0, 1, 0, 0, 0, 0];
 
         // Rule 3 in rows
-        for row in 0..25 {
-            for col in 0..15 {
-                for n in 0..11 {
-                    pattern[n] = self.data[row][col + n];
-                }
+        for row in 0..size {
+            for col in 0..size - 10 {
+                pattern.copy_from_slice(&self.data[row][col..col + 11]);
 
                 if pattern == search_ptn_1 || pattern == search_ptn_2 {
-                    score += 40;
+                    rules[2] += 40;
                 }
             }
         }
 
         // Rule 3 in columns
-        for row in 0..15 {
-            for col in 0..25 {
-                for n in 0..11 {
-                    pattern[n] = self.data[row + n][col];
+        for row in 0..size - 10 {
+            for col in 0..size {
+                for (n, module) in pattern.iter_mut().enumerate() {
+                    *module = self.data[row + n][col];
                 }
 
                 if pattern == search_ptn_1 || pattern == search_ptn_2 {
-                    score += 40;
+                    rules[2] += 40;
                 }
             }
         }
@@ -680,14 +2169,14 @@ impl Matrix {
         let mut count_dark = 0;
 
         // Calculate the percentage of dark modules
-        for row in 0..25 {
-            for col in 0..25 {
-                if self.data[row][col] == 1 {
+        for row in &self.data {
+            for module in row {
+                if *module == 1 {
                     count_dark += 1;
                 }
             }
         }
-        let percentage_dark = count_dark * 100 / (25 * 25);
+        let percentage_dark = count_dark * 100 / (size * size) as i32;
 
         // Take the adjacent multiples of 5 and subtract 50 from them.
         // The lower absolute value * 2 is the penalty score.
@@ -700,57 +2189,145 @@ impl Matrix {
         let a: i32 = lower_multiple - 50;
         let b: i32 = upper_multiple - 50;
 
-        let x;
-        if a.abs() <= b.abs() {
-            x = a.abs() * 2;
+        let x = if a.abs() <= b.abs() {
+            a.abs() * 2
         } else {
-            x = b.abs() * 2;
+            b.abs() * 2
+        };
+
+        rules[3] += x as usize;
+
+        rules
+    }
+
+    // Penalty score for each of the four rules of ISO 18004, counted
+    // exactly the way qrcodegen 1.8.0 (Nayuki) counts them, so that both
+    // choose the same mask
+    // Rule 1: 3 points for every run of 5 same-colored modules in a row or
+    // column, plus 1 for each further module of the run
+    // Rule 2: 3 points for every 2x2 block of the same color
+    // Rule 3: 40 points for every finder-like pattern, dark and light runs
+    // in the ratio 1:1:3:1:1, with a light run 4 times as wide on one side
+    // and at least as wide on the other. A pattern with such runs on both
+    // sides counts twice. The quiet zone is light and as wide as the
+    // symbol.
+    // Rule 4: 10 points for every full 5% the share of dark modules
+    // deviates from 50%
+    pub fn iso_penalty_rules(&self) -> [usize; 4] {
+        let size = self.size;
+        let mut rules: [usize; 4] = [0; 4];
+
+        // Rules 1 and 3 look at rows and columns alike
+        let mut lines: Vec<Vec<u8>> = self.data.clone();
+        for col in 0..size {
+            lines.push(self.data.iter().map(|row| row[col]).collect());
+        }
+
+        for line in &lines {
+            // Rule 1: measure every run when it ends
+            let mut run = 1;
+            for n in 1..=size {
+                if n < size && line[n] == line[n - 1] {
+                    run += 1;
+                } else {
+                    if run >= 5 {
+                        rules[0] += run - 2;
+                    }
+                    run = 1;
+                }
+            }
+
+            // Rule 3: runs alternate between light and dark, starting and
+            // ending with a light run that includes the quiet zone
+            let mut runs: Vec<usize> = vec![];
+            let mut color = 0;
+            let mut run = size;
+            for module in line {
+                if *module == color {
+                    run += 1;
+                } else {
+                    runs.push(run);
+                    color = *module;
+                    run = 1;
+                }
+            }
+            if color == 1 {
+                runs.push(run);
+                run = 0;
+            }
+            runs.push(run + size);
+
+            // Dark runs have odd indexes
+            for pattern in runs.windows(7).step_by(2) {
+                let n = pattern[1];
+                if pattern[2..6] != [n, n * 3, n, n] {
+                    continue;
+                }
+
+                if pattern[0] >= n * 4 && pattern[6] >= n {
+                    rules[2] += 40;
+                }
+                if pattern[6] >= n * 4 && pattern[0] >= n {
+                    rules[2] += 40;
+                }
+            }
         }
 
-        score += x as usize;
+        // Rule 2
+        for row in 0..size - 1 {
+            for col in 0..size - 1 {
+                let color = self.data[row][col];
+                if self.data[row][col + 1] == color
+                    && self.data[row + 1][col] == color
+                    && self.data[row + 1][col + 1] == color
+                {
+                    rules[1] += 3;
+                }
+            }
+        }
 
-        score
+        // Rule 4
+        let total = size * size;
+        let dark: usize = self
+            .data
+            .iter()
+            .flatten()
+            .map(|module| *module as usize)
+            .sum();
+        // 10 points for every full 5% step beyond 45-55% dark modules, so
+        // exactly 55% still scores 0
+        rules[3] = (dark * 20)
+            .abs_diff(total * 10)
+            .div_ceil(total)
+            .saturating_sub(1)
+            * 10;
+
+        rules
     }
 
     // The format string consists of error correction level,
     // mask number, and 10 error correction bits
     fn place_format_string(&mut self, mask_no: usize) {
-        let mut format_string: u16;
-        let mut gen_poly: u16 = 0b10100110111;
+        let size = self.size;
         let xor_mask: u16 = 0b101010000010010;
 
         // Create format string (five bits)
-        // 01 for EC level L, nnn for mask number
-        // Shift to MSB position
-        format_string = 8 + mask_no as u16;
-        format_string = format_string << 10;
-
-        // Prepare for first division
-        gen_poly = gen_poly << 3;
-
-        // XOR (i.e. divide) until 10 EC bits remain
-        while format_string.leading_zeros() < 6 {
-            while gen_poly.leading_zeros() != format_string.leading_zeros() {
-                gen_poly = gen_poly >> 1;
-            }
-            format_string = format_string ^ gen_poly;
-        }
+        // Two bits for EC level, nnn for mask number
+        let format_data = self.ecc_level.format_bits() << 3 | mask_no as u16;
 
-        // Add EC bits to format string
-        format_string = format_string ^ ((8 + mask_no as u16) << 10);
-
-        // Final step: XOR the resulting string with a predefined bit sequence
-        format_string = format_string ^ xor_mask;
+        // Add 10 EC bits, then XOR the resulting string with a predefined
+        // bit sequence
+        let format_string = format_bch(format_data) ^ xor_mask;
 
         // Extract single bits from the format string
         let mut mask: u16 = 0b0100_0000_0000_0000;
         let mut bits: [u8; 15] = [0; 15];
 
-        for n in 0..15 {
+        for bit in bits.iter_mut() {
             if format_string & mask == mask {
-                bits[n] = 1;
+                *bit = 1;
             }
-            mask = mask >> 1;
+            mask >>= 1;
         }
 
         // Place format string in matrix
@@ -761,25 +2338,46 @@ impl Matrix {
                 self.data[n][8] = bits[14 - n];
             }
             // Skip timing pattern
-            else if n >= 6 {
+            else {
                 self.data[8][n + 1] = bits[n];
                 self.data[1 + n][8] = bits[14 - n];
             }
             // Next to lower left and upper right finder pattern
             if n < 7 {
-                self.data[24 - n][8] = bits[n];
+                self.data[size - 1 - n][8] = bits[n];
             }
-            self.data[8][24 - n] = bits[14 - n];
+            self.data[8][size - 1 - n] = bits[14 - n];
         }
     }
 
+    // Number of modules per side
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Color of a single module: 0 - white, 1 - black
+    pub fn module(&self, row: usize, col: usize) -> u8 {
+        self.data[row][col]
+    }
+
+    // True for modules of function patterns and format information, which
+    // masks leave untouched
+    pub fn is_function_module(&self, row: usize, col: usize) -> bool {
+        self.mask[row][col]
+    }
+
+    // Return 2D matrix of modules, inverted, mirrored or rotated
+    pub fn export_transformed(&self, transform: &Transform) -> Vec<Vec<u8>> {
+        transform.apply(self.export())
+    }
+
     // Return 2D matrix of modules
-    pub fn export(&self) -> [[u8; 33]; 33] {
+    pub fn export(&self) -> Vec<Vec<u8>> {
         // Add 4 modules of whitespace on all sides
-        let mut qr_final: [[u8; 33]; 33] = [[0; 33]; 33];
+        let mut qr_final: Vec<Vec<u8>> = vec![vec![0; self.size + 8]; self.size + 8];
 
-        for row in 0..25 {
-            for col in 0..25 {
+        for row in 0..self.size {
+            for col in 0..self.size {
                 qr_final[row + 4][col + 4] = self.data[row][col];
             }
         }
@@ -787,3 +2385,348 @@ impl Matrix {
         qr_final
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A bare square of modules, with the given number of dark ones
+    fn square(size: usize, dark: usize) -> Matrix {
+        let mut matrix = Matrix::new(1, EccLevel::L);
+        matrix.size = size;
+        matrix.data = vec![vec![0; size]; size];
+        for n in 0..dark {
+            matrix.data[n / size][n % size] = 1;
+        }
+
+        matrix
+    }
+
+    #[test]
+    fn rule_4_allows_up_to_55_percent_dark() {
+        assert_eq!(square(20, 200).iso_penalty_rules()[3], 0);
+        assert_eq!(square(20, 220).iso_penalty_rules()[3], 0);
+        assert_eq!(square(20, 180).iso_penalty_rules()[3], 0);
+        assert_eq!(square(20, 221).iso_penalty_rules()[3], 10);
+        assert_eq!(square(20, 179).iso_penalty_rules()[3], 10);
+        assert_eq!(square(20, 240).iso_penalty_rules()[3], 10);
+        assert_eq!(square(20, 241).iso_penalty_rules()[3], 20);
+    }
+
+    // A bare square of light modules with one row of the given modules,
+    // starting at a column
+    fn row_pattern(size: usize, col: usize, modules: &[u8]) -> Matrix {
+        let mut matrix = square(size, 0);
+        matrix.data[10][col..col + modules.len()].copy_from_slice(modules);
+
+        matrix
+    }
+
+    #[test]
+    fn rule_3_counts_each_light_side_of_a_finder_like_pattern() {
+        // 4 light modules on both sides
+        let matrix = row_pattern(21, 7, &[1, 0, 1, 1, 1, 0, 1]);
+        assert_eq!(matrix.iso_penalty_rules()[2], 80);
+
+        // Against the quiet zone, which is light
+        let matrix = row_pattern(21, 0, &[1, 0, 1, 1, 1, 0, 1]);
+        assert_eq!(matrix.iso_penalty_rules()[2], 80);
+
+        // Only 3 light modules after it
+        let matrix = row_pattern(21, 3, &[1, 0, 1, 1, 1, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(matrix.iso_penalty_rules()[2], 40);
+
+        // Twice as wide
+        let pattern = [1, 1, 0, 0, 1, 1, 1, 1, 1, 1, 0, 0, 1, 1];
+        let matrix = row_pattern(21, 3, &pattern);
+        assert_eq!(matrix.iso_penalty_rules()[2], 80);
+
+        // The dark runs have to be in the ratio 1:3:1
+        let matrix = row_pattern(21, 7, &[1, 0, 1, 1, 0, 1]);
+        assert_eq!(matrix.iso_penalty_rules()[2], 0);
+    }
+
+    #[test]
+    fn iso_penalty_chooses_the_mask_qrcodegen_does() {
+        // Masks qrcodegen 1.8.0 chooses for "HELLO WORLD" in byte mode
+        for (ecc_level, version, mask) in [
+            (EccLevel::L, 1, 0),
+            (EccLevel::M, 1, 4),
+            (EccLevel::Q, 1, 7),
+            (EccLevel::H, 2, 3),
+        ] {
+            let segments = [Segment::bytes(b"HELLO WORLD")];
+            assert_eq!(select_version(&segments, ecc_level), Some(version));
+
+            let (_matrix, choice) = build_matrix_masked(
+                &segments,
+                version,
+                ecc_level,
+                MaskSelection::Evaluate,
+                &IsoPenalty,
+            )
+            .unwrap();
+            assert_eq!(choice.mask, mask);
+        }
+    }
+
+    #[test]
+    fn iso_penalty_scores_the_format_string() {
+        let segments = [Segment::bytes(b"penalty")];
+        let unmasked = unmasked_matrix(&segments, 1, EccLevel::M);
+
+        let mut matrix = unmasked.clone();
+        let choice = matrix
+            .select_mask(MaskSelection::Evaluate, &IsoPenalty)
+            .unwrap();
+
+        for (n, score) in choice.scores.unwrap().into_iter().enumerate() {
+            let mut candidate = unmasked.clone();
+            candidate.transform(n);
+            candidate.place_format_string(n);
+            assert_eq!(score, candidate.iso_penalty_rules().iter().sum::<usize>());
+        }
+    }
+
+    #[test]
+    fn forced_masks_above_7_are_rejected() {
+        let segments = [Segment::bytes(b"mask")];
+        let result = build_matrix_masked(
+            &segments,
+            1,
+            EccLevel::M,
+            MaskSelection::Forced(8),
+            &DefaultPenalty,
+        );
+
+        assert!(result.is_err());
+    }
+
+    // Bytes that start with zeros, then run through all values
+    fn sample(length: usize, zeros: usize) -> Vec<u8> {
+        (0..length)
+            .map(|n| if n < zeros { 0 } else { (n * 97 + 31) as u8 })
+            .collect()
+    }
+
+    #[test]
+    fn base45_number_round_trips() {
+        for length in 0..48 {
+            for zeros in [0, 1, 2, length] {
+                let bits = sample(length, zeros.min(length));
+                assert_eq!(decode_bits(&encode_bits(bits.clone(), 45), 45), Ok(bits));
+            }
+            let bits = vec![255; length];
+            assert_eq!(decode_bits(&encode_bits(bits.clone(), 45), 45), Ok(bits));
+        }
+    }
+
+    #[test]
+    fn base45_number_length_depends_on_input_length_only() {
+        assert_eq!(encode_bits(vec![], 45), vec![]);
+        let mut previous = 0;
+        for length in 1..400 {
+            let digits = encode_bits(vec![255; length], 45).len();
+            assert_eq!(encode_bits(vec![0; length], 45).len(), digits);
+            assert!(digits > previous);
+            previous = digits;
+        }
+    }
+
+    #[test]
+    fn recovers_passwords_starting_with_zeros() {
+        for length in 1..340 {
+            for bits in [sample(length, 1), sample(length, 2), vec![0; length]] {
+                let mut text: Vec<u8> = encode_bits(bits.clone(), 45)
+                    .iter()
+                    .map(|digit| ALPHANUMERIC_CHARSET[*digit as usize])
+                    .collect();
+                text.push(b'\n');
+                assert_eq!(recover_bits(&text, Base45::Number), Ok(bits));
+            }
+        }
+    }
+
+    #[test]
+    fn recovers_codes_padded_the_earlier_way() {
+        // Padded to length * 16 / 11 digits, rounded up, unless longer
+        for length in [11, 22, 33] {
+            let bits = sample(length, 1);
+            let mut digits = encode_bits(bits.clone(), 45);
+            while digits.len() > (length * 16).div_ceil(11) && digits[0] == 0 {
+                digits.remove(0);
+            }
+            assert_ne!(digits.len(), encode_bits(bits.clone(), 45).len());
+            assert_eq!(decode_bits(&digits, 45), Ok(bits));
+        }
+    }
+
+    #[test]
+    fn base45_number_rejects_impossible_digits() {
+        assert!(decode_bits(&[45], 45).is_err());
+        assert!(decode_bits(&[0, 0, 0, 1], 45).is_err());
+        assert!(decode_bits(&[44, 44, 44], 45).is_err());
+        assert!(recover_bits(b"abc", Base45::Number).is_err());
+    }
+
+    // Character values of alphanumeric text
+    fn digits(text: &str) -> Vec<u8> {
+        text.bytes()
+            .map(|character| {
+                ALPHANUMERIC_CHARSET
+                    .iter()
+                    .position(|known| *known == character)
+                    .unwrap() as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rfc9285_examples() {
+        for (bits, text) in [
+            (&b"AB"[..], "BB8"),
+            (b"Hello!!", "%69 VD92EX0"),
+            (b"base-45", "UJCLQE7W581"),
+            (b"ietf!", "QED8WEX0"),
+            (b"", ""),
+        ] {
+            assert_eq!(Base45::Rfc9285.encode(bits), digits(text));
+            assert_eq!(
+                recover_bits(text.as_bytes(), Base45::Rfc9285),
+                Ok(bits.to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn rfc9285_round_trips() {
+        for length in 0..16 {
+            for zeros in [0, 1, length] {
+                let bits = sample(length, zeros.min(length));
+                assert_eq!(decode_rfc9285(&encode_rfc9285(&bits)), Ok(bits));
+            }
+        }
+        assert_eq!(decode_rfc9285(&digits("FGW")), Ok(vec![255, 255]));
+        assert_eq!(decode_rfc9285(&digits("U5")), Ok(vec![255]));
+    }
+
+    #[test]
+    fn rfc9285_rejects_out_of_range_values() {
+        assert!(decode_rfc9285(&digits("GGW")).is_err());
+        assert!(decode_rfc9285(&digits(":::")).is_err());
+        assert!(decode_rfc9285(&digits("V5")).is_err());
+        assert!(decode_rfc9285(&digits("BB8B")).is_err());
+        assert!(decode_rfc9285(&[45, 0, 0]).is_err());
+    }
+
+    // 20 data codewords with 10 error correction codewords
+    fn rs_block() -> (Vec<u8>, Vec<u8>) {
+        let data: Vec<u8> = (0..20).map(|n| (n * 13 + 7) as u8).collect();
+        let mut block = data.clone();
+        block.extend(reed_solomon(&data, 10));
+
+        (data, block)
+    }
+
+    // Damage the codewords at the given positions
+    fn damage(block: &[u8], positions: &[usize]) -> Vec<u8> {
+        let mut damaged = block.to_vec();
+        for position in positions {
+            damaged[*position] ^= (*position as u8).wrapping_mul(37) | 1;
+        }
+
+        damaged
+    }
+
+    // The first n positions of a walk through the block in steps of 7
+    fn spread(n: usize) -> Vec<usize> {
+        (0..n).map(|m| m * 7 % 30).collect()
+    }
+
+    #[test]
+    fn corrects_errors_only() {
+        let (data, block) = rs_block();
+        for errors in 0..=5 {
+            let damaged = damage(&block, &spread(errors));
+            assert_eq!(
+                correct_errors(&damaged, 10, &[]),
+                Ok((data.clone(), errors))
+            );
+        }
+    }
+
+    #[test]
+    fn corrects_erasures_only() {
+        let (data, block) = rs_block();
+        for erased in 0..=10 {
+            let positions = spread(erased);
+            let damaged = damage(&block, &positions);
+            assert_eq!(
+                correct_errors(&damaged, 10, &positions),
+                Ok((data.clone(), erased))
+            );
+        }
+
+        // Erasures that turn out to be intact aren't counted as changed
+        assert_eq!(correct_errors(&block, 10, &[0, 15, 29]), Ok((data, 0)));
+    }
+
+    #[test]
+    fn corrects_errors_and_erasures_up_to_the_limit() {
+        let (data, block) = rs_block();
+        for errors in 0..=5 {
+            let erased = 10 - 2 * errors;
+            let positions = spread(errors + erased);
+            let damaged = damage(&block, &positions);
+            assert_eq!(
+                correct_errors(&damaged, 10, &positions[errors..]),
+                Ok((data.clone(), errors + erased))
+            );
+        }
+    }
+
+    #[test]
+    fn reports_uncorrectable_blocks() {
+        let (_data, block) = rs_block();
+
+        // One error beyond the limit, and one codeword beyond it with two
+        // errors and seven erasures
+        assert!(correct_errors(&damage(&block, &spread(6)), 10, &[]).is_err());
+        let positions = spread(9);
+        let damaged = damage(&block, &positions);
+        assert!(correct_errors(&damaged, 10, &positions[2..]).is_err());
+
+        // More erasures than error correction codewords
+        let positions = spread(11);
+        assert!(correct_errors(&damage(&block, &positions), 10, &positions).is_err());
+
+        // Erasures outside the block
+        assert!(correct_errors(&block, 10, &[30]).is_err());
+    }
+
+    #[test]
+    fn split_symbols_use_the_selected_mask() {
+        let message: Vec<u8> = (0..60).collect();
+        let split = |selection: MaskSelection| {
+            structured_append_with(
+                &message,
+                EccLevel::L,
+                1,
+                None,
+                selection,
+                &IsoPenalty,
+                |header, part| select_version_optimal(header, part, EccLevel::L),
+            )
+        };
+
+        for (selection, mask) in [(MaskSelection::Forced(3), 3), (MaskSelection::Fast, 0)] {
+            let symbols = split(selection).unwrap().unwrap();
+            assert!(symbols.len() > 1);
+            for symbol in &symbols {
+                assert_eq!(crate::decoder::decode_matrix(symbol).unwrap().mask, mask);
+            }
+        }
+
+        assert!(split(MaskSelection::Forced(8)).is_err());
+    }
+}
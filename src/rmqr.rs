@@ -0,0 +1,832 @@
+use crate::qr_code::{
+    EccLevel, Mode, Segment, Transform, append_bits, append_padding, apply_ecc_blocks,
+    mask_pattern, place_zigzag, version_bch,
+};
+
+// Represents a rectangular Micro QR code (rMQR) of one of 32 sizes, from
+// R7x43 (7 modules high, 43 wide) to R17x139
+// A finder pattern sits at the left end, a smaller sub-finder pattern in
+// the lower right corner. Timing patterns run along all four edges and
+// down the middle of each alignment pattern.
+// Matrix::mask defines areas where data cannot be written
+#[derive(Clone)]
+pub struct Matrix {
+    version: usize,
+    ecc_level: EccLevel,
+    height: usize,
+    width: usize,
+    data: Vec<Vec<u8>>,
+    mask: Vec<Vec<bool>>,
+}
+
+// Height and width of each version. The index is the version indicator
+// stored in the format information.
+pub(crate) const SIZES: [(usize, usize); 32] = [
+    (7, 43),
+    (7, 59),
+    (7, 77),
+    (7, 99),
+    (7, 139),
+    (9, 43),
+    (9, 59),
+    (9, 77),
+    (9, 99),
+    (9, 139),
+    (11, 27),
+    (11, 43),
+    (11, 59),
+    (11, 77),
+    (11, 99),
+    (11, 139),
+    (13, 27),
+    (13, 43),
+    (13, 59),
+    (13, 77),
+    (13, 99),
+    (13, 139),
+    (15, 43),
+    (15, 59),
+    (15, 77),
+    (15, 99),
+    (15, 139),
+    (17, 43),
+    (17, 59),
+    (17, 77),
+    (17, 99),
+    (17, 139),
+];
+
+// Number of codewords (data and error correction) that fit into each
+// version. 0 to 7 modules are left over as remainder bits.
+pub(crate) const TOTAL_CODEWORDS: [usize; 32] = [
+    13, 21, 32, 44, 68, 21, 33, 49, 66, 99, 15, 31, 47, 67, 89, 132, 21, 41, 60, 85, 113, 166, 51,
+    74, 103, 136, 199, 61, 88, 122, 160, 232,
+];
+
+// Error correction codewords per block for ECC level M and H
+#[rustfmt::skip]
+const ECC_CODEWORDS_PER_BLOCK: [[usize; 32]; 2] = [
+    [7, 9, 12, 16, 24, 9, 12, 18, 24, 18, 8, 12, 16, 24, 16, 24, 9, 14, 22, 16, 20, 20, 18, 26, 18, 24, 24, 22, 16, 22, 20, 20],
+    [10, 14, 22, 30, 22, 14, 22, 16, 22, 22, 10, 20, 16, 22, 30, 30, 14, 28, 20, 28, 26, 28, 18, 24, 24, 22, 26, 20, 30, 28, 26, 26],
+];
+
+// Number of error correction blocks for ECC level M and H
+#[rustfmt::skip]
+const ECC_BLOCKS: [[usize; 32]; 2] = [
+    [1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 2, 2, 1, 1, 1, 2, 2, 3, 1, 1, 2, 2, 3, 1, 2, 2, 3, 4],
+    [1, 1, 1, 1, 2, 1, 1, 2, 2, 3, 1, 1, 2, 2, 2, 3, 1, 1, 2, 2, 3, 4, 2, 2, 3, 4, 5, 2, 2, 3, 4, 6],
+];
+
+// Width of the character count indicator for numeric, alphanumeric, byte
+// and kanji mode. Just wide enough for the capacity of each version.
+const CHARACTER_COUNT_BITS: [[usize; 4]; 32] = [
+    [4, 3, 3, 2],
+    [5, 5, 4, 3],
+    [6, 5, 5, 4],
+    [7, 6, 5, 5],
+    [7, 6, 6, 5],
+    [5, 5, 4, 3],
+    [6, 5, 5, 4],
+    [7, 6, 5, 5],
+    [7, 6, 6, 5],
+    [8, 7, 6, 6],
+    [4, 4, 3, 2],
+    [6, 5, 5, 4],
+    [7, 6, 5, 5],
+    [7, 6, 6, 5],
+    [8, 7, 6, 6],
+    [8, 7, 7, 6],
+    [5, 5, 4, 3],
+    [6, 6, 5, 5],
+    [7, 6, 6, 5],
+    [7, 7, 6, 6],
+    [8, 7, 7, 6],
+    [8, 8, 7, 7],
+    [7, 6, 6, 5],
+    [7, 7, 6, 5],
+    [8, 7, 7, 6],
+    [8, 7, 7, 6],
+    [9, 8, 7, 7],
+    [7, 6, 6, 5],
+    [8, 7, 6, 6],
+    [8, 7, 7, 6],
+    [8, 8, 7, 6],
+    [9, 8, 8, 7],
+];
+
+// Columns of the alignment patterns, which only depend on the width
+fn alignment_columns(width: usize) -> &'static [usize] {
+    match width {
+        43 => &[21],
+        59 => &[19, 39],
+        77 => &[25, 51],
+        99 => &[23, 49, 75],
+        139 => &[27, 55, 83, 111],
+        _ => &[],
+    }
+}
+
+// rMQR codes only know levels M and H
+// L is raised to M and Q to H, so a symbol never gets less protection than
+// asked for
+pub fn ecc_level(requested: EccLevel) -> EccLevel {
+    match requested {
+        EccLevel::L | EccLevel::M => EccLevel::M,
+        EccLevel::Q | EccLevel::H => EccLevel::H,
+    }
+}
+
+// Index into the tables above: 0 for level M, 1 for level H
+fn level_index(ecc_level: EccLevel) -> usize {
+    match ecc_level {
+        EccLevel::L | EccLevel::M => 0,
+        EccLevel::Q | EccLevel::H => 1,
+    }
+}
+
+// Name of a version as printed on the label, e.g. R7x43
+pub fn name(version: usize) -> String {
+    format!("R{}x{}", SIZES[version].0, SIZES[version].1)
+}
+
+// Level identified by the format information bit, the reverse of
+// level_index
+pub(crate) fn level(index: u32) -> EccLevel {
+    if index == 0 { EccLevel::M } else { EccLevel::H }
+}
+
+// Number of error correction blocks of a version at an ECC level, and
+// error correction codewords per block
+pub(crate) fn ecc_blocks(version: usize, ecc_level: EccLevel) -> (usize, usize) {
+    let level = level_index(ecc_level);
+    (
+        ECC_BLOCKS[level][version],
+        ECC_CODEWORDS_PER_BLOCK[level][version],
+    )
+}
+
+// Number of data codewords of a version at an ECC level
+pub(crate) fn data_codewords(version: usize, ecc_level: EccLevel) -> usize {
+    let level = level_index(ecc_level);
+    TOTAL_CODEWORDS[version] - ECC_BLOCKS[level][version] * ECC_CODEWORDS_PER_BLOCK[level][version]
+}
+
+// Mode indicators are 3 bits wide
+// Structured append doesn't exist in rMQR codes
+fn mode_indicator(mode: Mode) -> Option<u32> {
+    match mode {
+        Mode::Numeric => Some(0b001),
+        Mode::Alphanumeric => Some(0b010),
+        Mode::Byte => Some(0b011),
+        Mode::Kanji => Some(0b100),
+        Mode::Fnc1First => Some(0b101),
+        Mode::Fnc1Second => Some(0b110),
+        Mode::Eci => Some(0b111),
+        Mode::StructuredAppend => None,
+    }
+}
+
+// Mode of a mode indicator, the reverse of mode_indicator
+// None for the terminator
+pub(crate) fn mode(indicator: u32) -> Option<Mode> {
+    [
+        Mode::Numeric,
+        Mode::Alphanumeric,
+        Mode::Byte,
+        Mode::Kanji,
+        Mode::Fnc1First,
+        Mode::Fnc1Second,
+        Mode::Eci,
+    ]
+    .into_iter()
+    .find(|mode| mode_indicator(*mode) == Some(indicator))
+}
+
+// Width of the character count indicator for each mode and version
+pub(crate) fn character_count_bits(mode: Mode, version: usize) -> usize {
+    match mode {
+        Mode::Numeric => CHARACTER_COUNT_BITS[version][0],
+        Mode::Alphanumeric => CHARACTER_COUNT_BITS[version][1],
+        Mode::Byte => CHARACTER_COUNT_BITS[version][2],
+        Mode::Kanji => CHARACTER_COUNT_BITS[version][3],
+        // No character count, the header data follows right away
+        Mode::Eci | Mode::StructuredAppend | Mode::Fnc1First | Mode::Fnc1Second => 0,
+    }
+}
+
+// Number of bits a segment takes up in a given version, including mode and
+// length indicator
+// None if the mode isn't supported, or if there are more characters than
+// the length indicator can count
+fn segment_bits(segment: &Segment, version: usize) -> Option<usize> {
+    mode_indicator(segment.mode)?;
+    let count_bits = character_count_bits(segment.mode, version);
+    if count_bits > 0 && segment.character_count >= 1 << count_bits {
+        return None;
+    }
+
+    Some(3 + count_bits + segment.bits.len())
+}
+
+// Find the lowest version whose data capacity fits all segments
+// Versions are ordered by height, then width, so the result is the
+// narrowest strip that holds the data, and the shortest one of that height
+pub fn select_version(segments: &[Segment], ecc_level: EccLevel) -> Option<usize> {
+    (0..SIZES.len()).find(|&version| fits_version(segments, version, ecc_level))
+}
+
+// Raise the error correction level from M to H if the segments still fit
+// into the given version
+pub fn boost_ecc_level(segments: &[Segment], version: usize, ecc_level: EccLevel) -> EccLevel {
+    if fits_version(segments, version, EccLevel::H) {
+        EccLevel::H
+    } else {
+        self::ecc_level(ecc_level)
+    }
+}
+
+// Check whether all segments fit into a given version
+fn fits_version(segments: &[Segment], version: usize, ecc_level: EccLevel) -> bool {
+    let mut total = 0;
+    for segment in segments {
+        match segment_bits(segment, version) {
+            Some(bits) => total += bits,
+            None => return false,
+        }
+    }
+
+    total <= data_codewords(version, ecc_level) * 8
+}
+
+// Take a list of segments and add everything needed to build an rMQR code
+// Same as in QR codes, but with 3 bit mode indicators and terminator
+pub fn encapsulate_data(segments: &[Segment], version: usize, ecc_level: EccLevel) -> Vec<u8> {
+    let capacity = data_codewords(version, ecc_level) * 8;
+    let mut data: Vec<u8> = Vec::with_capacity(capacity);
+
+    for segment in segments {
+        append_bits(&mut data, mode_indicator(segment.mode).unwrap_or(0), 3);
+        append_bits(
+            &mut data,
+            segment.character_count as u32,
+            character_count_bits(segment.mode, version),
+        );
+        data.extend_from_slice(&segment.bits);
+    }
+
+    append_padding(&mut data, capacity, 3);
+
+    data
+}
+
+// Calculate Reed-Solomon code words, interleaved across blocks as in QR
+// codes
+pub fn apply_ecc(data: Vec<u8>, version: usize, ecc_level: EccLevel) -> Vec<u8> {
+    let level = level_index(ecc_level);
+    apply_ecc_blocks(
+        data,
+        ECC_BLOCKS[level][version],
+        ECC_CODEWORDS_PER_BLOCK[level][version],
+    )
+}
+
+// Turn segments into a fully formed rMQR code
+pub fn build_matrix(segments: &[Segment], version: usize, ecc_level: EccLevel) -> Matrix {
+    let ecc_level = self::ecc_level(ecc_level);
+
+    // Add mode indicator, length indicator, padding, etc.
+    let data_bits = encapsulate_data(segments, version, ecc_level);
+
+    // Add error correction codewords
+    let data_ecc = apply_ecc(data_bits, version, ecc_level);
+
+    // Start with an empty matrix of the chosen version's size
+    let mut matrix = Matrix::new(version, ecc_level);
+
+    // Populate it with fixed patterns, data, and format information
+    // The timing patterns fill whatever the other patterns leave free on
+    // their lines, so they come last
+    matrix.place_finder_pattern();
+    matrix.place_sub_finder_pattern();
+    matrix.place_alignment_pattern();
+    matrix.place_corner_pattern();
+    matrix.place_timing_pattern();
+    matrix.reserve_format_area();
+    matrix.fill_data(data_ecc);
+    matrix.apply_mask();
+    matrix.place_format_string();
+
+    matrix
+}
+
+// Format information XOR masks of the copies next to the finder and the
+// sub-finder pattern
+pub(crate) const FORMAT_MASKS: [u32; 2] = [0b01_1111_1010_1011_0010, 0b10_0000_1010_0111_1011];
+
+// Modules taken up by fixed patterns and format information, where no
+// data goes
+pub(crate) fn function_modules(version: usize) -> Vec<Vec<bool>> {
+    let mut matrix = Matrix::new(version, EccLevel::M);
+
+    matrix.place_finder_pattern();
+    matrix.place_sub_finder_pattern();
+    matrix.place_alignment_pattern();
+    matrix.place_corner_pattern();
+    matrix.place_timing_pattern();
+    matrix.reserve_format_area();
+
+    matrix.mask
+}
+
+// Modules holding bits 0 to 17 of the format information
+// Next to the finder pattern: a block of 3x5 modules right of the
+// separator and 3 more modules in the column after it
+// Next to the sub-finder pattern: a block of 3x5 modules left of it and
+// 3 more modules in the row above it
+pub(crate) fn format_positions(version: usize, sub_finder: bool) -> Vec<(usize, usize)> {
+    let (height, width) = SIZES[version];
+    let (top, left) = if sub_finder {
+        (height - 6, width - 8)
+    } else {
+        (1, 8)
+    };
+
+    let mut positions: Vec<(usize, usize)> = (0..15).map(|n| (top + n % 5, left + n / 5)).collect();
+
+    for n in 0..3 {
+        if sub_finder {
+            positions.push((top, left + 3 + n));
+        } else {
+            positions.push((top + n, left + 3));
+        }
+    }
+
+    positions
+}
+
+// Representation of a 2D rMQR code and methods for preparing,
+// populating, and extracting it
+impl Matrix {
+    // Every module is represented by one u8: 0 - white, 1 - black
+    // Keep track of prohibited areas, where data can't be written
+    pub fn new(version: usize, ecc_level: EccLevel) -> Matrix {
+        let (height, width) = SIZES[version];
+        Matrix {
+            version,
+            ecc_level,
+            height,
+            width,
+            data: vec![vec![0; width]; height],
+            mask: vec![vec![false; width]; height],
+        }
+    }
+
+    // Place a square ring of dark modules, plus the dark center square
+    // The ring is `size` modules wide, the center square `size - 4`
+    fn place_square(&mut self, top: usize, left: usize, size: usize) {
+        for row in 0..size {
+            for col in 0..size {
+                let ring = row == 0 || col == 0 || row == size - 1 || col == size - 1;
+                let center = row >= 2 && col >= 2 && row < size - 2 && col < size - 2;
+                self.data[top + row][left + col] = (ring || center) as u8;
+                self.mask[top + row][left + col] = true;
+            }
+        }
+    }
+
+    // The finder pattern in the upper left corner is the same as in QR
+    // codes. It fills the full height of R7 codes, taller codes get a
+    // separator below it as well as to the right.
+    pub fn place_finder_pattern(&mut self) {
+        self.place_square(0, 0, 7);
+
+        for n in 0..self.height.min(8) {
+            self.mask[n][7] = true;
+        }
+        if self.height > 7 {
+            for n in 0..8 {
+                self.mask[7][n] = true;
+            }
+        }
+    }
+
+    // A smaller 5x5 pattern marks the lower right corner
+    pub fn place_sub_finder_pattern(&mut self) {
+        self.place_square(self.height - 5, self.width - 5, 5);
+    }
+
+    // 3x3 rings sit on the top and bottom edges at every alignment column
+    pub fn place_alignment_pattern(&mut self) {
+        for &col in alignment_columns(self.width) {
+            self.place_square(0, col - 1, 3);
+            self.place_square(self.height - 3, col - 1, 3);
+        }
+    }
+
+    // The remaining two corners are marked by small L-shaped patterns,
+    // which break the rhythm of the timing patterns
+    pub fn place_corner_pattern(&mut self) {
+        let last_row = self.height - 1;
+        let last_col = self.width - 1;
+
+        // Upper right
+        for (row, col, dark) in [
+            (0, last_col - 1, 1),
+            (0, last_col, 1),
+            (1, last_col - 1, 0),
+            (1, last_col, 1),
+        ] {
+            self.data[row][col] = dark;
+            self.mask[row][col] = true;
+        }
+
+        // Lower left, only a single row fits below the separator of R9
+        // codes
+        for col in 0..3 {
+            self.data[last_row][col] = 1;
+            self.mask[last_row][col] = true;
+        }
+        if self.height >= 11 {
+            self.data[last_row - 1][0] = 1;
+            self.data[last_row - 1][1] = 0;
+            self.mask[last_row - 1][0] = true;
+            self.mask[last_row - 1][1] = true;
+        }
+    }
+
+    // Timing patterns run along all four edges and through the alignment
+    // patterns, filling every module the other patterns left free
+    // Even rows and columns are dark
+    pub fn place_timing_pattern(&mut self) {
+        for col in 0..self.width {
+            for row in [0, self.height - 1] {
+                if !self.mask[row][col] {
+                    self.data[row][col] = (col % 2 == 0) as u8;
+                    self.mask[row][col] = true;
+                }
+            }
+        }
+
+        let mut columns = vec![0, self.width - 1];
+        columns.extend_from_slice(alignment_columns(self.width));
+
+        for row in 0..self.height {
+            for &col in &columns {
+                if !self.mask[row][col] {
+                    self.data[row][col] = (row % 2 == 0) as u8;
+                    self.mask[row][col] = true;
+                }
+            }
+        }
+    }
+
+    // Reserve space for formatting information next to both finder
+    // patterns. Will be added at the last step
+    pub fn reserve_format_area(&mut self) {
+        for sub_finder in [false, true] {
+            for (row, col) in format_positions(self.version, sub_finder) {
+                self.mask[row][col] = true;
+            }
+        }
+    }
+
+    // Data is placed in the same zig-zag pattern as in QR codes
+    // The right edge holds a timing pattern, so the first column pair is
+    // left of it
+    pub fn fill_data(&mut self, data_bits: Vec<u8>) {
+        place_zigzag(&mut self.data, &self.mask, &data_bits, self.width - 2, None);
+    }
+
+    // rMQR codes always use the same mask (number 4 of QR codes), there's
+    // nothing to choose or evaluate
+    pub fn apply_mask(&mut self) {
+        let eval = mask_pattern(4);
+
+        for row in 0..self.height {
+            for col in 0..self.width {
+                if !self.mask[row][col] && eval(row, col) == 0 {
+                    self.data[row][col] ^= 1;
+                }
+            }
+        }
+    }
+
+    // The format information consists of the ECC level (0 for M, 1 for H),
+    // 5 bits of version indicator, and 12 BCH error correction bits
+    // Each copy gets its own XOR mask
+    fn place_format_string(&mut self) {
+        let format_data = (level_index(self.ecc_level) << 5 | self.version) as u32;
+        let format_string = version_bch(format_data);
+
+        for (sub_finder, xor_mask) in [(false, FORMAT_MASKS[0]), (true, FORMAT_MASKS[1])] {
+            let masked = format_string ^ xor_mask;
+            for (n, (row, col)) in format_positions(self.version, sub_finder)
+                .into_iter()
+                .enumerate()
+            {
+                self.data[row][col] = ((masked >> n) & 1) as u8;
+            }
+        }
+    }
+
+    // Return 2D matrix of modules, inverted, mirrored or rotated
+    pub fn export_transformed(&self, transform: &Transform) -> Vec<Vec<u8>> {
+        transform.apply(self.export())
+    }
+
+    // Return 2D matrix of modules
+    pub fn export(&self) -> Vec<Vec<u8>> {
+        // Add 2 modules of whitespace on all sides
+        let mut qr_final: Vec<Vec<u8>> = vec![vec![0; self.width + 4]; self.height + 4];
+
+        for row in 0..self.height {
+            for col in 0..self.width {
+                qr_final[row + 2][col + 2] = self.data[row][col];
+            }
+        }
+
+        qr_final
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rows of a symbol without quiet zone, '#' for dark modules
+    fn rows(matrix: &Matrix) -> Vec<String> {
+        matrix
+            .data
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|&bit| if bit == 1 { '#' } else { '.' })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Most characters of one mode that fit into a version
+    fn capacity(mode: Mode, version: usize, ecc_level: EccLevel) -> usize {
+        let segment = |count: usize| match mode {
+            Mode::Numeric => Segment::numeric(&vec![b'0'; count]),
+            Mode::Alphanumeric => Segment::alphanumeric(&vec![0; count]),
+            Mode::Byte => Segment::bytes(&vec![0; count]),
+            _ => Segment::kanji(&vec![0x8140; count]),
+        };
+
+        (0..)
+            .take_while(|&count| fits_version(&[segment(count)], version, ecc_level))
+            .last()
+            .unwrap()
+    }
+
+    #[test]
+    fn reference_symbols() {
+        let matrix = build_matrix(&[Segment::numeric(b"123456789012")], 0, EccLevel::M);
+        assert_eq!(
+            rows(&matrix),
+            [
+                "#######.#.#.#.#.#.#.###.#.#.#.#.#.#.#.#.###",
+                "#.....#..#.#.######.#.#.....#...##.##...#.#",
+                "#.###.#.#.###...#..####.##.#.##.#.#########",
+                "#.###.#..##..##.###........#######....#...#",
+                "#.###.#...#.##...######..##.#####.##..#.#.#",
+                "#.....#.####.########.#.##.#..#.#.###.#...#",
+                "#######.#.#.#.#.#.#.###.#.#.#.#.#.#.#.#####",
+            ]
+        );
+
+        // Only the corner row fits below the separator of R9 codes
+        let matrix = build_matrix(&[Segment::bytes(b"rMQR")], 6, EccLevel::H);
+        assert_eq!(
+            rows(&matrix),
+            [
+                "#######.#.#.#.#.#.###.#.#.#.#.#.#.#.#.###.#.#.#.#.#.#.#.###",
+                "#.....#....##..####.#..##..##.#...##..#.#...#..##.#.##.##.#",
+                "#.###.#.##.#.#..#.####...#.#....##.######.##...#.#...#..#.#",
+                "#.###.#.####.#...##.#.##.#...#.....##....##..###...#.....#.",
+                "#.###.#....###...###.#...###...#.####.#####.#...#..#..#####",
+                "#.....#.#...###...#.##..###.#.#......##.######..#.##..#...#",
+                "#######..#...#.#..####.....#..##.####.####..####.#.#.##.#.#",
+                ".........#.#.######.#....######.##..###.##.##..#.###.##...#",
+                "###.#.#.#.#.#.#.#.###.#.#.#.#.#.#.#.#.###.#.#.#.#.#.#.#####",
+            ]
+        );
+
+        // No alignment patterns
+        let matrix = build_matrix(&[Segment::numeric(b"12345")], 10, EccLevel::H);
+        assert_eq!(
+            rows(&matrix),
+            [
+                "#######.#.#.#.#.#.#.#.#.###",
+                "#.....#..##.....####....#.#",
+                "#.###.#....#.#.#....#...#.#",
+                "#.###.#.#####.###.......#..",
+                "#.###.#..#...##...#####..##",
+                "#.....#.####.....#.####..#.",
+                "#######.#...#.....#.#.#####",
+                "........##..##..####..#...#",
+                "####.####..###.##.#####.#.#",
+                "#.#.....##...###.#.##.#...#",
+                "###.#.#.#.#.#.#.#.#.#.#####",
+            ]
+        );
+
+        // Two blocks of 7 and 8 data codewords
+        let matrix = build_matrix(&[Segment::bytes(b"rectangle")], 22, EccLevel::H);
+        assert_eq!(
+            rows(&matrix),
+            [
+                "#######.#.#.#.#.#.#.###.#.#.#.#.#.#.#.#.###",
+                "#.....#..#.#.#......#.##.##.##..#..##.###.#",
+                "#.###.#.#.#..#####..#####.###.#.......#..##",
+                "#.###.#.####.#....###.#....#...#.#####..#..",
+                "#.###.#.##........##.#.....###..#.#.##..#.#",
+                "#.....#..#.....#####..#.####.#..#..##.##...",
+                "#######.####...#....###..#.##.##..##..#.#.#",
+                "..........####.#.###....#..#.##.##########.",
+                "###.#...####..#..#.###.##..#.##.#...#######",
+                ".#####..#.##.####...#..#..###.#######..#...",
+                "##..##......###...#####.####.#..###########",
+                ".#..##..#.##....#####..#.##.##.##..#..#...#",
+                "####.###.####.#########.###...##..#.###.#.#",
+                "#.##.##....#.#..##..#.####.####...#.###...#",
+                "###.#.#.#.#.#.#.#.#.###.#.#.#.#.#.#.#.#####",
+            ]
+        );
+    }
+
+    #[test]
+    fn capacities_of_the_smallest_and_largest_size() {
+        let modes = [Mode::Numeric, Mode::Alphanumeric, Mode::Byte, Mode::Kanji];
+        let capacities = |version: usize, ecc_level: EccLevel| -> Vec<usize> {
+            modes
+                .iter()
+                .map(|&mode| capacity(mode, version, ecc_level))
+                .collect()
+        };
+
+        assert_eq!(capacities(0, EccLevel::M), [12, 7, 5, 3]);
+        assert_eq!(capacities(0, EccLevel::H), [5, 3, 2, 1]);
+        assert_eq!(capacities(31, EccLevel::M), [361, 219, 150, 92]);
+        assert_eq!(capacities(31, EccLevel::H), [178, 108, 74, 46]);
+    }
+
+    #[test]
+    fn codewords_fill_every_size() {
+        for (version, &(height, width)) in SIZES.iter().enumerate() {
+            assert!((7..=17).contains(&height) && height % 2 == 1);
+            assert!([27, 43, 59, 77, 99, 139].contains(&width));
+
+            // Up to 7 remainder bits are left over
+            let data_modules = function_modules(version)
+                .iter()
+                .flatten()
+                .filter(|function| !**function)
+                .count();
+            assert_eq!(data_modules / 8, TOTAL_CODEWORDS[version]);
+
+            for ecc_level in [EccLevel::M, EccLevel::H] {
+                let (blocks, ecc_length) = ecc_blocks(version, ecc_level);
+                assert_eq!(
+                    data_codewords(version, ecc_level) + blocks * ecc_length,
+                    TOTAL_CODEWORDS[version]
+                );
+            }
+            assert!(data_codewords(version, EccLevel::H) < data_codewords(version, EccLevel::M));
+        }
+    }
+
+    #[test]
+    fn finder_sub_finder_and_corner_patterns() {
+        for version in 0..SIZES.len() {
+            let matrix = build_matrix(&[Segment::numeric(b"1")], version, EccLevel::M);
+            let (height, width) = (matrix.height, matrix.width);
+            let module = |row: usize, col: usize| matrix.data[row][col];
+
+            // 7x7 finder: dark ring, light ring, 3x3 dark center
+            for row in 0..7_usize {
+                for col in 0..7_usize {
+                    let ring = row.abs_diff(3).max(col.abs_diff(3));
+                    assert_eq!(module(row, col), (ring != 2) as u8);
+                }
+            }
+
+            // 5x5 sub-finder: dark ring, light ring, single dark center
+            for row in 0..5_usize {
+                for col in 0..5_usize {
+                    let ring = row.abs_diff(2).max(col.abs_diff(2));
+                    assert_eq!(module(height - 5 + row, width - 5 + col), (ring != 1) as u8);
+                }
+            }
+
+            // Upper right corner, and lower left below the separator
+            assert_eq!(
+                [
+                    module(0, width - 2),
+                    module(0, width - 1),
+                    module(1, width - 2),
+                    module(1, width - 1)
+                ],
+                [1, 1, 0, 1]
+            );
+            assert_eq!(
+                [
+                    module(height - 1, 0),
+                    module(height - 1, 1),
+                    module(height - 1, 2)
+                ],
+                [1, 1, 1]
+            );
+            if height >= 11 {
+                assert_eq!([module(height - 2, 0), module(height - 2, 1)], [1, 0]);
+            }
+
+            // Timing patterns along the top and bottom edges, between the
+            // other patterns
+            let alignment = alignment_columns(width);
+            for col in 8..width - 5 {
+                if alignment.iter().all(|&x| col.abs_diff(x) > 1) {
+                    assert_eq!(module(0, col), (col % 2 == 0) as u8);
+                    assert_eq!(module(height - 1, col), (col % 2 == 0) as u8);
+                }
+            }
+
+            // Alignment patterns: dark ring around a light center, with a
+            // timing pattern between them
+            for &col in alignment {
+                for top in [0, height - 3] {
+                    assert_eq!(module(top + 1, col), 0);
+                    assert_eq!(module(top, col - 1) & module(top + 2, col + 1), 1);
+                }
+                for row in 3..height - 3 {
+                    assert_eq!(module(row, col), (row % 2 == 0) as u8);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn format_information_in_both_copies() {
+        for version in 0..SIZES.len() {
+            for (ecc_level, level_bit) in [(EccLevel::M, 0), (EccLevel::H, 1)] {
+                let matrix = build_matrix(&[Segment::numeric(b"1")], version, ecc_level);
+                let expected = version_bch((level_bit << 5 | version) as u32);
+
+                for (sub_finder, xor_mask) in [(false, FORMAT_MASKS[0]), (true, FORMAT_MASKS[1])] {
+                    let format_string = format_positions(version, sub_finder)
+                        .into_iter()
+                        .enumerate()
+                        .fold(0, |format_string, (n, (row, col))| {
+                            format_string | (matrix.data[row][col] as u32) << n
+                        });
+                    assert_eq!(format_string ^ xor_mask, expected);
+                }
+            }
+        }
+
+        // Right of the finder pattern, and left of the sub-finder pattern
+        let positions = format_positions(0, false);
+        assert_eq!(
+            (positions[0], positions[14], positions[17]),
+            ((1, 8), (5, 10), (3, 11))
+        );
+        let positions = format_positions(0, true);
+        assert_eq!(
+            (positions[0], positions[14], positions[17]),
+            ((1, 35), (5, 37), (1, 40))
+        );
+    }
+
+    #[test]
+    fn data_is_masked_with_qr_mask_4() {
+        let segments = [Segment::bytes(b"mask")];
+        let data = apply_ecc(
+            encapsulate_data(&segments, 11, EccLevel::M),
+            11,
+            EccLevel::M,
+        );
+
+        let mut matrix = Matrix::new(11, EccLevel::M);
+        matrix.place_finder_pattern();
+        matrix.place_sub_finder_pattern();
+        matrix.place_alignment_pattern();
+        matrix.place_corner_pattern();
+        matrix.place_timing_pattern();
+        matrix.reserve_format_area();
+        matrix.fill_data(data);
+        let unmasked = matrix.clone();
+        matrix.apply_mask();
+
+        for row in 0..matrix.height {
+            for col in 0..matrix.width {
+                let toggled = matrix.data[row][col] != unmasked.data[row][col];
+                assert_eq!(
+                    toggled,
+                    !matrix.mask[row][col] && (row / 2 + col / 3) % 2 == 0
+                );
+            }
+        }
+    }
+}